@@ -57,13 +57,13 @@ async fn main() {
                 (
                     StatusCode::TOO_MANY_REQUESTS,
                     // https://developer.mozilla.org/en-US/docs/Web/HTTP/Reference/Headers/Retry-After
-                    AppendHeaders([(header::RETRY_AFTER, err.details.retry_after)]),
+                    AppendHeaders([(header::RETRY_AFTER, err.retry_after())]),
                     Body::from("too many requests"),
                 )
                     .into_response()
             }
-            Error::Redis(err) => {
-                tracing::error!(err = %err, "error in rate limit layer");
+            Error::Redis { source, command } => {
+                tracing::error!(err = %source, command, "error in rate limit layer");
                 (StatusCode::INTERNAL_SERVER_ERROR).into_response()
             }
             _ => {
@@ -100,7 +100,28 @@ async fn main() {
         app.layer(layer)
     };
 
-    #[cfg(not(feature = "deadpool"))]
+    #[cfg(all(feature = "bb8", not(feature = "deadpool")))]
+    let app = {
+        use bb8_redis::RedisConnectionManager;
+        use tower_redis_cell::bb8::RateLimitLayer;
+
+        let manager = RedisConnectionManager::new(("localhost", port)).unwrap();
+        let pool = bb8::Pool::builder().build(manager).await.unwrap();
+        let layer = RateLimitLayer::new(rate_limit_config, pool);
+        app.layer(layer)
+    };
+
+    #[cfg(all(feature = "mobc", not(any(feature = "deadpool", feature = "bb8"))))]
+    let app = {
+        use tower_redis_cell::mobc::{RateLimitLayer, RedisConnectionManager};
+
+        let client = redis::Client::open(("localhost", port)).unwrap();
+        let pool = mobc::Pool::builder().build(RedisConnectionManager::new(client));
+        let layer = RateLimitLayer::new(rate_limit_config, pool);
+        app.layer(layer)
+    };
+
+    #[cfg(not(any(feature = "deadpool", feature = "bb8", feature = "mobc")))]
     let app = {
         use redis::{Client, aio::ConnectionManager, aio::ConnectionManagerConfig};
         use tower_redis_cell::RateLimitLayer;
@@ -0,0 +1,89 @@
+//! Exercise the defaults of `http::rate_limit_layer` against a real server.
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use axum::{Router, routing::get};
+use redis::{Client, aio::ConnectionManager};
+use tower::ServiceExt as _;
+use tower_redis_cell::http::rate_limit_layer;
+use tower_redis_cell::redis_cell::Policy;
+use tower_redis_cell::{ProvideRule, ProvideRuleResult, Rule};
+
+// 2 requests at once, and then 1 request per hour
+const POLICY: Policy = Policy::from_tokens_per_hour(1).max_burst(1);
+
+#[derive(Clone)]
+struct RuleProvider;
+
+impl<T> ProvideRule<Request<T>> for RuleProvider {
+    fn provide<'a>(&self, req: &'a Request<T>) -> ProvideRuleResult<'a> {
+        let key = req
+            .headers()
+            .get("x-api-key")
+            .and_then(|val| val.to_str().ok())
+            .ok_or("cannot define key, since 'x-api-key' header is missing")?;
+        Ok(Some(Rule::new(key, POLICY)))
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // launch a container with Valkey (with Redis Cell module)
+    let (_container, port) = utils::launch_redis_container().await;
+    let client = Client::open(("localhost", port)).unwrap();
+    let connection = ConnectionManager::new(client).await.unwrap();
+
+    let app = Router::new()
+        .route("/", get(|| async { "Hello, World!" }))
+        .layer(rate_limit_layer(RuleProvider, connection));
+
+    let resp = app
+        .clone()
+        .oneshot(Request::new(Body::empty()))
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+    for remaining in ["1", "0"] {
+        let resp = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["ratelimit-limit"], "2");
+        assert_eq!(resp.headers()["ratelimit-remaining"], remaining);
+    }
+
+    let resp = app.clone().oneshot(request()).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(resp.headers()["ratelimit-remaining"], "0");
+    let retry_after: u64 = resp.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 3_500);
+
+    println!("all good");
+}
+
+fn request() -> Request<Body> {
+    Request::builder()
+        .header("x-api-key", "secret")
+        .body(Body::empty())
+        .unwrap()
+}
+
+mod utils {
+    use testcontainers::ContainerAsync;
+    use testcontainers::core::IntoContainerPort as _;
+    use testcontainers::runners::AsyncRunner;
+    use testcontainers::{GenericImage, core::WaitFor};
+
+    pub async fn launch_redis_container() -> (ContainerAsync<GenericImage>, u16) {
+        let container = GenericImage::new("ghcr.io/rustworthy/valkey-cell", "latest")
+            .with_exposed_port(6379.tcp())
+            .with_wait_for(WaitFor::message_on_stdout("Ready to accept connections"))
+            .start()
+            .await
+            .unwrap();
+        let port = container.get_host_port_ipv4(6379).await.unwrap();
+        (container, port)
+    }
+}
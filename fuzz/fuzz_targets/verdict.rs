@@ -0,0 +1,62 @@
+//! Feeds arbitrary replies into the verdict decoder, which should only ever
+//! return `Ok` or `Err`, and never panic.
+//!
+//! The input is read as a sequence of tagged values, so that seeds can be
+//! written by hand, see `corpus/verdict`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use redis::{PushKind, Value};
+use tower_redis_cell::{CellThrottle, Limiter};
+
+const MAX_DEPTH: usize = 8;
+
+fn value(data: &mut &[u8], depth: usize) -> Value {
+    let Some((tag, rest)) = data.split_first() else {
+        return Value::Nil;
+    };
+    *data = rest;
+    match tag % 9 {
+        0 => Value::Nil,
+        1 => Value::Int(i64::from_le_bytes(take::<8>(data))),
+        2 => Value::BulkString(bytes(data)),
+        3 => Value::SimpleString(String::from_utf8_lossy(&bytes(data)).into_owned()),
+        4 => Value::Okay,
+        5 => Value::Double(f64::from_le_bytes(take::<8>(data))),
+        6 => Value::Boolean(take::<1>(data)[0] % 2 == 1),
+        7 if depth < MAX_DEPTH => Value::Array(values(data, depth + 1)),
+        8 if depth < MAX_DEPTH => Value::Push {
+            kind: PushKind::Message,
+            data: values(data, depth + 1),
+        },
+        _ => Value::Nil,
+    }
+}
+
+fn values(data: &mut &[u8], depth: usize) -> Vec<Value> {
+    let len = take::<1>(data)[0];
+    (0..len).map(|_| value(data, depth)).collect()
+}
+
+fn bytes(data: &mut &[u8]) -> Vec<u8> {
+    let len = (take::<1>(data)[0] as usize).min(data.len());
+    let (bytes, rest) = data.split_at(len);
+    *data = rest;
+    bytes.to_vec()
+}
+
+fn take<const N: usize>(data: &mut &[u8]) -> [u8; N] {
+    let mut buf = [0; N];
+    let len = N.min(data.len());
+    buf[..len].copy_from_slice(&data[..len]);
+    *data = &data[len..];
+    buf
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut data = data;
+    let reply = value(&mut data, 0);
+    for strict in [false, true] {
+        let _ = CellThrottle::new().strict_decode(strict).verdict(&reply);
+    }
+});
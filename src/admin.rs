@@ -0,0 +1,160 @@
+//! Operator helpers for auditing and clearing rate-limiting state.
+//!
+//! Keys are looked up with `SCAN` (never `KEYS`, which blocks the server), so
+//! these are safe to run against a live instance, but mind the semantics:
+//! the cursor-based iteration only guarantees that keys present for the whole
+//! duration of the scan are returned, keys created or deleted meanwhile may or
+//! may not be, and the `pattern` is matched on the server with glob-style
+//! syntax (`*`, `?`, `[...]`).
+//!
+//! The helpers take the [`RateLimitConfig`] the keys were created with, and
+//! match the `pattern` against the keys as the rules name them: the config's
+//! [key prefix](RateLimitConfig::key_prefix) is prepended to the pattern, and
+//! stripped from the keys returned. Keys rewritten with a
+//! [key transform](RateLimitConfig::key_transform) or hashed into
+//! [buckets](RateLimitConfig::bucket_count) are matched as stored though.
+//!
+//! In a cluster, `SCAN` only covers the node the command is routed to, so run
+//! these against every primary.
+//!
+//!```no_run
+//! use tower_redis_cell::{RateLimitConfig, admin};
+//! # struct Tenants;
+//!
+//! # async fn run(config: &RateLimitConfig<Tenants, (), (), ()>) -> redis::RedisResult<()> {
+//! let client = redis::Client::open("redis://127.0.0.1/")?;
+//! let mut connection = client.get_multiplexed_async_connection().await?;
+//!
+//! // `config` is the one the layer has been built with, e.g. shared as an `Arc`,
+//! // so with a key prefix of `rl:` this matches `rl:tenant:acme:*`
+//! let keys = admin::scan_keys(&mut connection, config, "tenant:acme:*").await?;
+//! println!("{} keys to reset: {:?}", keys.len(), keys);
+//! let deleted = admin::reset_matching(&mut connection, config, "tenant:acme:*").await?;
+//! # Ok(())
+//! # }
+//!```
+
+use crate::RateLimitConfig;
+use redis::RedisResult;
+use redis::aio::ConnectionLike;
+use std::collections::BTreeSet;
+
+/// List the keys matching the glob-style `pattern`, without the key prefix.
+pub async fn scan_keys<C, PR, ReqTy, RespTy, IntoRespTy, E>(
+    connection: &mut C,
+    config: &RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>,
+    pattern: &str,
+) -> RedisResult<Vec<String>>
+where
+    C: ConnectionLike,
+{
+    let prefix = config.key_prefix.as_deref().unwrap_or_default();
+    let pattern = prefixed(prefix, pattern);
+    // SCAN may return a key more than once
+    let mut keys = BTreeSet::new();
+    let mut cursor = 0u64;
+    loop {
+        let (next, page): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(&pattern)
+            .query_async(connection)
+            .await?;
+        keys.extend(page);
+        if next == 0 {
+            let keys = keys.into_iter().map(|key| match key.strip_prefix(prefix) {
+                Some(key) => key.to_string(),
+                None => key,
+            });
+            return Ok(keys.collect());
+        }
+        cursor = next;
+    }
+}
+
+/// Delete the keys matching the glob-style `pattern`, returning the number
+/// of keys actually deleted.
+///
+/// Resetting a key fills its bucket up, i.e. the next request is checked as
+/// if the key had never been seen.
+pub async fn reset_matching<C, PR, ReqTy, RespTy, IntoRespTy, E>(
+    connection: &mut C,
+    config: &RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>,
+    pattern: &str,
+) -> RedisResult<usize>
+where
+    C: ConnectionLike,
+{
+    let pattern = prefixed(config.key_prefix.as_deref().unwrap_or_default(), pattern);
+    let mut deleted = 0;
+    let mut cursor = 0u64;
+    loop {
+        let (next, page): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg(&pattern)
+            .query_async(connection)
+            .await?;
+        if !page.is_empty() {
+            let count: usize = redis::cmd("DEL").arg(&page).query_async(connection).await?;
+            deleted += count;
+        }
+        if next == 0 {
+            return Ok(deleted);
+        }
+        cursor = next;
+    }
+}
+
+/// Prepend the `prefix` to the `pattern`, escaping the glob-style special
+/// characters the prefix may contain.
+fn prefixed(prefix: &str, pattern: &str) -> String {
+    let mut prefixed = String::with_capacity(prefix.len() + pattern.len());
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            prefixed.push('\\');
+        }
+        prefixed.push(c);
+    }
+    prefixed.push_str(pattern);
+    prefixed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::LocalGcra;
+    use crate::{Error, Rule, rate_limit_check};
+    use redis_cell_rs::Policy;
+
+    #[tokio::test]
+    async fn matches_under_the_key_prefix() {
+        let mut redis = LocalGcra::new();
+        let policy = Policy::from_tokens_per_minute(1);
+        for key in [
+            "rl*:acme:alice",
+            "rl*:acme:bob",
+            "rl:acme:carol",
+            "acme:dave",
+        ] {
+            rate_limit_check(&mut redis, &Rule::new(key, policy))
+                .await
+                .unwrap();
+        }
+        let config: RateLimitConfig<(), (), (), ()> =
+            RateLimitConfig::new((), |_: Error, _: &()| ()).key_prefix("rl*:");
+
+        let keys = scan_keys(&mut redis, &config, "acme:*").await.unwrap();
+        assert_eq!(keys, ["acme:alice", "acme:bob"]);
+        assert_eq!(
+            reset_matching(&mut redis, &config, "acme:*").await.unwrap(),
+            2
+        );
+        assert!(
+            scan_keys(&mut redis, &config, "*")
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
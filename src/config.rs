@@ -1,5 +1,23 @@
+use crate::decision::{DecisionSender, DecisionStream};
 use crate::error::Error;
-use crate::rule::RequestAllowedDetails;
+use crate::error::ProvideRuleError;
+use crate::history::VerdictHistory;
+use crate::limiter::{CellThrottle, Limiter};
+use crate::load::LoadSignal;
+#[cfg(feature = "local-cache")]
+use crate::local::BlockedCache;
+use crate::local::LocalCounter;
+use crate::rule::{RequestAllowedDetails, Rule};
+use redis_cell_rs::{Key, Policy, Verdict};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::marker::PhantomData;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::sync::Semaphore;
 
 pub(crate) type SyncSuccessHandler<RespTy> =
     Box<dyn Fn(RequestAllowedDetails, &mut RespTy) + Send + Sync + 'static>;
@@ -9,6 +27,9 @@ pub(crate) type SyncUnruledHandler<RespTy> = Box<dyn Fn(&mut RespTy) + Send + Sy
 pub(crate) type SyncErrorHandler<ReqTy, IntoRespTy> =
     Box<dyn Fn(Error, &ReqTy) -> IntoRespTy + Send + Sync + 'static>;
 
+pub(crate) type FallibleErrorHandler<ReqTy, IntoRespTy, E> =
+    Box<dyn Fn(Error, &ReqTy) -> Result<IntoRespTy, E> + Send + Sync + 'static>;
+
 pub(crate) enum OnSuccess<RespTy> {
     Noop,
     Sync(SyncSuccessHandler<RespTy>),
@@ -19,27 +40,260 @@ pub(crate) enum OnUnruled<RespTy> {
     Sync(SyncUnruledHandler<RespTy>),
 }
 
-pub(crate) enum OnError<ReqTy, IntoRespTy> {
+pub(crate) enum OnError<ReqTy, IntoRespTy, E> {
     Sync(SyncErrorHandler<ReqTy, IntoRespTy>),
+    Fallible(FallibleErrorHandler<ReqTy, IntoRespTy, E>),
+}
+
+pub(crate) type VerdictHandler<ReqTy> = Box<dyn Fn(&Verdict, &ReqTy) + Send + Sync + 'static>;
+
+pub(crate) type SyncResponseHandler<RespTy> = Box<dyn Fn(&mut RespTy) + Send + Sync + 'static>;
+
+pub(crate) type ErrorFallback<RespTy> = Box<dyn Fn() -> RespTy + Send + Sync + 'static>;
+
+pub(crate) type PolicyAdjuster = Box<dyn Fn(Policy, &LoadSignal) -> Policy + Send + Sync + 'static>;
+
+pub(crate) type PolicyEscalator = Box<dyn Fn(Policy, &[Verdict]) -> Policy + Send + Sync + 'static>;
+
+pub(crate) type ResponseCost<RespTy> = Box<dyn Fn(&RespTy) -> usize + Send + Sync + 'static>;
+
+pub(crate) type RequestCost<ReqTy> = Box<dyn Fn(&ReqTy) -> Option<usize> + Send + Sync + 'static>;
+
+/// Delay between the attempts, see [`RateLimitConfig::retries`].
+pub const RETRY_DELAY: Duration = Duration::from_millis(50);
+
+/// How long a request waits for one of the commands in flight to complete by
+/// default, see [`RateLimitConfig::max_concurrent_redis`].
+pub const REDIS_PERMIT_WAIT: Duration = Duration::from_millis(100);
+
+pub(crate) type RedactKey = Box<dyn Fn(&str) -> String + Send + Sync + 'static>;
+
+/// Whether blocked requests are actually rejected, see [`RateLimitConfig::mode`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Mode {
+    #[default]
+    Enforce,
+    /// Consume tokens and record the verdicts as usual, but let the requests
+    /// through even when blocked.
+    Shadow,
+}
+
+/// What to do with a request when Redis cannot be consulted, see
+/// [`RateLimitConfig::on_redis_error`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FailMode {
+    /// Reject the request, i.e. invoke the error handler.
+    #[default]
+    Deny,
+    /// Pass the request through to the inner service.
+    Allow,
+}
+
+/// Where the verdicts are recorded, see [`RateLimitConfig::tracing_mode`].
+#[cfg(feature = "tracing")]
+#[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TracingMode {
+    /// On a `rate_limit` span of their own, a child of the current one.
+    #[default]
+    ChildSpan,
+    /// On the current span, e.g. the request span created by the framework.
+    CurrentSpan,
+}
+
+/// How keys are transformed before being sent to Redis, see
+/// [`RateLimitConfig::key_transform`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyTransform {
+    /// Send the key as is.
+    #[default]
+    Identity,
+    /// Send the 64-bit FNV-1a hash of the key as 16 hex digits.
+    ///
+    /// Compact and cheap, but not collision resistant: with many millions of
+    /// keys, two identities may end up sharing a limit.
+    Fnv1aHex,
+    /// Send the SHA-256 digest of the key as 64 hex digits.
+    #[cfg(feature = "sha2")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "sha2")))]
+    Sha256Hex,
+}
+
+impl KeyTransform {
+    pub(crate) fn apply(&self, key: &str) -> Option<String> {
+        match self {
+            KeyTransform::Identity => None,
+            KeyTransform::Fnv1aHex => Some(format!("{:016x}", fnv1a(key.as_bytes()))),
+            #[cfg(feature = "sha2")]
+            KeyTransform::Sha256Hex => {
+                use sha2::Digest as _;
+                use std::fmt::Write as _;
+                let digest = sha2::Sha256::digest(key.as_bytes());
+                Some(
+                    digest
+                        .iter()
+                        .fold(String::with_capacity(64), |mut hex, byte| {
+                            let _ = write!(hex, "{:02x}", byte);
+                            hex
+                        }),
+                )
+            }
+        }
+    }
 }
 
-pub struct RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy> {
+/// How keys appear in diagnostics, see [`RateLimitConfig::key_log_policy`].
+///
+/// Keys often contain personal data (emails, IP addresses), so they are hashed
+/// by default. The hash (64-bit FNV-1a, rendered as `hashed:<hex>`) lets you
+/// correlate entries for the same key, but it is not a cryptographic one and
+/// low-entropy keys like IP addresses can be recovered by brute force, so
+/// treat hashed keys as pseudonymized rather than anonymized.
+///
+///```
+/// use tower_redis_cell::{Error, KeyLogPolicy, RateLimitConfig};
+/// # struct ByUser;
+///
+/// assert_eq!(KeyLogPolicy::default(), KeyLogPolicy::Hashed);
+///
+/// // e.g. while debugging in a staging environment
+/// let config: RateLimitConfig<_, String, String, _> =
+///     RateLimitConfig::new(ByUser, |err: Error, _req: &String| err.to_string())
+///         .key_log_policy(KeyLogPolicy::Full);
+///```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyLogPolicy {
+    Full,
+    #[default]
+    Hashed,
+    Omit,
+}
+
+impl KeyLogPolicy {
+    /// Render the key as per the policy, `None` meaning it is to be left out.
+    pub(crate) fn render(&self, key: &str) -> Option<String> {
+        match self {
+            KeyLogPolicy::Full => Some(key.to_string()),
+            KeyLogPolicy::Hashed => Some(format!("hashed:{:016x}", fnv1a(key.as_bytes()))),
+            KeyLogPolicy::Omit => None,
+        }
+    }
+}
+
+/// Configuration of the rate-limiting services.
+///
+/// `E` is the error type of the inner service, which a
+/// [fallible](RateLimitConfig::new_fallible) error handler fails requests with.
+pub struct RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E = Infallible> {
     pub(crate) rule_provider: PR,
-    pub(crate) on_error: OnError<ReqTy, IntoRespTy>,
+    pub(crate) on_error: OnError<ReqTy, IntoRespTy, E>,
     pub(crate) on_success: OnSuccess<RespTy>,
     pub(crate) on_unruled: OnUnruled<RespTy>,
+    pub(crate) on_response: Option<SyncResponseHandler<RespTy>>,
+    pub(crate) on_verdict: Option<VerdictHandler<ReqTy>>,
+    pub(crate) error_fallback: Option<ErrorFallback<RespTy>>,
+    pub(crate) redact_key: Option<RedactKey>,
+    pub(crate) key_log_policy: KeyLogPolicy,
+    pub(crate) limiter: Box<dyn Limiter + Send + Sync + 'static>,
+    pub(crate) policies: HashMap<&'static str, Policy>,
+    pub(crate) min_retry_after: Option<u64>,
+    pub(crate) max_retry_after: Option<u64>,
+    pub(crate) bucket_count: Option<u64>,
+    pub(crate) key_prefix: Option<Cow<'static, str>>,
+    pub(crate) key_transform: KeyTransform,
+    pub(crate) local_counter: Option<LocalCounter>,
+    #[cfg(feature = "local-cache")]
+    pub(crate) blocked_cache: Option<BlockedCache>,
+    pub(crate) decisions: Option<DecisionSender>,
+    pub(crate) policy_adjuster: Option<(LoadSignal, PolicyAdjuster)>,
+    pub(crate) escalation: Option<(VerdictHistory, PolicyEscalator)>,
+    pub(crate) mode: Mode,
+    pub(crate) fail_mode: FailMode,
+    pub(crate) retries: usize,
+    pub(crate) pool_readiness: bool,
+    #[cfg(feature = "tracing")]
+    pub(crate) tracing_mode: TracingMode,
+    pub(crate) would_block_total: AtomicU64,
+    pub(crate) cost: Option<RequestCost<ReqTy>>,
+    pub(crate) post_charge: Option<ResponseCost<RespTy>>,
+    pub(crate) redis_permits: Option<Semaphore>,
+    pub(crate) redis_permit_wait: Duration,
+    pub(crate) in_flight: Option<Arc<Semaphore>>,
 }
 
-impl<RP, ReqTy, RespTy, IntoRespTy> RateLimitConfig<RP, ReqTy, RespTy, IntoRespTy> {
+impl<RP, ReqTy, RespTy, IntoRespTy, E> RateLimitConfig<RP, ReqTy, RespTy, IntoRespTy, E> {
     pub fn new<EH>(rule_provider: RP, error_handler: EH) -> Self
     where
         EH: Fn(Error, &ReqTy) -> IntoRespTy + Send + Sync + 'static,
     {
+        Self::with_error_handler(rule_provider, OnError::Sync(Box::new(error_handler)))
+    }
+
+    /// Like [`RateLimitConfig::new`], but with an error handler which can
+    /// either synthesize a response or fail the request with an error of the
+    /// inner service, e.g. to turn Redis being down into a variant of one's
+    /// own error enum.
+    ///
+    /// The handler's error type `E` has got to be the inner service's one.
+    ///
+    ///```
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct ByCaller;
+    ///
+    /// #[derive(Debug)]
+    /// enum AppError {
+    ///     Unavailable,
+    /// }
+    ///
+    /// let config: RateLimitConfig<_, String, String, _, AppError> =
+    ///     RateLimitConfig::new_fallible(ByCaller, |err: Error, _req: &String| match err {
+    ///         Error::RateLimit(details) => Ok(format!("retry in {}s", details.retry_after())),
+    ///         _ => Err(AppError::Unavailable),
+    ///     });
+    ///```
+    pub fn new_fallible<EH>(rule_provider: RP, error_handler: EH) -> Self
+    where
+        EH: Fn(Error, &ReqTy) -> Result<IntoRespTy, E> + Send + Sync + 'static,
+    {
+        Self::with_error_handler(rule_provider, OnError::Fallible(Box::new(error_handler)))
+    }
+
+    fn with_error_handler(rule_provider: RP, on_error: OnError<ReqTy, IntoRespTy, E>) -> Self {
         RateLimitConfig {
             rule_provider,
-            on_error: OnError::Sync(Box::new(error_handler)),
+            on_error,
             on_success: OnSuccess::Noop,
             on_unruled: OnUnruled::Noop,
+            on_response: None,
+            on_verdict: None,
+            error_fallback: None,
+            redact_key: None,
+            key_log_policy: KeyLogPolicy::Hashed,
+            limiter: Box::new(CellThrottle::new()),
+            policies: HashMap::new(),
+            min_retry_after: None,
+            max_retry_after: None,
+            bucket_count: None,
+            key_prefix: None,
+            key_transform: KeyTransform::Identity,
+            local_counter: None,
+            #[cfg(feature = "local-cache")]
+            blocked_cache: None,
+            decisions: None,
+            policy_adjuster: None,
+            escalation: None,
+            mode: Mode::Enforce,
+            fail_mode: FailMode::Deny,
+            retries: 0,
+            pool_readiness: false,
+            #[cfg(feature = "tracing")]
+            tracing_mode: TracingMode::ChildSpan,
+            would_block_total: AtomicU64::new(0),
+            cost: None,
+            post_charge: None,
+            redis_permits: None,
+            redis_permit_wait: REDIS_PERMIT_WAIT,
+            in_flight: None,
         }
     }
 
@@ -58,4 +312,1811 @@ impl<RP, ReqTy, RespTy, IntoRespTy> RateLimitConfig<RP, ReqTy, RespTy, IntoRespT
         self.on_unruled = OnUnruled::Sync(Box::new(handler));
         self
     }
+
+    /// Mutate every response leaving the layer, e.g. to add CORS headers.
+    ///
+    /// The hook runs last: the error, success, or unruled handler builds or
+    /// touches the response first, and then `on_response` gets to mutate it.
+    /// This includes responses produced by the error handler, which never
+    /// reach the inner service or any layers wrapped by this one.
+    ///
+    ///```
+    /// use axum::http::{HeaderValue, Request, Response, StatusCode, header};
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// let config = RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| {
+    ///     let mut resp = Response::new(());
+    ///     *resp.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    ///     resp
+    /// })
+    /// .on_response(|resp: &mut Response<()>| {
+    ///     let origin = HeaderValue::from_static("*");
+    ///     resp.headers_mut().insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    /// });
+    /// # let _: RateLimitConfig<_, _, _, _> = config;
+    ///```
+    pub fn on_response<H>(mut self, handler: H) -> Self
+    where
+        H: Fn(&mut RespTy) + Send + Sync + 'static,
+    {
+        self.on_response = Some(Box::new(handler));
+        self
+    }
+
+    /// Observe every verdict, allowed or blocked, e.g. for an audit log.
+    ///
+    /// The hook runs as soon as the verdict is in, before the request is
+    /// either passed through or rejected, and so regardless of which of the
+    /// success and error handlers runs next. With several rules, only the
+    /// verdict that decides the request's fate is reported.
+    ///
+    ///```
+    /// use std::sync::{Arc, Mutex};
+    /// use tower_redis_cell::redis_cell::Verdict;
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct ByCaller;
+    ///
+    /// let audit = Arc::new(Mutex::new(Vec::new()));
+    /// let config: RateLimitConfig<_, _, &str, _> =
+    ///     RateLimitConfig::new(ByCaller, |_err: Error, _req: &String| "blocked").on_verdict({
+    ///         let audit = Arc::clone(&audit);
+    ///         move |verdict: &Verdict, caller: &String| {
+    ///             let blocked = matches!(verdict, Verdict::Blocked(_));
+    ///             audit.lock().unwrap().push((caller.clone(), blocked));
+    ///         }
+    ///     });
+    ///```
+    pub fn on_verdict<H>(mut self, handler: H) -> Self
+    where
+        H: Fn(&Verdict, &ReqTy) + Send + Sync + 'static,
+    {
+        self.on_verdict = Some(Box::new(handler));
+        self
+    }
+
+    /// Respond with what `fallback` builds should the error handler panic.
+    ///
+    /// Without a fallback, a panicking error handler unwinds through the
+    /// service, and typically takes down the task serving the request. With
+    /// a fallback, the panic is caught with [`std::panic::catch_unwind`], still
+    /// reported by the panic hook (which prints it to stderr by default), and
+    /// the [`on_response`](Self::on_response) hook is applied to the fallback
+    /// response as usual. The error handler is assumed to leave nothing in an
+    /// inconsistent state when it panics, and a panic cannot be caught at all
+    /// with `panic = "abort"`.
+    ///
+    ///```
+    /// use axum::http::{Request, Response, StatusCode};
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// let config = RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| -> Response<String> {
+    ///     unimplemented!("forgot to handle this one")
+    /// })
+    /// .error_fallback(|| {
+    ///     let mut resp = Response::new("internal server error".to_string());
+    ///     *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    ///     resp
+    /// });
+    /// # let _: RateLimitConfig<_, _, _, _> = config;
+    ///```
+    pub fn error_fallback<F>(mut self, fallback: F) -> Self
+    where
+        F: Fn() -> RespTy + Send + Sync + 'static,
+    {
+        self.error_fallback = Some(Box::new(fallback));
+        self
+    }
+
+    /// Redact the key whenever it is rendered for diagnostics, e.g. in the
+    /// command context of [`Error::Redis`].
+    ///
+    /// This takes precedence over [`Self::key_log_policy`].
+    pub fn redact_key<F>(mut self, redact: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.redact_key = Some(Box::new(redact));
+        self
+    }
+
+    /// Use a custom [`Limiter`] instead of the default [`CellThrottle`].
+    pub fn limiter<L>(mut self, limiter: L) -> Self
+    where
+        L: Limiter + Send + Sync + 'static,
+    {
+        self.limiter = Box::new(limiter);
+        self
+    }
+
+    /// Register the policies of the resources, so that the rule provider can
+    /// refer to them by name with [`Rule::for_resource`].
+    ///
+    /// With the `tracing` feature on, the layers log a warning for each of
+    /// these policies failing [`PolicyExt::sanity_check`](crate::PolicyExt::sanity_check)
+    /// when built.
+    ///
+    ///```
+    /// use axum::http::{Request, Response, StatusCode};
+    /// use std::collections::HashMap;
+    /// use tower_redis_cell::redis_cell::Policy;
+    /// use tower_redis_cell::{Error, ProvideRule, ProvideRuleResult, RateLimitConfig, Rule};
+    ///
+    /// #[derive(Clone)]
+    /// struct ByRoute;
+    ///
+    /// impl<B> ProvideRule<Request<B>> for ByRoute {
+    ///     fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+    ///         let resource = match req.uri().path() {
+    ///             "/search" => "search",
+    ///             _ => "unknown",
+    ///         };
+    ///         Ok(Some(Rule::for_resource("user123", resource)))
+    ///     }
+    /// }
+    ///
+    /// // requests for unknown resources fail with `Error::ProvideRule`
+    /// let policies = HashMap::from([("search", Policy::from_tokens_per_minute(1))]);
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(ByRoute, |err: Error, _req: &Request<()>| {
+    ///         let mut resp = Response::new(());
+    ///         *resp.status_mut() = match err {
+    ///             Error::RateLimit(_) => StatusCode::TOO_MANY_REQUESTS,
+    ///             _ => StatusCode::INTERNAL_SERVER_ERROR,
+    ///         };
+    ///         resp
+    ///     })
+    ///     .policies(policies);
+    ///```
+    pub fn policies(mut self, policies: HashMap<&'static str, Policy>) -> Self {
+        self.policies = policies;
+        self
+    }
+
+    /// Use the default limiter with strict decoding, see [`CellThrottle::strict_decode`].
+    ///
+    /// Note that this replaces any limiter set previously.
+    pub fn strict_decode(self, strict: bool) -> Self {
+        self.limiter(CellThrottle::new().strict_decode(strict))
+    }
+
+    /// Advertise a retry-after of at least `secs` seconds, unless the rule
+    /// sets its own floor with [`Rule::min_retry_after`].
+    pub fn min_retry_after(mut self, secs: u64) -> Self {
+        self.min_retry_after = Some(secs);
+        self
+    }
+
+    /// Advertise a retry-after of at most `secs` seconds, unless the rule
+    /// sets its own ceiling with [`Rule::max_retry_after`].
+    pub fn max_retry_after(mut self, secs: u64) -> Self {
+        self.max_retry_after = Some(secs);
+        self
+    }
+
+    /// Hash every key into one of `count` fixed buckets named
+    /// `{scope}:key_bucket_{h}`.
+    ///
+    /// This strictly bounds the number of keys (and so the memory) used in
+    /// Redis, at the expense of precision: different identities sharing a
+    /// bucket share the limit too, so a noisy client can get its neighbours
+    /// blocked. This is meant for coarse global protections rather than for
+    /// per-client fairness. The same key always lands in the same bucket.
+    ///
+    /// The buckets are per resource and policy (the `scope`, e.g.
+    /// `search:basic` for the resource `search` and the policy `basic`), as a
+    /// cell checked against two policies would keep its state under whichever
+    /// policy touched it last. A policy without a name is told apart by its
+    /// tokens, period, and burst instead, so `count` buckets are used for
+    /// every distinct policy.
+    ///
+    ///```
+    /// use axum::http::{Request, Response};
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct ByUser;
+    ///
+    /// // all the users share 4 cells
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(ByUser, |_err: Error, _req: &Request<()>| Response::new(()))
+    ///         .bucket_count(4);
+    ///```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is zero.
+    pub fn bucket_count(mut self, count: u64) -> Self {
+        assert!(count > 0, "bucket count must be positive");
+        self.bucket_count = Some(count);
+        self
+    }
+
+    /// Prepend `prefix` to every key sent to Redis, e.g. to scope the rate
+    /// limiting keys of a service sharing the instance with others.
+    ///
+    /// The prefix is applied once the rule has been provided (and bucketed, see
+    /// [`Self::bucket_count`]), and so the keys in the error handler, the
+    /// decision events, and the logs are the full keys as found in Redis.
+    ///
+    ///```
+    /// use axum::http::{Request, Response};
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct ByUser;
+    ///
+    /// // e.g. `svc_a:rl:user123`, also in the error handler
+    /// let config = RateLimitConfig::new(ByUser, |err: Error, _req: &Request<()>| match err {
+    ///     Error::RateLimit(details) => Response::new(details.rule.key.to_string()),
+    ///     err => Response::new(err.to_string()),
+    /// })
+    /// .key_prefix("svc_a:rl:");
+    /// # let _: RateLimitConfig<_, _, Response<String>, _> = config;
+    ///```
+    pub fn key_prefix(mut self, prefix: impl Into<Cow<'static, str>>) -> Self {
+        self.key_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Hash every key before it is sent to Redis, e.g. to bound the memory
+    /// taken by long keys like URLs or JWT subjects.
+    ///
+    /// The transform is deterministic, so repeated requests for the same key
+    /// hit the same cell. It is applied before [`Self::key_prefix`], which
+    /// remains readable. Note that enabling (or changing) the transform
+    /// changes the keys sent to Redis, and so the limits start afresh, the
+    /// cells of the original keys being left to expire.
+    ///
+    ///```
+    /// use axum::http::{Request, Response};
+    /// use tower_redis_cell::{Error, KeyTransform, RateLimitConfig};
+    /// # struct ByUrl;
+    ///
+    /// // e.g. `rl:` followed by 16 hex digits, whatever the length of the URL
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(ByUrl, |_err: Error, _req: &Request<()>| Response::new(()))
+    ///         .key_prefix("rl:")
+    ///         .key_transform(KeyTransform::Fnv1aHex);
+    ///```
+    pub fn key_transform(mut self, transform: KeyTransform) -> Self {
+        self.key_transform = transform;
+        self
+    }
+
+    /// Only consult Redis every `every` requests per key, as long as the keys
+    /// are far enough from their limits.
+    ///
+    /// For keys that rarely approach their limit, the round trip to Redis on
+    /// every request is wasteful. With this option on, the remaining tokens
+    /// reported by Redis are tracked locally, and a request is allowed without
+    /// consulting Redis if the tokens owed by all the requests allowed locally
+    /// since the last round trip (and by the request which is going to make
+    /// the next one) still fit into that estimate. The tokens owed
+    /// are charged in Redis with the next request that does make the round
+    /// trip, which happens at least every `every` requests or as soon as
+    /// the estimate nears the limit.
+    ///
+    /// This is an eventually consistent optimization. Other instances (and
+    /// other keys hashed into the same [bucket](Self::bucket_count)) consuming
+    /// tokens are not seen until the next round trip. Should that round trip
+    /// get blocked, the owed tokens are charged on their own (or as many of
+    /// them as are left), and they are only forgiven if it fails. So each
+    /// instance can over-allow by at most `every - 1` requests per key and
+    /// round trip.
+    /// Passing `1` effectively turns the option off.
+    ///
+    ///```
+    /// use axum::http::{Request, Response, StatusCode};
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// // a round trip for at least every 5 requests
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| {
+    ///         let mut resp = Response::new(());
+    ///         *resp.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    ///         resp
+    ///     })
+    ///     .local_counter(5);
+    ///```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `every` is zero.
+    pub fn local_counter(mut self, every: usize) -> Self {
+        assert!(every > 0, "local counter sync interval must be positive");
+        self.local_counter = Some(LocalCounter::new(every));
+        self
+    }
+
+    /// Remember up to `capacity` keys Redis has blocked, and block further
+    /// requests for them without a round trip until their retry-after is over.
+    /// Nor is a connection taken (out of the pool, for the pooled services)
+    /// for such a request.
+    ///
+    /// Only blocks are cached, never allows, so that the requests served by
+    /// other replicas are still accounted for. As the retry-after reported by
+    /// Redis Cell is rounded up to whole seconds, a key may be blocked locally
+    /// for up to a second longer than Redis would, and a cached block applies
+    /// to all the requests for the key, whatever their cost. Rules with a
+    /// [fallback](crate::Rule::fallback) are not cached, and a request checked
+    /// against several rules is blocked as soon as any of them is. Once full,
+    /// the blocks lifted the soonest are evicted first.
+    ///
+    ///```
+    /// use axum::http::{Request, Response, StatusCode};
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(Global, |err: Error, _req: &Request<()>| {
+    ///         let mut resp = Response::new(());
+    ///         *resp.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    ///         if let Error::RateLimit(details) = err {
+    ///             resp.headers_mut().insert("retry-after", details.retry_after().into());
+    ///         }
+    ///         resp
+    ///     })
+    ///     .blocked_cache(10_000);
+    ///```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    #[cfg(feature = "local-cache")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "local-cache")))]
+    pub fn blocked_cache(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "blocked cache capacity must be positive");
+        self.blocked_cache = Some(BlockedCache::new(capacity));
+        self
+    }
+
+    /// Have at most `max` rate-limiting commands in flight at once.
+    ///
+    /// This bounds the load the limiter itself puts on Redis during a traffic
+    /// spike. The limit is shared by all the services built with this config,
+    /// and requests over it wait for a command to complete before sending
+    /// theirs, for up to [`REDIS_PERMIT_WAIT`] (see [`Self::redis_permit_wait`]).
+    /// A request still waiting by then fails with [`Error::Overloaded`], which
+    /// is handled as per [`Self::on_redis_error`], just like Redis being
+    /// unavailable. Requests allowed by the [local counter](Self::local_counter)
+    /// do not count towards the limit.
+    ///
+    ///```
+    /// use axum::http::{Request, Response};
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| Response::new(()))
+    ///         .max_concurrent_redis(3);
+    ///```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero or exceeds [`Semaphore::MAX_PERMITS`].
+    pub fn max_concurrent_redis(mut self, max: usize) -> Self {
+        assert!(max > 0, "maximum concurrency must be positive");
+        assert!(
+            max <= Semaphore::MAX_PERMITS,
+            "maximum concurrency is too large"
+        );
+        self.redis_permits = Some(Semaphore::new(max));
+        self
+    }
+
+    /// Have the requests over [`Self::max_concurrent_redis`] wait for up to
+    /// `wait` (instead of [`REDIS_PERMIT_WAIT`]) before failing with
+    /// [`Error::Overloaded`].
+    pub fn redis_permit_wait(mut self, wait: Duration) -> Self {
+        self.redis_permit_wait = wait;
+        self
+    }
+
+    /// Have the services built with this config accept at most `max` calls
+    /// in flight at once, a local safety valve complementing the distributed
+    /// limit during a flood.
+    ///
+    /// Like `tower::limit::ConcurrencyLimit`, a service reserves a slot in
+    /// `poll_ready`, which returns `Pending` while all of them are taken, and
+    /// holds it until the response future completes (or is dropped). Behind a
+    /// [`Buffer`](https://docs.rs/tower/latest/tower/buffer/struct.Buffer.html),
+    /// the requests over the limit wait in the buffer (and, once that is full,
+    /// in front of it), while a load-shedding layer (e.g. `tower::load_shed`)
+    /// rejects them right away. Unlike [`Self::max_concurrent_redis`], the
+    /// whole call counts, including the inner service. Disabled by default.
+    ///
+    ///```
+    /// use axum::http::{Request, Response};
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| Response::new(()))
+    ///         .max_in_flight(1_000);
+    ///```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero or exceeds [`Semaphore::MAX_PERMITS`].
+    pub fn max_in_flight(mut self, max: usize) -> Self {
+        assert!(max > 0, "maximum concurrency must be positive");
+        assert!(
+            max <= Semaphore::MAX_PERMITS,
+            "maximum concurrency is too large"
+        );
+        self.in_flight = Some(Arc::new(Semaphore::new(max)));
+        self
+    }
+
+    /// Publish every decision as a [`DecisionEvent`](crate::DecisionEvent) on
+    /// a bounded channel with room for `capacity` events.
+    ///
+    /// Events are consumed from the returned stream, e.g. to aggregate and
+    /// export them asynchronously. The hot path never waits for the consumer:
+    /// if the channel is full, the event is dropped and counted, see
+    /// [`DecisionStream::dropped`].
+    ///
+    ///```
+    /// use axum::http::{Request, Response};
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// # async fn export(_: tower_redis_cell::DecisionEvent) {}
+    /// # async fn run() {
+    /// let (config, mut decisions) =
+    ///     RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| Response::new(()))
+    ///         .decision_channel(1_024);
+    /// # let _: RateLimitConfig<_, _, Response<()>, _> = config;
+    /// while let Some(event) = decisions.recv().await {
+    ///     export(event).await;
+    /// }
+    /// # }
+    ///```
+    pub fn decision_channel(mut self, capacity: usize) -> (Self, DecisionStream) {
+        let (sender, stream) = DecisionSender::channel(capacity);
+        self.decisions = Some(sender);
+        (self, stream)
+    }
+
+    /// Adjust the rule's policy to the current system load before checking it.
+    ///
+    /// This enables brownout-style adaptive limiting: tighten the limits when
+    /// the `signal` reports high load, and relax them back once it is gone.
+    /// The adjuster runs on every request, so keep it cheap.
+    ///
+    ///```
+    /// use axum::http::{Request, Response};
+    /// use tower_redis_cell::{Error, LoadSignal, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// // CPU usage in percent, sampled elsewhere
+    /// let cpu = LoadSignal::new();
+    ///
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| Response::new(()))
+    ///         .policy_adjuster(cpu.clone(), |mut policy, cpu| {
+    ///             if cpu.get() >= 90 {
+    ///                 policy.tokens /= 4;
+    ///             }
+    ///             policy
+    ///         });
+    ///```
+    pub fn policy_adjuster<F>(mut self, signal: LoadSignal, adjuster: F) -> Self
+    where
+        F: Fn(Policy, &LoadSignal) -> Policy + Send + Sync + 'static,
+    {
+        self.policy_adjuster = Some((signal, Box::new(adjuster)));
+        self
+    }
+
+    /// Tighten the policy for keys based on how they have fared recently, e.g.
+    /// to penalize a client which keeps on hammering the service although
+    /// it is being blocked.
+    ///
+    /// The last `depth` verdicts are recorded per key, oldest first, and the
+    /// `escalator` is handed them along with the rule's policy to decide on
+    /// the policy to apply. A key's history is forgotten once no verdict has
+    /// been recorded for it within `ttl`. The history is kept in memory, so
+    /// each instance only sees the verdicts for the requests it has served
+    /// itself, and keys hashed into the same [bucket](Self::bucket_count)
+    /// share their history.
+    ///
+    ///```
+    /// use axum::http::{Request, Response};
+    /// use std::time::Duration;
+    /// use tower_redis_cell::redis_cell::Verdict;
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// // halve the rate for every recent block
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| Response::new(()))
+    ///         .escalation(3, Duration::from_secs(600), |mut policy, verdicts| {
+    ///             let blocked = verdicts.iter().filter(|v| matches!(v, Verdict::Blocked(_))).count();
+    ///             policy.tokens >>= blocked;
+    ///             policy
+    ///         });
+    ///```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `depth` is zero.
+    pub fn escalation<F>(mut self, depth: usize, ttl: Duration, escalator: F) -> Self
+    where
+        F: Fn(Policy, &[Verdict]) -> Policy + Send + Sync + 'static,
+    {
+        assert!(depth > 0, "verdict history depth must be positive");
+        let history = VerdictHistory::new(depth, ttl);
+        self.escalation = Some((history, Box::new(escalator)));
+        self
+    }
+
+    /// Enforce the limits (the default) or run in shadow mode.
+    ///
+    /// In [`Mode::Shadow`], the throttle command is still issued (so tokens
+    /// are consumed as if the limits were enforced, which keeps the
+    /// measurements realistic) and the verdict is recorded as usual, e.g. on
+    /// the [decision channel](Self::decision_channel), but blocked requests
+    /// are passed through to the inner service rather than to the error
+    /// handler. Neither the success nor the error handler runs for those.
+    /// This helps to measure how often a limit _would_ trip during rollout:
+    /// with the `metrics` feature, such requests are counted in
+    /// `ratelimit_would_block_total`, see also [`Self::would_block_total`].
+    ///
+    ///```
+    /// use axum::http::{Request, Response, StatusCode};
+    /// use std::sync::Arc;
+    /// use tower_redis_cell::{Error, Mode, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| {
+    ///         let mut resp = Response::new(());
+    ///         *resp.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    ///         resp
+    ///     })
+    ///     .mode(Mode::Shadow);
+    /// // keep a handle on the config to read the counter
+    /// let config = Arc::new(config);
+    /// assert_eq!(config.would_block_total(), 0);
+    ///```
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Fail closed (the default) or open when Redis cannot be consulted.
+    ///
+    /// With [`FailMode::Allow`], a request for which a connection cannot be
+    /// procured, or for which the command fails or yields a reply that cannot
+    /// be decoded, is passed through to the inner service instead of being
+    /// reported to the error handler, so that a Redis outage does not take
+    /// down the whole API. There is no verdict for such a request, so neither
+    /// the [success](Self::on_success) nor the [unruled](Self::on_unruled)
+    /// handler is invoked, while the [`on_response`](Self::on_response) hook
+    /// still is.
+    ///
+    ///```
+    /// use axum::http::{Request, Response, StatusCode};
+    /// use tower_redis_cell::{Error, FailMode, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// let config = RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| {
+    ///     let mut resp = Response::new(());
+    ///     *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    ///     resp
+    /// })
+    /// .on_redis_error(FailMode::Allow);
+    /// # let _: RateLimitConfig<_, _, Response<()>, _> = config;
+    ///```
+    pub fn on_redis_error(mut self, mode: FailMode) -> Self {
+        self.fail_mode = mode;
+        self
+    }
+
+    /// Retry the round trip to Redis up to `retries` times, [`RETRY_DELAY`]
+    /// apart, when it fails with a connection or I/O error (e.g. a dropped
+    /// connection which [`ConnectionManager`](redis::aio::ConnectionManager)
+    /// is re-establishing), before giving up and invoking the error handler.
+    ///
+    /// Other errors (e.g. an error reply, or a reply which cannot be decoded)
+    /// are never retried. Retries add latency to the failure path, and a
+    /// command lost along with the connection may well have been applied,
+    /// in which case retrying it consumes the tokens twice. Defaults to `0`.
+    ///
+    ///```
+    /// use axum::http::{Request, Response, StatusCode};
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| {
+    ///         let mut resp = Response::new(());
+    ///         *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    ///         resp
+    ///     })
+    ///     .retries(2);
+    ///```
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Have the pooled services (see the `deadpool` and `bb8` features) report
+    /// they are not ready while every connection in the pool is in use.
+    ///
+    /// Tower only expects `call` once `poll_ready` has returned `Ready`. With
+    /// this enabled, a service waits in `poll_ready` for one of as many slots
+    /// as the pool has connections at most (taken when the layer or service is
+    /// created), and holds on to it until the call completes. The slots are
+    /// shared by the services created from the same layer, so connections
+    /// checked out of the pool by anyone else are not accounted for. Pair with
+    /// a load-shedding layer (e.g. `tower::load_shed`) to reject requests
+    /// rather than wait for a slot. Disabled by default, in which case
+    /// readiness is that of the inner service. Has no effect on
+    /// [`RateLimit`](crate::RateLimit).
+    ///
+    /// **Ignored by the `mobc` services**: a `mobc` pool only tells its size
+    /// asynchronously, so they never wait for a connection in `poll_ready`.
+    pub fn pool_readiness(mut self, enabled: bool) -> Self {
+        self.pool_readiness = enabled;
+        self
+    }
+
+    /// Choose the span the verdicts are recorded on (a child span by default).
+    ///
+    /// The (rendered, see [`Self::key_log_policy`]) key, the policy name, the
+    /// rule's resource, the verdict, the remaining tokens, and the retry-after
+    /// of blocked requests are recorded as `rate_limit.key`, `rate_limit.policy`,
+    /// `rate_limit.resource`, `rate_limit.verdict`, `rate_limit.remaining`, and
+    /// `rate_limit.retry_after` respectively, once the verdict is in. The rule's
+    /// [labels](crate::Rule::labels), if any, are recorded as `rate_limit.labels`
+    /// in the `name=value,name=value` form. With [`TracingMode::CurrentSpan`],
+    /// no span is created, but the fields are recorded on whatever span is
+    /// current instead. Since `tracing` only records fields a span has been
+    /// created with, that span has to declare them (as [`Empty`](tracing::field::Empty)).
+    ///
+    ///```
+    /// use axum::http::{Request, Response};
+    /// use tower_redis_cell::{Error, RateLimitConfig, TracingMode};
+    /// use tracing::field::Empty;
+    /// # struct Global;
+    ///
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| Response::new(()))
+    ///         .tracing_mode(TracingMode::CurrentSpan);
+    ///
+    /// // as created by the framework, e.g. in a `TraceLayer::make_span_with`
+    /// let request_span = tracing::info_span!(
+    ///     "request",
+    ///     rate_limit.key = Empty,
+    ///     rate_limit.policy = Empty,
+    ///     rate_limit.resource = Empty,
+    ///     rate_limit.verdict = Empty,
+    ///     rate_limit.remaining = Empty,
+    ///     rate_limit.retry_after = Empty,
+    ///     rate_limit.labels = Empty,
+    /// );
+    ///```
+    #[cfg(feature = "tracing")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "tracing")))]
+    pub fn tracing_mode(mut self, mode: TracingMode) -> Self {
+        self.tracing_mode = mode;
+        self
+    }
+
+    /// Number of requests let through in [`Mode::Shadow`] which would have
+    /// been blocked otherwise, for convenience: with the `metrics` feature,
+    /// dashboards get the `ratelimit_would_block_total` counter instead.
+    pub fn would_block_total(&self) -> u64 {
+        self.would_block_total.load(Ordering::Relaxed)
+    }
+
+    /// Derive the number of tokens to apply from the request itself, e.g. from
+    /// a cost computed upstream.
+    ///
+    /// If the closure returns `None`, the rule's `policy.apply` is used.
+    /// With the `http` feature on, see also [`Self::cost_from_extension`].
+    pub fn cost<F>(mut self, cost: F) -> Self
+    where
+        F: Fn(&ReqTy) -> Option<usize> + Send + Sync + 'static,
+    {
+        self.cost = Some(Box::new(cost));
+        self
+    }
+
+    /// Charge allowed requests a cost only known once the inner service has
+    /// responded, e.g. the number of rows returned.
+    ///
+    /// The request is let through charging the rule's usual cost (a single
+    /// token by default), and once the response is in, the tokens returned
+    /// by `cost` are charged with a follow-up command against the cell which
+    /// allowed the request (that of the [fallback](crate::Rule::fallback) policy
+    /// if it came to that). The response is never retroactively blocked,
+    /// whatever the outcome of the follow-up charge, which also means it cannot
+    /// fail the request should Redis be unavailable by then.
+    ///
+    /// The follow-up command is awaited before the response is handed out, so
+    /// this adds a round trip to Redis to the latency of every allowed request
+    /// with a non-zero cost.
+    ///
+    /// Redis Cell only ever charges the tokens if all of them fit into the
+    /// bucket: if the cost exceeds what is left, the bucket is drained instead,
+    /// and the excess is not carried over (the bucket never goes negative).
+    ///
+    ///```
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct ByCaller;
+    ///
+    /// // a query returning rows, charged a token per row
+    /// let config: RateLimitConfig<_, String, _, Vec<u32>> =
+    ///     RateLimitConfig::new(ByCaller, |_err: Error, _req: &String| Vec::new())
+    ///         .post_charge(|rows: &Vec<u32>| rows.len());
+    ///```
+    pub fn post_charge<F>(mut self, cost: F) -> Self
+    where
+        F: Fn(&RespTy) -> usize + Send + Sync + 'static,
+    {
+        self.post_charge = Some(Box::new(cost));
+        self
+    }
+
+    /// Override the tokens to apply if [`Self::cost`] yields any.
+    pub(crate) fn costed<'a>(&self, mut rule: Rule<'a>, req: &ReqTy) -> Rule<'a> {
+        if let Some(apply) = self.cost.as_ref().and_then(|cost| cost(req)) {
+            rule.policy.apply = apply;
+        }
+        rule
+    }
+
+    /// Adjust the rule's policy if [`Self::policy_adjuster`] is set.
+    pub(crate) fn adjusted<'a>(&self, mut rule: Rule<'a>) -> Rule<'a> {
+        if let Some((ref signal, ref adjust)) = self.policy_adjuster {
+            rule.policy = adjust(rule.policy, signal);
+        }
+        rule
+    }
+
+    /// Look the rule's policy up if it is to be, see [`Rule::for_resource`].
+    pub(crate) fn resolved<'a>(
+        &self,
+        mut rule: Rule<'a>,
+    ) -> Result<Rule<'a>, ProvideRuleError<'a>> {
+        let Some(resource) = rule.unresolved else {
+            return Ok(rule);
+        };
+        let Some(policy) = self.policies.get(resource) else {
+            let detail = format!("no policy registered for resource {:?}", resource);
+            return Err(ProvideRuleError::new(rule.key, detail));
+        };
+        rule.policy = *policy;
+        rule.unresolved = None;
+        Ok(rule)
+    }
+
+    /// Apply all the rule transformations configured, in order.
+    pub(crate) fn prepared<'a>(&self, rule: Rule<'a>, req: &ReqTy) -> Rule<'a> {
+        let rule = self.costed(self.applied(rule), req);
+        self.escalated(self.prefixed(self.adjusted(self.bucketed(rule))))
+    }
+
+    /// Fold the rule's [`apply`](Rule::apply) override into its (resolved) policy.
+    pub(crate) fn applied<'a>(&self, mut rule: Rule<'a>) -> Rule<'a> {
+        rule.policy = rule.charged_policy();
+        rule.apply = None;
+        rule
+    }
+
+    /// Escalate the rule's policy if [`Self::escalation`] is set.
+    pub(crate) fn escalated<'a>(&self, mut rule: Rule<'a>) -> Rule<'a> {
+        if let Some((ref history, ref escalate)) = self.escalation {
+            let key = rule.key.to_string();
+            rule.policy = history.with(&key, |verdicts| escalate(rule.policy, verdicts));
+        }
+        rule
+    }
+
+    /// Record the verdict for [`Self::escalation`], if set.
+    pub(crate) fn record(&self, key: &Key<'_>, verdict: &Verdict) {
+        if let Some((ref history, _)) = self.escalation {
+            history.record(key.to_string(), verdict);
+        }
+    }
+
+    /// Warn about the [registered](Self::policies) policies which misbehave,
+    /// see [`PolicyExt::sanity_check`](crate::PolicyExt::sanity_check).
+    #[cfg(feature = "tracing")]
+    pub(crate) fn warn_misconfigured(&self) {
+        use crate::policy::PolicyExt as _;
+        for (resource, policy) in &self.policies {
+            if let Err(warning) = policy.sanity_check() {
+                tracing::warn!(
+                    resource,
+                    policy = policy.name,
+                    %warning,
+                    "misconfigured rate-limiting policy"
+                );
+            }
+        }
+    }
+
+    /// The span to record the decision on, as per [`Self::tracing_mode`].
+    #[cfg(feature = "tracing")]
+    pub(crate) fn span(&self) -> tracing::Span {
+        use tracing::field::Empty;
+        match self.tracing_mode {
+            TracingMode::ChildSpan => tracing::info_span!(
+                "rate_limit",
+                rate_limit.key = Empty,
+                rate_limit.policy = Empty,
+                rate_limit.resource = Empty,
+                rate_limit.verdict = Empty,
+                rate_limit.remaining = Empty,
+                rate_limit.retry_after = Empty,
+                rate_limit.labels = Empty,
+            ),
+            TracingMode::CurrentSpan => tracing::Span::current(),
+        }
+    }
+
+    /// Record the rule and its verdict on the span created with [`Self::span`].
+    #[cfg(feature = "tracing")]
+    pub(crate) fn trace(&self, span: &tracing::Span, rule: &Rule<'_>, verdict: &Verdict) {
+        if let Some(key) = self.render_key(&rule.key.to_string()) {
+            span.record("rate_limit.key", key);
+        }
+        if let Some(name) = rule.policy.name {
+            span.record("rate_limit.policy", name);
+        }
+        if let Some(resource) = rule.resource {
+            span.record("rate_limit.resource", resource);
+        }
+        if !rule.labels.is_empty() {
+            let labels: Vec<_> = rule
+                .labels
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect();
+            span.record("rate_limit.labels", labels.join(","));
+        }
+        match verdict {
+            Verdict::Allowed(details) => {
+                span.record("rate_limit.verdict", "allowed");
+                span.record("rate_limit.remaining", details.remaining);
+            }
+            Verdict::Blocked(details) => {
+                span.record("rate_limit.verdict", "blocked");
+                span.record("rate_limit.remaining", details.remaining);
+                span.record("rate_limit.retry_after", details.retry_after);
+            }
+        }
+    }
+
+    /// Replace the rule's key with its bucket if [`Self::bucket_count`] is set.
+    ///
+    /// This goes before [`Self::adjusted`], so that the bucket of a policy
+    /// without a name does not change with the load.
+    pub(crate) fn bucketed<'a>(&self, mut rule: Rule<'a>) -> Rule<'a> {
+        if let Some(count) = self.bucket_count {
+            let bucket = fnv1a(rule.key.to_string().as_bytes()) % count;
+            let policy = &rule.policy;
+            let policy = match policy.name {
+                Some(name) => name.to_string(),
+                None => format!(
+                    "{}-{}ms-{}",
+                    policy.tokens,
+                    policy.period.as_millis(),
+                    policy.burst
+                ),
+            };
+            let scope = match rule.resource {
+                Some(resource) => format!("{}:{}", resource, policy),
+                None => policy,
+            };
+            rule.key = Key::String(format!("{}:key_bucket_{}", scope, bucket));
+        }
+        rule
+    }
+
+    /// Transform the rule's key as per [`Self::key_transform`] and prepend
+    /// [`Self::key_prefix`] to it, if set.
+    pub(crate) fn prefixed<'a>(&self, mut rule: Rule<'a>) -> Rule<'a> {
+        if let Some(key) = self.key_transform.apply(&rule.key.to_string()) {
+            rule.key = Key::String(key);
+        }
+        if let Some(ref prefix) = self.key_prefix {
+            rule.key = Key::String(format!("{}{}", prefix, rule.key));
+        }
+        rule
+    }
+
+    /// Fill in the retry-after bounds the rule does not set itself.
+    pub(crate) fn retry_after_bounds<'a>(&self, mut rule: Rule<'a>) -> Rule<'a> {
+        rule.min_retry_after = rule.min_retry_after.or(self.min_retry_after);
+        rule.max_retry_after = rule.max_retry_after.or(self.max_retry_after);
+        rule
+    }
+
+    pub(crate) fn handle_error(&self, err: Error, req: &ReqTy) -> Result<RespTy, E>
+    where
+        IntoRespTy: Into<RespTy>,
+    {
+        let handle = || match self.on_error {
+            OnError::Sync(ref h) => Ok(h(err, req).into()),
+            OnError::Fallible(ref h) => h(err, req).map(Into::into),
+        };
+        let outcome = match self.error_fallback {
+            Some(ref fallback) => {
+                panic::catch_unwind(AssertUnwindSafe(handle)).unwrap_or_else(|_| Ok(fallback()))
+            }
+            None => handle(),
+        };
+        match outcome {
+            Ok(mut resp) => {
+                self.handle_response(&mut resp);
+                Ok(resp)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub(crate) fn handle_success(&self, details: RequestAllowedDetails, resp: &mut RespTy) {
+        if let OnSuccess::Sync(ref h) = self.on_success {
+            h(details, resp);
+        }
+        self.handle_response(resp);
+    }
+
+    pub(crate) fn handle_unruled(&self, resp: &mut RespTy) {
+        if let OnUnruled::Sync(ref h) = self.on_unruled {
+            h(resp);
+        }
+        self.handle_response(resp);
+    }
+
+    /// Choose how keys appear in diagnostics, e.g. in the command context of
+    /// [`Error::Redis`]. Defaults to [`KeyLogPolicy::Hashed`].
+    ///
+    /// Only the logging path is affected, the key stored in Redis is not.
+    pub fn key_log_policy(mut self, policy: KeyLogPolicy) -> Self {
+        self.key_log_policy = policy;
+        self
+    }
+
+    /// Render the key for diagnostics, `None` meaning it is to be left out.
+    pub(crate) fn render_key(&self, key: &str) -> Option<String> {
+        match self.redact_key {
+            Some(ref redact) => Some(redact(key)),
+            None => self.key_log_policy.render(key),
+        }
+    }
+
+    pub(crate) fn handle_response(&self, resp: &mut RespTy) {
+        if let Some(ref h) = self.on_response {
+            h(resp);
+        }
+    }
+
+    pub(crate) fn command_context(&self, cmd: &redis::Cmd) -> String {
+        command_context(cmd, |key| self.render_key(key))
+    }
+}
+
+impl RateLimitConfig<(), (), (), ()> {
+    /// Start building a config with the request and response types pinned up
+    /// front, so that none of the handlers need type annotations.
+    ///
+    ///```
+    /// use axum::http::{Request, Response, StatusCode};
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// let config = RateLimitConfig::builder::<Request<()>, Response<()>>()
+    ///     .rule_provider(Global)
+    ///     .error_handler(|err, _req| {
+    ///         let mut resp = Response::new(());
+    ///         *resp.status_mut() = match err {
+    ///             Error::RateLimit(_) => StatusCode::TOO_MANY_REQUESTS,
+    ///             _ => StatusCode::INTERNAL_SERVER_ERROR,
+    ///         };
+    ///         resp
+    ///     })
+    ///     .on_success(|details, resp| {
+    ///         resp.headers_mut().insert("x-ratelimit-remaining", details.remaining().into());
+    ///     });
+    /// # let _: RateLimitConfig<_, _, _, _> = config;
+    ///```
+    pub fn builder<ReqTy, RespTy>() -> RateLimitConfigBuilder<(), ReqTy, RespTy> {
+        RateLimitConfigBuilder {
+            rule_provider: (),
+            types: PhantomData,
+        }
+    }
+}
+
+/// Builder of a [`RateLimitConfig`], see [`RateLimitConfig::builder`].
+pub struct RateLimitConfigBuilder<PR, ReqTy, RespTy> {
+    rule_provider: PR,
+    types: PhantomData<fn(ReqTy) -> RespTy>,
+}
+
+impl<ReqTy, RespTy> RateLimitConfigBuilder<(), ReqTy, RespTy> {
+    pub fn rule_provider<PR>(self, rule_provider: PR) -> RateLimitConfigBuilder<PR, ReqTy, RespTy> {
+        RateLimitConfigBuilder {
+            rule_provider,
+            types: PhantomData,
+        }
+    }
+}
+
+impl<PR, ReqTy, RespTy> RateLimitConfigBuilder<PR, ReqTy, RespTy> {
+    /// Finish with the error handler, see [`RateLimitConfig::new`].
+    pub fn error_handler<IntoRespTy, E, EH>(
+        self,
+        error_handler: EH,
+    ) -> RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>
+    where
+        EH: Fn(Error, &ReqTy) -> IntoRespTy + Send + Sync + 'static,
+    {
+        RateLimitConfig::new(self.rule_provider, error_handler)
+    }
+
+    /// Finish with a fallible error handler, see [`RateLimitConfig::new_fallible`].
+    pub fn fallible_error_handler<IntoRespTy, E, EH>(
+        self,
+        error_handler: EH,
+    ) -> RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>
+    where
+        EH: Fn(Error, &ReqTy) -> Result<IntoRespTy, E> + Send + Sync + 'static,
+    {
+        RateLimitConfig::new_fallible(self.rule_provider, error_handler)
+    }
+}
+
+#[cfg(feature = "http")]
+#[cfg_attr(docsrs, doc(cfg(feature = "http")))]
+impl<RP, ReqTy, B, E> RateLimitConfig<RP, ReqTy, ::http::Response<B>, ::http::Response<B>, E>
+where
+    ReqTy: 'static,
+    B: From<String> + Default + 'static,
+{
+    /// Create a config which uses [`default_error_response`](crate::http::default_error_response)
+    /// as the error handler.
+    pub fn with_default_http_handler(rule_provider: RP) -> Self {
+        RateLimitConfig::new(rule_provider, |err, _req: &ReqTy| {
+            crate::http::default_error_response(err)
+        })
+    }
+}
+
+/// Render the command for diagnostics, with its key (the first argument after
+/// the command's name) rendered by `render_key`.
+pub(crate) fn command_context<F>(cmd: &redis::Cmd, render_key: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut context = String::new();
+    for (i, arg) in cmd.args_iter().enumerate() {
+        let redis::Arg::Simple(arg) = arg else {
+            continue;
+        };
+        if !context.is_empty() {
+            context.push(' ');
+        }
+        let arg = String::from_utf8_lossy(arg);
+        if i != 1 {
+            context.push_str(&arg);
+            continue;
+        }
+        match render_key(&arg) {
+            Some(key) => context.push_str(&key),
+            None => context.push_str("<omitted>"),
+        }
+    }
+    context
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    })
+}
+
+#[cfg(feature = "http")]
+#[cfg_attr(docsrs, doc(cfg(feature = "http")))]
+impl<RP, B, RespTy, IntoRespTy, E> RateLimitConfig<RP, ::http::Request<B>, RespTy, IntoRespTy, E> {
+    /// Read the number of tokens to apply from the request extension of type
+    /// `T`, falling back to the rule's `policy.apply` if there is none.
+    ///
+    /// This is handy when the cost is computed upstream, e.g. by an API
+    /// gateway or a preceding middleware. Any type convertible into `usize`
+    /// will do, [`Cost`](crate::http::Cost) is provided for convenience.
+    ///
+    ///```
+    /// use http::{Request, Response};
+    /// use tower_redis_cell::http::Cost;
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct Global;
+    ///
+    /// // e.g. `Cost(10)` inserted by a preceding middleware, a single token otherwise
+    /// let config: RateLimitConfig<_, _, Response<()>, _> =
+    ///     RateLimitConfig::new(Global, |_err: Error, _req: &Request<()>| Response::new(()))
+    ///         .cost_from_extension::<Cost>();
+    ///```
+    pub fn cost_from_extension<T>(self) -> Self
+    where
+        T: Clone + Into<usize> + Send + Sync + 'static,
+    {
+        self.cost(|req: &::http::Request<B>| req.extensions().get::<T>().cloned().map(Into::into))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fixed, LocalGcra, MockConnection};
+    use crate::{RateLimitLayer, rate_limit_check};
+    use std::sync::Mutex;
+    use tower::{Layer as _, Service as _, ServiceExt as _, service_fn};
+
+    #[test]
+    fn command_context_redacts_only_the_key() {
+        // a key which reads like one of the other arguments
+        let cmd = redis::cmd("CL.THROTTLE")
+            .arg("1")
+            .arg(0)
+            .arg(1)
+            .arg(60)
+            .arg(1)
+            .clone();
+        let context = command_context(&cmd, |key| Some(format!("<{}>", key)));
+        assert_eq!(context, "CL.THROTTLE <1> 0 1 60 1");
+        assert_eq!(
+            command_context(&cmd, |_| None),
+            "CL.THROTTLE <omitted> 0 1 60 1"
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn warns_about_misconfigured_policies() {
+        use std::sync::Arc;
+        use tracing_subscriber::layer::{Context, Layer, SubscriberExt as _};
+
+        /// Collects the events' fields.
+        #[derive(Clone, Default)]
+        struct Events(Arc<Mutex<Vec<String>>>);
+
+        impl<S: tracing::Subscriber> Layer<S> for Events {
+            fn on_event(&self, event: &tracing::Event<'_>, _: Context<'_, S>) {
+                let mut fields = String::new();
+                event.record(
+                    &mut |field: &tracing::field::Field, value: &dyn std::fmt::Debug| {
+                        fields.push_str(&format!("{}={:?} ", field.name(), value));
+                    },
+                );
+                self.0.lock().unwrap().push(fields);
+            }
+        }
+
+        let events = Events::default();
+        let subscriber = tracing_subscriber::registry().with(events.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let policies = HashMap::from([
+            ("search", Policy::from_tokens_per_minute(10)),
+            ("upload", Policy::from_tokens_per_minute(10).apply_tokens(5)),
+        ]);
+        let config = RateLimitConfig::new((), |_: Error, _: &()| ()).policies(policies);
+        let _layer: crate::RateLimitLayer<_, _, (), _, _> =
+            crate::RateLimitLayer::new(config, MockConnection::allowed(10, 9));
+
+        let events = events.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].contains("resource=\"upload\""));
+        assert!(events[0].contains("always blocks"));
+    }
+
+    fn global(policy: Policy) -> Fixed {
+        Fixed(Rule::new("global", policy))
+    }
+
+    fn status(err: Error, _: &String) -> String {
+        match err {
+            Error::RateLimit(_) => "blocked".to_string(),
+            err => err.to_string(),
+        }
+    }
+
+    macro_rules! pong {
+        () => {
+            service_fn(|_req: String| async { Ok::<_, Infallible>("pong".to_string()) })
+        };
+    }
+
+    #[tokio::test]
+    async fn hashes_the_key_in_the_command_context_by_default() {
+        let rule = Rule::new("jane@example.com", Policy::from_tokens_per_minute(10));
+        let err = rate_limit_check(&mut MockConnection::failing(), &rule).await;
+        let Err(Error::Redis { command, .. }) = err else {
+            panic!("should be a Redis error");
+        };
+        let command = command.unwrap();
+        assert!(command.starts_with("CL.THROTTLE hashed:"));
+        assert!(!command.contains("jane@example.com"));
+    }
+
+    #[tokio::test]
+    async fn fails_the_request_with_the_error_handler_error() {
+        let config = RateLimitConfig::new_fallible(
+            global(Policy::from_tokens_per_second(10)),
+            |err: Error, _: &String| match err {
+                Error::RateLimit(_) => Ok("blocked".to_string()),
+                _ => Err("unavailable"),
+            },
+        );
+        let svc = RateLimitLayer::new(config, MockConnection::failing())
+            .layer(service_fn(|_req: String| async { Ok("pong".to_string()) }));
+        assert_eq!(svc.oneshot("user123".into()).await, Err("unavailable"));
+    }
+
+    #[tokio::test]
+    async fn mutates_the_responses_of_the_error_handler_too() {
+        let config = RateLimitConfig::new(global(Policy::from_tokens_per_minute(1)), status)
+            .on_response(|resp: &mut String| resp.push('!'));
+        let svc = RateLimitLayer::new(config, MockConnection::blocked(1, 60)).layer(pong!());
+        assert_eq!(svc.oneshot("user123".into()).await.unwrap(), "blocked!");
+    }
+
+    #[tokio::test]
+    async fn reports_every_verdict() {
+        let audit = Arc::new(Mutex::new(Vec::new()));
+        let config = RateLimitConfig::new(global(Policy::from_tokens_per_hour(1)), status)
+            .on_verdict({
+                let audit = Arc::clone(&audit);
+                move |verdict: &Verdict, caller: &String| {
+                    let blocked = matches!(verdict, Verdict::Blocked(_));
+                    audit.lock().unwrap().push((caller.clone(), blocked));
+                }
+            });
+        let svc = RateLimitLayer::new(config, LocalGcra::new()).layer(pong!());
+
+        assert_eq!(svc.clone().oneshot("alice".into()).await.unwrap(), "pong");
+        assert_eq!(svc.oneshot("bob".into()).await.unwrap(), "blocked");
+        let audit = audit.lock().unwrap();
+        assert_eq!(
+            *audit,
+            [("alice".to_string(), false), ("bob".to_string(), true)]
+        );
+    }
+
+    #[tokio::test]
+    async fn falls_back_should_the_error_handler_panic() {
+        let config = RateLimitConfig::new(
+            global(Policy::from_tokens_per_minute(1)),
+            |_: Error, _: &String| -> String { unimplemented!("forgot to handle this one") },
+        )
+        .error_fallback(|| "internal server error".to_string());
+        let svc = RateLimitLayer::new(config, MockConnection::blocked(1, 60)).layer(pong!());
+        let resp = svc.oneshot("user123".into()).await.unwrap();
+        assert_eq!(resp, "internal server error");
+    }
+
+    #[test]
+    fn resolves_the_policy_registered_for_the_resource() {
+        let policies = HashMap::from([("search", Policy::from_tokens_per_minute(10))]);
+        let config: RateLimitConfig<(), (), (), ()> =
+            RateLimitConfig::new((), |_: Error, _: &()| ()).policies(policies);
+
+        let rule = config
+            .resolved(Rule::for_resource("user123", "search"))
+            .unwrap();
+        assert_eq!(rule.policy.tokens, 10);
+        assert!(rule.unresolved.is_none());
+
+        let err = config
+            .resolved(Rule::for_resource("user123", "upload"))
+            .unwrap_err();
+        assert!(err.detail.unwrap().contains("\"upload\""));
+    }
+
+    #[tokio::test]
+    async fn fails_the_requests_for_unregistered_resources() {
+        let policies = HashMap::from([("search", Policy::from_tokens_per_minute(1))]);
+        let resource = |resource| Fixed(Rule::for_resource("user123", resource));
+        let connection = MockConnection::allowed(1, 0);
+
+        let config = RateLimitConfig::new(resource("search"), status).policies(policies.clone());
+        let svc = RateLimitLayer::new(config, connection.clone()).layer(pong!());
+        assert_eq!(svc.oneshot("user123".into()).await.unwrap(), "pong");
+
+        let config = RateLimitConfig::new(resource("upload"), status).policies(policies);
+        let svc = RateLimitLayer::new(config, connection.clone()).layer(pong!());
+        let resp = svc.oneshot("user123".into()).await.unwrap();
+        assert!(resp.contains("no policy registered for resource \"upload\""));
+        assert_eq!(connection.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn charges_the_override_against_the_registered_policy() {
+        let policy = Policy::from_tokens_per_minute(100).max_burst(99);
+        let policies = HashMap::from([("batch", policy)]);
+        let rule = Rule::for_resource("user123", "batch").apply(7);
+        let redis = LocalGcra::new();
+        let config = RateLimitConfig::new(Fixed(rule), status).policies(policies);
+        let svc = RateLimitLayer::new(config, redis.clone()).layer(pong!());
+
+        assert_eq!(svc.oneshot("user123".into()).await.unwrap(), "pong");
+        assert_eq!(
+            redis.last_command(),
+            ["CL.THROTTLE", "user123", "99", "100", "60", "7"]
+        );
+    }
+
+    #[derive(Clone)]
+    struct ByCaller;
+
+    impl crate::ProvideRule<String> for ByCaller {
+        fn provide<'a>(&self, caller: &'a String) -> crate::ProvideRuleResult<'a> {
+            let policy = Policy::from_tokens_per_minute(1);
+            Ok(Some(Rule::new(caller.as_str(), policy)))
+        }
+    }
+
+    #[tokio::test]
+    async fn hashes_the_keys_into_buckets() {
+        let redis = LocalGcra::new();
+        let config = RateLimitConfig::new(ByCaller, status).bucket_count(4);
+        let svc = RateLimitLayer::new(config, redis.clone()).layer(pong!());
+
+        for user in (0..100).chain(0..100) {
+            svc.clone().oneshot(user.to_string()).await.unwrap();
+        }
+        let keys = redis.keys();
+        assert_eq!(keys.len(), 4);
+        assert!(
+            keys.iter()
+                .all(|key| key.starts_with("1-60000ms-0:key_bucket_"))
+        );
+    }
+
+    #[derive(Clone)]
+    struct ByTier;
+
+    impl crate::ProvideRule<String> for ByTier {
+        fn provide<'a>(&self, req: &'a String) -> crate::ProvideRuleResult<'a> {
+            let (tier, user) = req.split_once(':').unwrap();
+            let rule = match tier {
+                "basic" => Rule::new(user, Policy::from_tokens_per_minute(10).name("basic")),
+                "premium" => Rule::new(user, Policy::from_tokens_per_minute(100).name("premium")),
+                "search" => Rule::new(user, Policy::from_tokens_per_minute(10).name("basic"))
+                    .resource("search"),
+                "other" => Rule::new(user, Policy::from_tokens_per_minute(1)),
+                _ => Rule::new(user, Policy::from_tokens_per_minute(2)),
+            };
+            Ok(Some(rule))
+        }
+    }
+
+    #[tokio::test]
+    async fn never_shares_a_bucket_between_policies() {
+        let redis = LocalGcra::new();
+        let config = RateLimitConfig::new(ByTier, status).bucket_count(1);
+        let svc = RateLimitLayer::new(config, redis.clone()).layer(pong!());
+
+        for tier in ["basic", "premium", "search", "other", "unnamed"] {
+            for user in 0..10 {
+                svc.clone()
+                    .oneshot(format!("{}:{}", tier, user))
+                    .await
+                    .unwrap();
+            }
+        }
+        assert_eq!(
+            redis.keys(),
+            [
+                "1-60000ms-0:key_bucket_0",
+                "2-60000ms-0:key_bucket_0",
+                "basic:key_bucket_0",
+                "premium:key_bucket_0",
+                "search:basic:key_bucket_0",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn prefixes_the_keys_sent_and_reported() {
+        let redis = LocalGcra::new();
+        let config = RateLimitConfig::new(ByCaller, |err: Error, _: &String| match err {
+            Error::RateLimit(details) => details.rule.key.to_string(),
+            err => err.to_string(),
+        })
+        .key_prefix("svc_a:rl:");
+        let svc = RateLimitLayer::new(config, redis.clone()).layer(pong!());
+
+        assert_eq!(svc.clone().oneshot("user123".into()).await.unwrap(), "pong");
+        let resp = svc.oneshot("user123".into()).await.unwrap();
+        assert_eq!(resp, "svc_a:rl:user123");
+        assert_eq!(redis.keys(), ["svc_a:rl:user123"]);
+    }
+
+    #[tokio::test]
+    async fn hashes_the_keys_under_the_prefix() {
+        let redis = LocalGcra::new();
+        let config = RateLimitConfig::new(ByCaller, status)
+            .key_prefix("rl:")
+            .key_transform(KeyTransform::Fnv1aHex);
+        let svc = RateLimitLayer::new(config, redis.clone()).layer(pong!());
+
+        let url = "https://example.com/search?q=a-very-long-query-string-indeed";
+        for _ in 0..2 {
+            svc.clone().oneshot(url.into()).await.unwrap();
+        }
+        let keys = redis.keys();
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].starts_with("rl:"));
+        assert_eq!(keys[0].len(), "rl:".len() + 16);
+    }
+
+    #[tokio::test]
+    async fn counts_locally_without_over_allowing() {
+        let redis = LocalGcra::new();
+        // a bucket of 20 tokens which is not replenished in the meantime
+        let policy = Policy::from_tokens_per_hour(1).max_burst(19);
+        let config = RateLimitConfig::new(global(policy), status).local_counter(5);
+        let svc = RateLimitLayer::new(config, redis.clone()).layer(pong!());
+
+        let mut resps = Vec::new();
+        for _ in 0..25 {
+            resps.push(svc.clone().oneshot("user123".into()).await.unwrap());
+        }
+        assert!(resps[..20].iter().all(|resp| resp == "pong"));
+        assert!(resps[20..].iter().all(|resp| resp == "blocked"));
+        assert!(redis.calls() < 15);
+    }
+
+    #[cfg(feature = "local-cache")]
+    #[tokio::test]
+    async fn blocks_from_the_cache_until_the_retry_after() {
+        let connection = MockConnection::blocked(1, 60);
+        let config = RateLimitConfig::new(
+            global(Policy::from_tokens_per_minute(1)),
+            |err: Error, _: &String| match err {
+                Error::RateLimit(details) => details.retry_after().to_string(),
+                err => err.to_string(),
+            },
+        )
+        .blocked_cache(10_000);
+        let svc = RateLimitLayer::new(config, connection.clone()).layer(pong!());
+
+        for _ in 0..3 {
+            assert_eq!(svc.clone().oneshot("user123".into()).await.unwrap(), "60");
+        }
+        assert_eq!(connection.calls(), 1);
+    }
+
+    /// Tracks the commands in flight, which take a few polls to complete.
+    #[derive(Clone, Default)]
+    struct Tracking {
+        in_flight: Arc<std::sync::atomic::AtomicUsize>,
+        peak: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl redis::aio::ConnectionLike for Tracking {
+        fn req_packed_command<'a>(
+            &'a mut self,
+            _: &'a redis::Cmd,
+        ) -> redis::RedisFuture<'a, redis::Value> {
+            Box::pin(async move {
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.peak.fetch_max(in_flight, Ordering::SeqCst);
+                for _ in 0..10 {
+                    tokio::task::yield_now().await;
+                }
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                MockConnection::allowed(10, 9)
+                    .req_packed_command(&redis::Cmd::new())
+                    .await
+            })
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            _: &'a redis::Pipeline,
+            _: usize,
+            _: usize,
+        ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+            unimplemented!()
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    #[tokio::test]
+    async fn bounds_the_commands_in_flight() {
+        let connection = Tracking::default();
+        let config = RateLimitConfig::new(global(Policy::from_tokens_per_second(1_000)), status)
+            .max_concurrent_redis(3);
+        let svc = RateLimitLayer::new(config, connection.clone()).layer(pong!());
+
+        let requests = (0..20).map(|_| svc.clone().oneshot("user123".into()));
+        for resp in futures_util::future::join_all(requests).await {
+            assert_eq!(resp.unwrap(), "pong");
+        }
+        assert_eq!(connection.peak.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn holds_a_slot_until_the_call_completes() {
+        let config = RateLimitConfig::new(global(Policy::from_tokens_per_second(10)), status)
+            .max_in_flight(1);
+        let svc = RateLimitLayer::new(config, MockConnection::allowed(10, 9)).layer(pong!());
+
+        let (mut first, mut second) = (svc.clone(), svc);
+        let call = first.ready().await.unwrap().call("user123".into());
+        let ready = std::future::poll_fn(|cx| std::task::Poll::Ready(second.poll_ready(cx))).await;
+        assert!(ready.is_pending());
+
+        call.await.unwrap();
+        second
+            .ready()
+            .await
+            .unwrap()
+            .call("user123".into())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn publishes_the_decisions() {
+        let rule = Rule::new("global", Policy::from_tokens_per_hour(1).name("hourly"));
+        let rule = rule.resource("api").labels(&[("plan", "free")]);
+        let (config, mut decisions) =
+            RateLimitConfig::new(Fixed(rule), status).decision_channel(16);
+        let svc = RateLimitLayer::new(config, LocalGcra::new()).layer(pong!());
+
+        svc.clone().oneshot("user123".into()).await.unwrap();
+        svc.oneshot("user123".into()).await.unwrap();
+
+        let event = decisions.recv().await.unwrap();
+        assert_eq!(event.key.to_string(), "global");
+        assert!(event.redacted_key.unwrap().starts_with("hashed:"));
+        assert_eq!(event.policy.name, Some("hourly"));
+        assert_eq!(event.resource, Some("api"));
+        assert_eq!(event.labels, [("plan", "free")]);
+        assert!(matches!(event.verdict, Verdict::Allowed(_)));
+        let event = decisions.recv().await.unwrap();
+        assert!(matches!(event.verdict, Verdict::Blocked(_)));
+        assert_eq!(decisions.dropped(), 0);
+    }
+
+    #[tokio::test]
+    async fn adjusts_the_policy_to_the_load() {
+        let cpu = LoadSignal::new();
+        let (config, mut decisions) =
+            RateLimitConfig::new(global(Policy::from_tokens_per_second(100)), status)
+                .policy_adjuster(cpu.clone(), |mut policy, cpu| {
+                    if cpu.get() >= 90 {
+                        policy.tokens /= 4;
+                    }
+                    policy
+                })
+                .decision_channel(16);
+        let svc = RateLimitLayer::new(config, MockConnection::allowed(100, 99)).layer(pong!());
+
+        cpu.set(30);
+        svc.clone().oneshot("user123".into()).await.unwrap();
+        assert_eq!(decisions.recv().await.unwrap().policy.tokens, 100);
+        cpu.set(95);
+        svc.oneshot("user123".into()).await.unwrap();
+        assert_eq!(decisions.recv().await.unwrap().policy.tokens, 25);
+    }
+
+    #[tokio::test]
+    async fn escalates_the_policy_of_keys_being_blocked() {
+        let (config, mut decisions) =
+            RateLimitConfig::new(global(Policy::from_tokens_per_minute(100)), status)
+                .escalation(3, Duration::from_secs(600), |mut policy, verdicts| {
+                    let blocked = verdicts
+                        .iter()
+                        .filter(|verdict| matches!(verdict, Verdict::Blocked(_)))
+                        .count();
+                    policy.tokens >>= blocked;
+                    policy
+                })
+                .decision_channel(16);
+        let svc = RateLimitLayer::new(config, MockConnection::blocked(10, 5)).layer(pong!());
+
+        for tokens in [100, 50, 25, 12, 12] {
+            svc.clone().oneshot("user123".into()).await.unwrap();
+            assert_eq!(decisions.recv().await.unwrap().policy.tokens, tokens);
+        }
+    }
+
+    #[tokio::test]
+    async fn lets_blocked_requests_through_in_shadow_mode() {
+        let config = RateLimitConfig::new(global(Policy::from_tokens_per_minute(1)), status)
+            .mode(Mode::Shadow);
+        let config = Arc::new(config);
+        let svc =
+            RateLimitLayer::new(Arc::clone(&config), MockConnection::blocked(1, 60)).layer(pong!());
+
+        assert_eq!(svc.oneshot("user123".into()).await.unwrap(), "pong");
+        assert_eq!(config.would_block_total(), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_open_without_running_the_success_handler() {
+        let config = RateLimitConfig::new(global(Policy::from_tokens_per_second(10)), status)
+            .on_success(|_details, resp: &mut String| resp.push_str(" (allowed)"))
+            .on_redis_error(FailMode::Allow);
+        let svc = RateLimitLayer::new(config, MockConnection::failing()).layer(pong!());
+        assert_eq!(svc.oneshot("user123".into()).await.unwrap(), "pong");
+    }
+
+    /// Drops the connection on the first attempt.
+    #[derive(Clone, Default)]
+    struct Flaky(Arc<std::sync::atomic::AtomicUsize>);
+
+    impl redis::aio::ConnectionLike for Flaky {
+        fn req_packed_command<'a>(
+            &'a mut self,
+            cmd: &'a redis::Cmd,
+        ) -> redis::RedisFuture<'a, redis::Value> {
+            let mut connection = match self.0.fetch_add(1, Ordering::Relaxed) {
+                0 => MockConnection::failing(),
+                _ => MockConnection::allowed(10, 9),
+            };
+            Box::pin(async move { connection.req_packed_command(cmd).await })
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            _: &'a redis::Pipeline,
+            _: usize,
+            _: usize,
+        ) -> redis::RedisFuture<'a, Vec<redis::Value>> {
+            unimplemented!()
+        }
+
+        fn get_db(&self) -> i64 {
+            0
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_a_dropped_connection() {
+        let connection = Flaky::default();
+        let config =
+            RateLimitConfig::new(global(Policy::from_tokens_per_second(10)), status).retries(2);
+        let svc = RateLimitLayer::new(config, connection.clone()).layer(pong!());
+        assert_eq!(svc.oneshot("user123".into()).await.unwrap(), "pong");
+        assert_eq!(connection.0.load(Ordering::Relaxed), 2);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn records_the_verdict_on_the_current_span() {
+        use tracing::Instrument as _;
+        use tracing::field::{Empty, Field, Visit};
+        use tracing_subscriber::layer::{Context, Layer, SubscriberExt as _};
+        use tracing_subscriber::registry::LookupSpan;
+
+        /// Collects the fields recorded on the spans.
+        #[derive(Clone, Default)]
+        struct Recorded(Arc<Mutex<Vec<(String, String)>>>);
+
+        impl Visit for Recorded {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                let value = format!("{:?}", value).trim_matches('"').to_string();
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push((field.name().to_string(), value));
+            }
+        }
+
+        impl<S: tracing::Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Recorded {
+            fn on_record(
+                &self,
+                id: &tracing::span::Id,
+                values: &tracing::span::Record<'_>,
+                ctx: Context<'_, S>,
+            ) {
+                assert_eq!(ctx.span(id).unwrap().name(), "request");
+                values.record(&mut self.clone());
+            }
+        }
+
+        let recorded = Recorded::default();
+        let subscriber = tracing_subscriber::registry().with(recorded.clone());
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let rule = Rule::new("global", Policy::from_tokens_per_second(10).name("api"));
+        let rule = rule
+            .resource("search")
+            .labels(&[("region", "eu"), ("plan", "pro")]);
+        let config = RateLimitConfig::new(Fixed(rule), status)
+            .key_log_policy(KeyLogPolicy::Full)
+            .tracing_mode(TracingMode::CurrentSpan);
+        let svc = RateLimitLayer::new(config, MockConnection::allowed(10, 9)).layer(pong!());
+
+        let request_span = tracing::info_span!(
+            "request",
+            rate_limit.key = Empty,
+            rate_limit.policy = Empty,
+            rate_limit.resource = Empty,
+            rate_limit.verdict = Empty,
+            rate_limit.remaining = Empty,
+            rate_limit.labels = Empty,
+        );
+        let req = svc.oneshot("user123".into()).instrument(request_span);
+        req.await.unwrap();
+
+        let recorded = recorded.0.lock().unwrap();
+        let field = |name: &str| {
+            let (_, value) = recorded.iter().find(|(field, _)| field == name)?;
+            Some(value.as_str())
+        };
+        assert_eq!(field("rate_limit.key"), Some("global"));
+        assert_eq!(field("rate_limit.policy"), Some("api"));
+        assert_eq!(field("rate_limit.resource"), Some("search"));
+        assert_eq!(field("rate_limit.verdict"), Some("allowed"));
+        assert_eq!(field("rate_limit.remaining"), Some("9"));
+        assert_eq!(field("rate_limit.labels"), Some("region=eu,plan=pro"));
+    }
+
+    #[tokio::test]
+    async fn charges_the_cost_of_the_response() {
+        let redis = LocalGcra::new();
+        let policy = Policy::from_tokens_per_minute(100).max_burst(99);
+        let config = RateLimitConfig::new(global(policy), |_: Error, _: &String| Vec::new())
+            .post_charge(|rows: &Vec<u32>| rows.len());
+        let svc =
+            RateLimitLayer::new(config, redis.clone()).layer(service_fn(|_req: String| async {
+                Ok::<_, Infallible>(vec![1, 2, 3, 4, 5])
+            }));
+        svc.oneshot("user123".into()).await.unwrap();
+
+        // a token for the request, and then one per row
+        let rule = Rule::new("global", policy);
+        let verdict = rate_limit_check(&mut redis.clone(), &rule).await.unwrap();
+        assert!(matches!(verdict, Verdict::Allowed(details) if details.remaining == 93));
+    }
+
+    #[tokio::test]
+    async fn pins_the_request_and_response_types() {
+        let config = RateLimitConfig::builder::<String, String>()
+            .rule_provider(global(Policy::from_tokens_per_second(10)))
+            .error_handler(status)
+            .on_success(|details, resp| resp.push_str(&format!(" ({})", details.remaining())));
+        let svc = RateLimitLayer::new(config, MockConnection::allowed(10, 9)).layer(pong!());
+        assert_eq!(svc.oneshot("user123".into()).await.unwrap(), "pong (9)");
+    }
+
+    #[cfg(feature = "http")]
+    #[tokio::test]
+    async fn reads_the_cost_from_the_request_extension() {
+        use crate::http::Cost;
+        use ::http::{Request, Response};
+
+        let (config, mut decisions) = RateLimitConfig::new(
+            global(Policy::from_tokens_per_second(100)),
+            |_: Error, _: &Request<()>| Response::new(()),
+        )
+        .cost_from_extension::<Cost>()
+        .decision_channel(16);
+        let svc = RateLimitLayer::new(config, MockConnection::allowed(100, 90)).layer(service_fn(
+            |_req| async { Ok::<_, Infallible>(Response::new(())) },
+        ));
+
+        let mut req = Request::new(());
+        req.extensions_mut().insert(Cost(10));
+        svc.clone().oneshot(req).await.unwrap();
+        assert_eq!(decisions.recv().await.unwrap().policy.apply, 10);
+        svc.oneshot(Request::new(())).await.unwrap();
+        assert_eq!(decisions.recv().await.unwrap().policy.apply, 1);
+    }
 }
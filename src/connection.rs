@@ -0,0 +1,295 @@
+use futures_util::future::{BoxFuture, FutureExt as _, Shared};
+use futures_util::lock::Mutex;
+use redis::aio::ConnectionLike;
+use redis::{Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, Value};
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::Arc;
+
+type PendingConnection<C> = Shared<BoxFuture<'static, Result<C, Arc<str>>>>;
+
+/// Connection which is established on first use.
+///
+/// The underlying future is only polled once no matter how many clones of
+/// this connection are around, and the resulting connection is then shared
+/// between all of them. Note that a failure to establish the connection is
+/// shared just the same, i.e. every subsequent command will fail with
+/// a [`RedisError`] describing the original error, so the future should
+/// normally resolve to something that reconnects on its own (like
+/// [`ConnectionManager`](redis::aio::ConnectionManager)).
+///
+/// See [`RateLimitLayer::from_future`](crate::RateLimitLayer::from_future).
+pub struct LazyConnection<C> {
+    pending: PendingConnection<C>,
+    connection: Option<C>,
+}
+
+impl<C> Clone for LazyConnection<C>
+where
+    C: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            pending: self.pending.clone(),
+            connection: self.connection.clone(),
+        }
+    }
+}
+
+impl<C> LazyConnection<C>
+where
+    C: Clone + Send + Sync + 'static,
+{
+    pub fn new<F, E>(connection: F) -> Self
+    where
+        F: Future<Output = Result<C, E>> + Send + 'static,
+        E: Display,
+    {
+        let pending = async move { connection.await.map_err(|e| Arc::from(e.to_string())) };
+        LazyConnection {
+            pending: pending.boxed().shared(),
+            connection: None,
+        }
+    }
+
+    async fn connection(&mut self) -> Result<&mut C, RedisError> {
+        if self.connection.is_none() {
+            let connection = self.pending.clone().await.map_err(|detail| {
+                RedisError::from((
+                    ErrorKind::ClientError,
+                    "failed to establish connection",
+                    detail.to_string(),
+                ))
+            })?;
+            self.connection = Some(connection);
+        }
+        Ok(self.connection.as_mut().expect("just initialized"))
+    }
+}
+
+impl<C> ConnectionLike for LazyConnection<C>
+where
+    C: ConnectionLike + Clone + Send + Sync + 'static,
+{
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        async move { self.connection().await?.req_packed_command(cmd).await }.boxed()
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        async move {
+            self.connection()
+                .await?
+                .req_packed_commands(cmd, offset, count)
+                .await
+        }
+        .boxed()
+    }
+
+    fn get_db(&self) -> i64 {
+        match self.connection {
+            Some(ref connection) => connection.get_db(),
+            None => match self.pending.peek() {
+                Some(Ok(connection)) => connection.get_db(),
+                _ => 0,
+            },
+        }
+    }
+}
+
+/// Connection shared behind a mutex.
+///
+/// This makes it possible to use connections which are not [`Clone`] with
+/// the service: the lock is held for the duration of every command, so
+/// concurrent requests wait for each other. Prefer a connection which is
+/// cheap to clone and multiplexes commands, like
+/// [`ConnectionManager`](redis::aio::ConnectionManager), where available.
+///
+///```
+/// use redis::aio::ConnectionLike;
+/// use tower_redis_cell::MutexConnection;
+///
+/// // e.g. for a connection which is not `Clone`
+/// fn shared<C: ConnectionLike>(connection: C) -> MutexConnection<C> {
+///     MutexConnection::new(connection)
+/// }
+///```
+pub struct MutexConnection<C> {
+    connection: Arc<Mutex<C>>,
+    db: i64,
+}
+
+impl<C> Clone for MutexConnection<C> {
+    fn clone(&self) -> Self {
+        Self {
+            connection: Arc::clone(&self.connection),
+            db: self.db,
+        }
+    }
+}
+
+impl<C> MutexConnection<C>
+where
+    C: ConnectionLike,
+{
+    pub fn new(connection: C) -> Self {
+        MutexConnection {
+            db: connection.get_db(),
+            connection: Arc::new(Mutex::new(connection)),
+        }
+    }
+}
+
+impl<C> ConnectionLike for MutexConnection<C>
+where
+    C: ConnectionLike + Send,
+{
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        async move { self.connection.lock().await.req_packed_command(cmd).await }.boxed()
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        async move {
+            self.connection
+                .lock()
+                .await
+                .req_packed_commands(cmd, offset, count)
+                .await
+        }
+        .boxed()
+    }
+
+    fn get_db(&self) -> i64 {
+        self.db
+    }
+}
+
+/// Pair of connections sending peeks to a read replica and everything else
+/// to the primary.
+///
+/// A peek is a `CL.THROTTLE` applying no tokens, i.e. a rule whose policy
+/// is built with [`apply_tokens(0)`](redis_cell_rs::Policy::apply_tokens):
+/// it reports the state of the bucket without consuming from it, and so can
+/// be answered by a replica to offload the primary. Keep in mind that:
+///
+/// - the replica lags behind the primary, so a peek may report more tokens
+///   remaining than there actually are;
+/// - Redis Cell registers `CL.THROTTLE` as a write command, so the replica
+///   has to be configured with `replica-read-only no` (anything it writes
+///   when peeking stays local to the replica).
+///
+///```no_run
+/// use tower_redis_cell::ReplicaConnection;
+///
+/// # async fn run() {
+/// let primary = redis::Client::open("redis://primary/").unwrap();
+/// let replica = redis::Client::open("redis://replica/").unwrap();
+/// let connection = ReplicaConnection::new(
+///     primary.get_multiplexed_async_connection().await.unwrap(),
+///     replica.get_multiplexed_async_connection().await.unwrap(),
+/// );
+/// # }
+///```
+#[derive(Debug, Clone)]
+pub struct ReplicaConnection<P, R> {
+    primary: P,
+    replica: R,
+}
+
+impl<P, R> ReplicaConnection<P, R> {
+    pub fn new(primary: P, replica: R) -> Self {
+        ReplicaConnection { primary, replica }
+    }
+}
+
+/// Whether this is a `CL.THROTTLE` applying no tokens.
+fn is_peek(cmd: &Cmd) -> bool {
+    let args: Vec<_> = cmd.args_iter().collect();
+    match args.as_slice() {
+        [
+            Arg::Simple(name),
+            _key,
+            _burst,
+            _tokens,
+            _period,
+            Arg::Simple(apply),
+        ] => name.eq_ignore_ascii_case(b"CL.THROTTLE") && *apply == b"0",
+        _ => false,
+    }
+}
+
+impl<P, R> ConnectionLike for ReplicaConnection<P, R>
+where
+    P: ConnectionLike + Send,
+    R: ConnectionLike + Send,
+{
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        if is_peek(cmd) {
+            self.replica.req_packed_command(cmd)
+        } else {
+            self.primary.req_packed_command(cmd)
+        }
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        self.primary.req_packed_commands(cmd, offset, count)
+    }
+
+    fn get_db(&self) -> i64 {
+        self.primary.get_db()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rate_limit_check;
+    use crate::rule::Rule;
+    use crate::testing::MockConnection;
+    use redis_cell_rs::Policy;
+
+    #[tokio::test]
+    async fn clones_share_the_connection() {
+        let mock = MockConnection::allowed(10, 9);
+        let mut connection = MutexConnection::new(mock.clone());
+        let mut clone = connection.clone();
+
+        let rule = Rule::new("user123", Policy::from_tokens_per_second(10));
+        rate_limit_check(&mut connection, &rule).await.unwrap();
+        rate_limit_check(&mut clone, &rule).await.unwrap();
+        assert_eq!(mock.calls(), 2);
+        assert_eq!(clone.get_db(), 0);
+    }
+
+    #[tokio::test]
+    async fn sends_the_peeks_to_the_replica() {
+        let (primary, replica) = (
+            MockConnection::allowed(10, 9),
+            MockConnection::allowed(10, 9),
+        );
+        let mut connection = ReplicaConnection::new(primary.clone(), replica.clone());
+        let policy = Policy::from_tokens_per_second(10);
+
+        let peek = Rule::new("user123", policy.apply_tokens(0));
+        rate_limit_check(&mut connection, &peek).await.unwrap();
+        assert_eq!((primary.calls(), replica.calls()), (0, 1));
+        rate_limit_check(&mut connection, &Rule::new("user123", policy))
+            .await
+            .unwrap();
+        assert_eq!((primary.calls(), replica.calls()), (1, 1));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::rule::Rule;
+use redis_cell_rs::{Key, Policy, Verdict};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+
+/// A rate-limiting decision, see [`RateLimitConfig::decision_channel`](crate::RateLimitConfig::decision_channel).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DecisionEvent {
+    pub key: Key<'static>,
+    /// The key rendered as per [`RateLimitConfig::key_log_policy`](crate::RateLimitConfig::key_log_policy)
+    /// (or [`redact_key`](crate::RateLimitConfig::redact_key)), for logs and
+    /// audit trails which should not carry the key itself.
+    pub redacted_key: Option<String>,
+    pub policy: Policy,
+    pub verdict: Verdict,
+    /// Time it took to reach the verdict, including procuring a connection.
+    pub latency: Duration,
+    pub timestamp: SystemTime,
+    /// Resource the rule protects, see [`Rule::resource`].
+    pub resource: Option<&'static str>,
+    /// Labels attached to the rule, see [`Rule::labels`].
+    pub labels: &'static [(&'static str, &'static str)],
+}
+
+/// Receiving end of the decision channel.
+#[derive(Debug)]
+pub struct DecisionStream {
+    rx: mpsc::Receiver<DecisionEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl DecisionStream {
+    /// Receive the next event, or `None` once all the services are gone.
+    pub async fn recv(&mut self) -> Option<DecisionEvent> {
+        self.rx.recv().await
+    }
+
+    /// Receive the next event if there is one already.
+    pub fn try_recv(&mut self) -> Option<DecisionEvent> {
+        self.rx.try_recv().ok()
+    }
+
+    /// Number of events dropped so far because the channel was full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+pub(crate) struct DecisionSender {
+    tx: mpsc::Sender<DecisionEvent>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl DecisionSender {
+    pub(crate) fn channel(capacity: usize) -> (Self, DecisionStream) {
+        let (tx, rx) = mpsc::channel(capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let sender = DecisionSender {
+            tx,
+            dropped: Arc::clone(&dropped),
+        };
+        (sender, DecisionStream { rx, dropped })
+    }
+
+    /// Send the event without waiting, dropping it if the channel is full.
+    pub(crate) fn send(
+        &self,
+        rule: &Rule<'_>,
+        redacted_key: Option<String>,
+        verdict: &Verdict,
+        latency: Duration,
+    ) {
+        let event = DecisionEvent {
+            key: rule.clone().into_owned().key,
+            redacted_key,
+            policy: rule.policy,
+            verdict: verdict.clone(),
+            latency,
+            timestamp: SystemTime::now(),
+            resource: rule.resource,
+            labels: rule.labels,
+        };
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.tx.try_send(event) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
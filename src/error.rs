@@ -1,7 +1,12 @@
-use crate::rule::RequestBlockedDetails;
+use crate::policy::PolicyError;
+use crate::rule::{RequestBlockedDetails, key_into_owned};
+#[cfg(feature = "bb8")]
+use bb8::RunError;
 #[cfg(feature = "deadpool")]
 use deadpool_redis::PoolError;
-use redis::RedisError;
+#[cfg(feature = "mobc")]
+use mobc::Error as MobcError;
+use redis::{ErrorKind, RedisError};
 use redis_cell_rs::Key;
 use std::borrow::Cow;
 use std::fmt::Display;
@@ -25,6 +30,14 @@ impl Display for ProvideRuleError<'_> {
 }
 
 impl<'a> ProvideRuleError<'a> {
+    /// Detach the error from the request it has been produced for.
+    pub fn into_owned(self) -> ProvideRuleError<'static> {
+        ProvideRuleError {
+            detail: self.detail.map(|detail| Cow::Owned(detail.into_owned())),
+            key: self.key.map(key_into_owned),
+        }
+    }
+
     pub fn new<K, D>(key: K, detail: D) -> Self
     where
         K: Into<Key<'a>>,
@@ -62,19 +75,179 @@ impl<'a> From<&'a str> for ProvideRuleError<'a> {
     }
 }
 
-#[derive(Debug, thiserror::Error)]
+// blocked requests are the hot path under load, and boxing their details
+// would cost an allocation for every one of them
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
 #[non_exhaustive]
 pub enum Error<'a> {
-    #[error("rule: {0}")]
     ProvideRule(ProvideRuleError<'a>),
 
-    #[error(transparent)]
-    Redis(#[from] RedisError),
+    Redis {
+        source: RedisError,
+        /// The rate-limiting command that failed, with the key rendered as per
+        /// [`RateLimitConfig::key_log_policy`](crate::RateLimitConfig::key_log_policy)
+        /// (hashed by default).
+        command: Option<String>,
+    },
 
     #[cfg(feature = "deadpool")]
-    #[error(transparent)]
-    Deadpool(#[from] PoolError),
+    Deadpool(PoolError),
+
+    #[cfg(feature = "bb8")]
+    Bb8(RunError<RedisError>),
+
+    #[cfg(feature = "mobc")]
+    Mobc(MobcError<RedisError>),
 
-    #[error("request blocked for key {} and can be retried after {} second(s)", .0.rule.key, .0.details.retry_after)]
     RateLimit(RequestBlockedDetails<'a>),
+
+    /// The policy cannot be sent to Redis Cell, see [`PolicyExt::validate`](crate::PolicyExt::validate).
+    Policy(PolicyError),
+
+    /// No command in flight completed in time to make room for the request's
+    /// one, see [`RateLimitConfig::max_concurrent_redis`](crate::RateLimitConfig::max_concurrent_redis).
+    Overloaded,
+}
+
+impl Error<'_> {
+    /// Detach the error from the request it has been produced for, e.g. to
+    /// box it as a [`tower::BoxError`].
+    pub fn into_owned(self) -> Error<'static> {
+        match self {
+            Error::ProvideRule(err) => Error::ProvideRule(err.into_owned()),
+            Error::Redis { source, command } => Error::Redis { source, command },
+            #[cfg(feature = "deadpool")]
+            Error::Deadpool(err) => Error::Deadpool(err),
+            #[cfg(feature = "bb8")]
+            Error::Bb8(err) => Error::Bb8(err),
+            #[cfg(feature = "mobc")]
+            Error::Mobc(err) => Error::Mobc(err),
+            Error::RateLimit(details) => Error::RateLimit(details.into_owned()),
+            Error::Policy(err) => Error::Policy(err),
+            Error::Overloaded => Error::Overloaded,
+        }
+    }
+
+    /// Whether Redis does not know the rate-limiting command, which most
+    /// likely means that the Redis Cell module is not loaded.
+    ///
+    ///```
+    /// use redis::{ErrorKind, RedisError, Value};
+    /// use tower_redis_cell::{CellThrottle, Error, Limiter};
+    ///
+    /// let unknown: RedisError = (
+    ///     ErrorKind::ResponseError,
+    ///     "An error was signalled by the server",
+    ///     "unknown command 'CL.THROTTLE', with args beginning with: 'user123'".to_string(),
+    /// )
+    ///     .into();
+    /// let err = Error::from(unknown);
+    /// assert!(err.is_module_missing());
+    /// assert!(!err.is_invalid_reply());
+    ///
+    /// let reply = Value::Array(vec![Value::Int(0), Value::Int(10)]);
+    /// let err = Error::from(CellThrottle::new().verdict(&reply).unwrap_err());
+    /// assert!(err.is_invalid_reply());
+    /// assert!(!err.is_module_missing());
+    /// assert!(err.to_string().contains("[int(0), int(10)]"));
+    ///```
+    pub fn is_module_missing(&self) -> bool {
+        match self {
+            Error::Redis { source, .. } => {
+                source.kind() == ErrorKind::ResponseError
+                    && source.detail().is_some_and(|detail| {
+                        detail.to_ascii_lowercase().starts_with("unknown command")
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the reply to the rate-limiting command could not be decoded,
+    /// e.g. because the module has changed its protocol.
+    ///
+    /// The error detail holds the reply (truncated), see
+    /// [`Error::is_module_missing`] for an example.
+    pub fn is_invalid_reply(&self) -> bool {
+        match self {
+            Error::Redis { source, .. } => source.kind() == ErrorKind::ParseError,
+            _ => false,
+        }
+    }
+}
+
+impl Display for Error<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::ProvideRule(err) => write!(f, "rule: {}", err),
+            Error::Redis { source, command } => {
+                Display::fmt(source, f)?;
+                if let Some(command) = command {
+                    write!(f, " (command: {})", command)?;
+                }
+                Ok(())
+            }
+            #[cfg(feature = "deadpool")]
+            Error::Deadpool(err) => Display::fmt(err, f),
+            #[cfg(feature = "bb8")]
+            Error::Bb8(err) => Display::fmt(err, f),
+            #[cfg(feature = "mobc")]
+            Error::Mobc(err) => Display::fmt(err, f),
+            Error::RateLimit(details) => write!(
+                f,
+                "request blocked for key {} and can be retried after {} second(s)",
+                details.rule.key,
+                details.retry_after()
+            ),
+            Error::Policy(err) => Display::fmt(err, f),
+            Error::Overloaded => f.write_str("too many rate-limiting commands in flight"),
+        }
+    }
+}
+
+impl std::error::Error for Error<'_> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Redis { source, .. } => Some(source),
+            Error::Policy(err) => Some(err),
+            #[cfg(feature = "deadpool")]
+            Error::Deadpool(err) => err.source(),
+            #[cfg(feature = "bb8")]
+            Error::Bb8(RunError::User(err)) => Some(err),
+            #[cfg(feature = "mobc")]
+            Error::Mobc(MobcError::Inner(err)) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<RedisError> for Error<'_> {
+    fn from(source: RedisError) -> Self {
+        Error::Redis {
+            source,
+            command: None,
+        }
+    }
+}
+
+#[cfg(feature = "deadpool")]
+impl From<PoolError> for Error<'_> {
+    fn from(err: PoolError) -> Self {
+        Error::Deadpool(err)
+    }
+}
+
+#[cfg(feature = "bb8")]
+impl From<RunError<RedisError>> for Error<'_> {
+    fn from(err: RunError<RedisError>) -> Self {
+        Error::Bb8(err)
+    }
+}
+
+#[cfg(feature = "mobc")]
+impl From<MobcError<RedisError>> for Error<'_> {
+    fn from(err: MobcError<RedisError>) -> Self {
+        Error::Mobc(err)
+    }
 }
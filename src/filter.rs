@@ -0,0 +1,123 @@
+//! Composition with [`tower::filter`].
+
+use crate::error::Error;
+use crate::rule::{ProvideRule, RequestBlockedDetails};
+use crate::service::rate_limit_check;
+use futures_util::future::{BoxFuture, FutureExt as _};
+use redis::aio::ConnectionLike;
+use redis_cell_rs::Verdict;
+use tower::BoxError;
+use tower::filter::AsyncPredicate;
+
+/// Rate limit as an [`AsyncPredicate`] for [`AsyncFilter`](tower::filter::AsyncFilter).
+///
+/// Requests are checked with [`rate_limit_check`] and passed through when
+/// allowed or unruled, while a blocked request (or a failure to check it) is
+/// rejected with [`Error`], boxed into [`BoxError`].
+///
+/// Prefer this over [`RateLimitLayer`](crate::RateLimitLayer) when your stack
+/// already turns service errors into responses (say, with axum's
+/// `HandleErrorLayer`) and none of the handlers and options offered by
+/// [`RateLimitConfig`](crate::RateLimitConfig) are needed.
+///
+///```no_run
+/// use tower::filter::AsyncFilterLayer;
+/// use tower::{ServiceBuilder, service_fn};
+/// use tower_redis_cell::filter::RateLimitPredicate;
+/// use tower_redis_cell::redis_cell::Policy;
+/// use tower_redis_cell::{ProvideRule, ProvideRuleResult, Rule};
+///
+/// #[derive(Clone)]
+/// struct ByUser;
+///
+/// impl ProvideRule<String> for ByUser {
+///     fn provide<'a>(&self, user: &'a String) -> ProvideRuleResult<'a> {
+///         Ok(Some(Rule::new(user.as_str(), Policy::from_tokens_per_minute(1))))
+///     }
+/// }
+///
+/// # async fn run() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let connection = redis::aio::ConnectionManager::new(client).await.unwrap();
+///
+/// let svc = ServiceBuilder::new()
+///     .layer(AsyncFilterLayer::new(RateLimitPredicate::new(ByUser, connection)))
+///     .service(service_fn(|user: String| async move { Ok::<_, tower::BoxError>(user) }));
+/// # }
+///```
+#[derive(Debug, Clone)]
+pub struct RateLimitPredicate<PR, C> {
+    rule_provider: PR,
+    connection: C,
+}
+
+impl<PR, C> RateLimitPredicate<PR, C> {
+    pub fn new(rule_provider: PR, connection: C) -> Self {
+        RateLimitPredicate {
+            rule_provider,
+            connection,
+        }
+    }
+}
+
+impl<R, PR, C> AsyncPredicate<R> for RateLimitPredicate<PR, C>
+where
+    R: Send + 'static,
+    PR: ProvideRule<R>,
+    C: ConnectionLike + Clone + Send + 'static,
+{
+    type Future = BoxFuture<'static, Result<R, BoxError>>;
+    type Request = R;
+
+    fn check(&mut self, req: R) -> Self::Future {
+        let rule = match self.rule_provider.provide(&req) {
+            Ok(rule) => rule.map(|rule| rule.into_owned()),
+            Err(e) => {
+                let err = Error::ProvideRule(e).into_owned();
+                return std::future::ready(Err(err.into())).boxed();
+            }
+        };
+        let mut connection = self.connection.clone();
+        async move {
+            let Some(rule) = rule else {
+                return Ok(req);
+            };
+            match rate_limit_check(&mut connection, &rule).await? {
+                Verdict::Allowed(_) => Ok(req),
+                Verdict::Blocked(details) => {
+                    Err(Error::RateLimit(RequestBlockedDetails { details, rule }).into())
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::Rule;
+    use crate::testing::{Fixed, LocalGcra};
+    use redis_cell_rs::Policy;
+    use tower::filter::AsyncFilterLayer;
+    use tower::{ServiceBuilder, ServiceExt as _, service_fn};
+
+    #[tokio::test]
+    async fn rejects_the_blocked_requests() {
+        let rule = Rule::new("user123", Policy::from_tokens_per_minute(1).max_burst(0));
+        let predicate = RateLimitPredicate::new(Fixed(rule), LocalGcra::new());
+        let svc = ServiceBuilder::new()
+            .layer(AsyncFilterLayer::new(predicate))
+            .service(service_fn(
+                |user: String| async move { Ok::<_, BoxError>(user) },
+            ));
+
+        let user = svc.clone().oneshot("user123".to_string()).await.unwrap();
+        assert_eq!(user, "user123");
+        let err = svc.oneshot("user123".to_string()).await.unwrap_err();
+        let err = err.downcast::<Error>().unwrap();
+        assert!(
+            matches!(*err, Error::RateLimit(details) if details.rule.key.to_string() == "user123")
+        );
+    }
+}
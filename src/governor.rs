@@ -0,0 +1,128 @@
+//! Compatibility with [`tower_governor`](https://docs.rs/tower_governor).
+//!
+//! Key extractors written for `tower_governor` can be reused as rule providers
+//! with [`GovernorAdapter`], which makes it easy to migrate from in-memory to
+//! distributed rate limiting:
+//!
+//!```
+//! use http::Request;
+//! use tower_governor::GovernorError;
+//! use tower_governor::key_extractor::KeyExtractor;
+//! use tower_redis_cell::ProvideRule;
+//! use tower_redis_cell::governor::GovernorAdapter;
+//! use tower_redis_cell::redis_cell::Policy;
+//!
+//! #[derive(Clone)]
+//! struct ApiKeyExtractor;
+//!
+//! impl KeyExtractor for ApiKeyExtractor {
+//!     type Key = String;
+//!
+//!     fn extract<T>(&self, req: &Request<T>) -> Result<Self::Key, GovernorError> {
+//!         req.headers()
+//!             .get("x-api-key")
+//!             .and_then(|val| val.to_str().ok())
+//!             .map(ToOwned::to_owned)
+//!             .ok_or(GovernorError::UnableToExtractKey)
+//!     }
+//! }
+//!
+//! let provider = GovernorAdapter::new(ApiKeyExtractor, Policy::from_tokens_per_second(1));
+//!
+//! let req = Request::builder().header("x-api-key", "secret").body(()).unwrap();
+//! let rule = provider.provide(&req).unwrap().unwrap();
+//! assert_eq!(rule.key.to_string(), "secret");
+//!
+//! let req = Request::new(());
+//! let err = provider.provide(&req).unwrap_err();
+//! assert_eq!(err.detail.as_deref(), Some("Unable to extract key!"));
+//!```
+//!
+//! Existing [`Quota`] definitions can be carried over with [`policy_from_quota`].
+
+use crate::error::ProvideRuleError;
+use crate::rule::{ProvideRule, ProvideRuleResult, Rule};
+use governor::Quota;
+use http::Request;
+use redis_cell_rs::Policy;
+use std::fmt::Display;
+use std::time::Duration;
+use tower_governor::GovernorError;
+use tower_governor::key_extractor::KeyExtractor;
+
+/// Rule provider backed by a `tower_governor` key extractor.
+///
+/// The extracted key is rendered with its [`Display`] implementation and
+/// combined with the fixed policy.
+#[derive(Debug, Clone)]
+pub struct GovernorAdapter<K> {
+    extractor: K,
+    policy: Policy,
+}
+
+impl<K> GovernorAdapter<K> {
+    pub fn new(extractor: K, policy: Policy) -> Self {
+        GovernorAdapter { extractor, policy }
+    }
+}
+
+impl<B, K> ProvideRule<Request<B>> for GovernorAdapter<K>
+where
+    K: KeyExtractor,
+    K::Key: Display,
+{
+    fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+        let key = self.extractor.extract(req).map_err(|err| match err {
+            GovernorError::Other { msg: Some(msg), .. } => ProvideRuleError::from(msg),
+            err => ProvideRuleError::from(err.to_string()),
+        })?;
+        Ok(Some(Rule::new(key.to_string(), self.policy)))
+    }
+}
+
+/// Translate a `governor` [`Quota`] into a [`Policy`].
+///
+/// Both crates implement GCRA, so a quota replenishing one cell every
+/// interval with a burst size of `n` maps onto a policy emitting one token per
+/// that interval with a bucket of `n` tokens, i.e. `max_burst(n - 1)`.
+///
+/// Redis Cell only supports periods of whole seconds though, so the rate gets
+/// approximated: an interval below one second turns into the nearest whole
+/// number of tokens per second (e.g. `Quota::per_second(3)` replenishes every
+/// 333,333,333 nanoseconds and becomes 3 tokens per second), while a longer
+/// interval is rounded to the nearest second.
+///
+///```
+/// use governor::Quota;
+/// use std::num::NonZeroU32;
+/// use std::time::Duration;
+/// use tower_redis_cell::governor::policy_from_quota;
+///
+/// let quota = Quota::per_second(NonZeroU32::new(3).unwrap());
+/// let policy = policy_from_quota(quota);
+/// assert_eq!((policy.tokens, policy.period, policy.burst), (3, Duration::from_secs(1), 2));
+///
+/// let quota = Quota::per_minute(NonZeroU32::new(10).unwrap()).allow_burst(NonZeroU32::new(1).unwrap());
+/// let policy = policy_from_quota(quota);
+/// assert_eq!((policy.tokens, policy.period, policy.burst), (1, Duration::from_secs(6), 0));
+///
+/// let quota = Quota::with_period(Duration::from_millis(1500)).unwrap();
+/// let policy = policy_from_quota(quota);
+/// assert_eq!((policy.tokens, policy.period, policy.burst), (1, Duration::from_secs(2), 0));
+///```
+///
+/// Note that this is a function rather than a `TryFrom` implementation, since
+/// neither of the types is defined in this crate.
+pub fn policy_from_quota(quota: Quota) -> Policy {
+    const SECOND: u128 = 1_000_000_000;
+    let burst = quota.burst_size().get() as usize - 1;
+    let interval = quota.replenish_interval().as_nanos().max(1);
+    let policy = if interval < SECOND {
+        let tokens = (SECOND + interval / 2) / interval;
+        Policy::from_tokens_per_second(tokens as usize)
+    } else {
+        let secs = (interval + SECOND / 2) / SECOND;
+        Policy::from_tokens_per_period(1, Duration::from_secs(secs as u64))
+    };
+    policy.max_burst(burst)
+}
@@ -0,0 +1,62 @@
+use redis_cell_rs::Verdict;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Entry {
+    verdicts: VecDeque<Verdict>,
+    updated_at: Instant,
+}
+
+/// Per-key record of the latest verdicts, see [`RateLimitConfig::escalation`](crate::RateLimitConfig::escalation).
+pub(crate) struct VerdictHistory {
+    depth: usize,
+    ttl: Duration,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl VerdictHistory {
+    pub(crate) fn new(depth: usize, ttl: Duration) -> Self {
+        VerdictHistory {
+            depth,
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Call `f` with the verdicts recorded for this key, oldest first, or
+    /// with an empty slice if there are none or they have expired.
+    pub(crate) fn with<F, T>(&self, key: &str, f: F) -> T
+    where
+        F: FnOnce(&[Verdict]) -> T,
+    {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(key) {
+            Some(entry) if entry.updated_at.elapsed() < self.ttl => {
+                f(entry.verdicts.make_contiguous())
+            }
+            _ => f(&[]),
+        }
+    }
+
+    /// Record the verdict, forgetting the oldest one if the history is full.
+    pub(crate) fn record(&self, key: String, verdict: &Verdict) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() == entries.capacity() {
+            entries.retain(|_, entry| entry.updated_at.elapsed() < self.ttl);
+        }
+        let now = Instant::now();
+        let entry = entries.entry(key).or_insert_with(|| Entry {
+            verdicts: VecDeque::with_capacity(self.depth),
+            updated_at: now,
+        });
+        if entry.updated_at.elapsed() >= self.ttl {
+            entry.verdicts.clear();
+        }
+        if entry.verdicts.len() == self.depth {
+            entry.verdicts.pop_front();
+        }
+        entry.verdicts.push_back(verdict.clone());
+        entry.updated_at = now;
+    }
+}
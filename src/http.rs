@@ -0,0 +1,967 @@
+//! Helpers for services speaking [`http`](https://docs.rs/http).
+//!
+//! If you do not need any custom error handling, [`default_error_response`]
+//! maps each [`Error`] onto a sensible HTTP response, and
+//! [`RateLimitConfig::with_default_http_handler`](crate::RateLimitConfig::with_default_http_handler)
+//! wires it up for you:
+//!
+//!```
+//! use http::StatusCode;
+//! use tower_redis_cell::http::default_error_response;
+//! use tower_redis_cell::{Error, ProvideRuleError};
+//!
+//! let err = Error::ProvideRule(ProvideRuleError::from("'x-api-key' header is missing"));
+//! let resp = default_error_response::<String>(err);
+//! assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+//!```
+
+use crate::error::{Error, ProvideRuleError};
+use crate::rule::{
+    ProvideRule, ProvideRuleResult, ProvideRulesResult, RequestAllowedDetails,
+    RequestBlockedDetails, Rule,
+};
+use crate::service::ResponseFuture;
+#[cfg(feature = "axum")]
+use crate::{config::RateLimitConfig, service::RateLimitLayer};
+#[cfg(feature = "axum")]
+use axum::body::Body;
+use bytes::Bytes;
+use http::{HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, header};
+use http_body::Body as HttpBody;
+use http_body_util::{BodyExt as _, LengthLimitError, Limited};
+use redis_cell_rs::{AllowedDetails, BlockedDetails, Key, Policy, Verdict};
+use std::net::IpAddr;
+use std::task::{Context, Poll};
+use tower::{BoxError, Layer, Service};
+
+/// Build a response for the given error.
+///
+/// Failure to provide a rule results in `401 Unauthorized`, a blocked request
+/// in `429 Too Many Requests` with the `Retry-After` header set, and any other
+/// error in a bodiless `500 Internal Server Error`, so that backend details
+/// are not leaked to the client.
+pub fn default_error_response<B>(err: Error<'_>) -> Response<B>
+where
+    B: From<String> + Default,
+{
+    match err {
+        Error::ProvideRule(err) => {
+            let mut resp = Response::new(B::from(err.to_string()));
+            *resp.status_mut() = StatusCode::UNAUTHORIZED;
+            resp
+        }
+        Error::RateLimit(err) => {
+            let mut resp = Response::new(B::from("too many requests".to_string()));
+            *resp.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+            resp.headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(err.retry_after()));
+            resp
+        }
+        _ => {
+            let mut resp = Response::new(B::default());
+            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            resp
+        }
+    }
+}
+
+/// Build a response for the given error, asking clients to back off on
+/// backend outages.
+///
+/// Since this crate fails closed, i.e. requests are rejected whenever the
+/// limit cannot be checked, a bare `500` gives clients no guidance during
+/// a Redis outage. This responds to [`Error::Redis`] (and pool errors) with
+/// `503 Service Unavailable` and `Retry-After` set to `retry_after` seconds,
+/// and otherwise behaves just like [`default_error_response`].
+///
+///```
+/// use http::{Response, StatusCode, header};
+/// use redis::{ErrorKind, RedisError};
+/// use tower_redis_cell::http::unavailable_error_response;
+/// use tower_redis_cell::{Error, RateLimitConfig};
+/// # use tower_redis_cell::{ProvideRule, ProvideRuleResult};
+/// # struct Provider;
+/// # impl ProvideRule<()> for Provider {
+/// #     fn provide<'a>(&self, _: &'a ()) -> ProvideRuleResult<'a> { Ok(None) }
+/// # }
+///
+/// let config: RateLimitConfig<_, _, Response<String>, _> = RateLimitConfig::new(
+///     Provider,
+///     |err, _req: &()| unavailable_error_response::<String>(err, 5),
+/// );
+///
+/// let err = Error::from(RedisError::from((ErrorKind::IoError, "connection refused")));
+/// let resp = unavailable_error_response::<String>(err, 5);
+/// assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+/// assert_eq!(resp.headers()[header::RETRY_AFTER], "5");
+///```
+pub fn unavailable_error_response<B>(err: Error<'_>, retry_after: u64) -> Response<B>
+where
+    B: From<String> + Default,
+{
+    match err {
+        Error::Redis { .. } => unavailable(retry_after),
+        #[cfg(feature = "deadpool")]
+        Error::Deadpool(_) => unavailable(retry_after),
+        #[cfg(feature = "bb8")]
+        Error::Bb8(_) => unavailable(retry_after),
+        #[cfg(feature = "mobc")]
+        Error::Mobc(_) => unavailable(retry_after),
+        err => default_error_response(err),
+    }
+}
+
+fn unavailable<B: Default>(retry_after: u64) -> Response<B> {
+    let mut resp = Response::new(B::default());
+    *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    resp.headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+    resp
+}
+
+/// Seconds clients are asked to wait before retrying while Redis is
+/// unreachable, as advertised by [`rate_limit_layer`].
+#[cfg(feature = "axum")]
+#[cfg_attr(docsrs, doc(cfg(feature = "axum")))]
+pub const UNAVAILABLE_RETRY_AFTER: u64 = 5;
+
+/// Build a rate-limiting layer for an [`axum`](https://docs.rs/axum) router
+/// with sensible defaults.
+///
+/// Errors are mapped onto responses with [`unavailable_error_response`], i.e.
+/// `401`, `429`, `500`, and `503` (advertising a retry after
+/// [`UNAVAILABLE_RETRY_AFTER`] seconds). Both allowed and blocked responses
+/// carry the rate limit headers in the [default style](HeaderStyle::default).
+/// Reach for [`RateLimitConfig`] and [`RateLimitLayer::new`] whenever anything
+/// needs customizing.
+///
+///```no_run
+/// use axum::http::Request;
+/// use axum::{Router, routing::get};
+/// use tower_redis_cell::http::rate_limit_layer;
+/// use tower_redis_cell::redis_cell::Policy;
+/// use tower_redis_cell::{ProvideRule, ProvideRuleResult, Rule};
+///
+/// #[derive(Clone)]
+/// struct ByApiKey;
+///
+/// impl<B> ProvideRule<Request<B>> for ByApiKey {
+///     fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+///         let key = req
+///             .headers()
+///             .get("x-api-key")
+///             .and_then(|val| val.to_str().ok())
+///             .ok_or("'x-api-key' header is missing")?;
+///         Ok(Some(Rule::new(key, Policy::from_tokens_per_second(10))))
+///     }
+/// }
+///
+/// # async fn run() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let connection = redis::aio::ConnectionManager::new(client).await.unwrap();
+///
+/// let app: Router = Router::new()
+///     .route("/", get(|| async { "Hello, World!" }))
+///     .layer(rate_limit_layer(ByApiKey, connection));
+/// # }
+///```
+#[cfg(feature = "axum")]
+#[cfg_attr(docsrs, doc(cfg(feature = "axum")))]
+pub fn rate_limit_layer<PR, C>(
+    rule_provider: PR,
+    connection: C,
+) -> RateLimitLayer<PR, Request<Body>, Response<Body>, Response<Body>, C> {
+    let config = RateLimitConfig::new(rule_provider, |err, _req: &Request<Body>| {
+        let details = match err {
+            Error::RateLimit(ref err) => Some(err.details.clone()),
+            _ => None,
+        };
+        let mut resp = unavailable_error_response(err, UNAVAILABLE_RETRY_AFTER);
+        if let Some(details) = details {
+            HeaderStyle::default().insert(
+                resp.headers_mut(),
+                details.total,
+                details.remaining,
+                details.reset_after,
+            );
+        }
+        resp
+    })
+    .on_success(|details, resp: &mut Response<Body>| {
+        let details = details.details;
+        HeaderStyle::default().insert(
+            resp.headers_mut(),
+            details.total,
+            details.remaining,
+            details.reset_after,
+        );
+    });
+    RateLimitLayer::new(config, connection)
+}
+
+/// Number of tokens a request costs, see
+/// [`RateLimitConfig::cost_from_extension`](crate::RateLimitConfig::cost_from_extension).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cost(pub usize);
+
+impl From<Cost> for usize {
+    fn from(cost: Cost) -> Self {
+        cost.0
+    }
+}
+
+/// Rule provider limiting authenticated users by their ID and anonymous
+/// clients by their IP address.
+///
+/// The `user` extractor is consulted first, and - if it yields a key - the
+/// `authed_policy` is applied. Otherwise the `ip` extractor is used together
+/// with the (normally stricter) `anonymous_policy`. If neither extractor
+/// yields anything, providing the rule fails.
+///
+///```
+/// use http::Request;
+/// use std::net::IpAddr;
+/// use tower_redis_cell::ProvideRule;
+/// use tower_redis_cell::http::UserOrIpProvider;
+/// use tower_redis_cell::redis_cell::{Key, Policy};
+///
+/// const AUTHED: Policy = Policy::from_tokens_per_second(10).name("authed");
+/// const ANONYMOUS: Policy = Policy::from_tokens_per_minute(10).name("anonymous");
+///
+/// let provider = UserOrIpProvider::new(
+///     |req: &Request<()>| {
+///         req.headers()
+///             .get("x-user-id")
+///             .and_then(|val| val.to_str().ok())
+///             .map(Key::from)
+///     },
+///     AUTHED,
+///     |req: &Request<()>| req.extensions().get::<IpAddr>().copied(),
+///     ANONYMOUS,
+/// );
+///
+/// let req = Request::builder().header("x-user-id", "user123").body(()).unwrap();
+/// let rule = provider.provide(&req).unwrap().unwrap();
+/// assert_eq!(rule.key.to_string(), "user123");
+/// assert_eq!(rule.policy.name, Some("authed"));
+///
+/// let mut req = Request::new(());
+/// req.extensions_mut().insert("127.0.0.1".parse::<IpAddr>().unwrap());
+/// let rule = provider.provide(&req).unwrap().unwrap();
+/// assert_eq!(rule.key.to_string(), "127.0.0.1");
+/// assert_eq!(rule.policy.name, Some("anonymous"));
+///
+/// assert!(provider.provide(&Request::new(())).is_err());
+///```
+#[derive(Debug, Clone)]
+pub struct UserOrIpProvider<U, I> {
+    user: U,
+    authed_policy: Policy,
+    ip: I,
+    anonymous_policy: Policy,
+}
+
+impl<U, I> UserOrIpProvider<U, I> {
+    pub fn new<B>(user: U, authed_policy: Policy, ip: I, anonymous_policy: Policy) -> Self
+    where
+        U: for<'r> Fn(&'r Request<B>) -> Option<Key<'r>>,
+        I: Fn(&Request<B>) -> Option<IpAddr>,
+    {
+        UserOrIpProvider {
+            user,
+            authed_policy,
+            ip,
+            anonymous_policy,
+        }
+    }
+}
+
+impl<B, U, I> ProvideRule<Request<B>> for UserOrIpProvider<U, I>
+where
+    U: for<'r> Fn(&'r Request<B>) -> Option<Key<'r>>,
+    I: Fn(&Request<B>) -> Option<IpAddr>,
+{
+    fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+        if let Some(key) = (self.user)(req) {
+            return Ok(Some(Rule::new(key, self.authed_policy)));
+        }
+        let ip = (self.ip)(req).ok_or_else(|| {
+            ProvideRuleError::from("cannot identify client: neither user nor IP address is known")
+        })?;
+        Ok(Some(Rule::new(ip.to_string(), self.anonymous_policy)))
+    }
+}
+
+/// Rule provider applying the inner provider only to canary traffic.
+///
+/// Requests carrying the `header` set to `value` are handed over to the inner
+/// provider, while everyone else is left unruled. This makes it possible to
+/// roll out rate limiting gradually.
+///
+///```
+/// use http::Request;
+/// use tower_redis_cell::http::CanaryProvider;
+/// use tower_redis_cell::redis_cell::Policy;
+/// use tower_redis_cell::{ProvideRule, ProvideRuleResult, Rule};
+///
+/// struct Global;
+///
+/// impl<B> ProvideRule<Request<B>> for Global {
+///     fn provide<'a>(&self, _: &'a Request<B>) -> ProvideRuleResult<'a> {
+///         Ok(Some(Rule::new("global", Policy::from_tokens_per_second(100))))
+///     }
+/// }
+///
+/// let provider = CanaryProvider::new(Global, "x-ratelimit-canary", "1");
+///
+/// let req = Request::builder().header("x-ratelimit-canary", "1").body(()).unwrap();
+/// assert!(provider.provide(&req).unwrap().is_some());
+///
+/// let req = Request::builder().header("x-ratelimit-canary", "0").body(()).unwrap();
+/// assert!(provider.provide(&req).unwrap().is_none());
+/// assert!(provider.provide(&Request::new(())).unwrap().is_none());
+///```
+#[derive(Debug, Clone)]
+pub struct CanaryProvider<P> {
+    inner: P,
+    header: HeaderName,
+    value: HeaderValue,
+}
+
+impl<P> CanaryProvider<P> {
+    /// # Panics
+    ///
+    /// Panics if `header` is not a valid header name or `value` is not a
+    /// valid header value.
+    pub fn new(inner: P, header: &'static str, value: &'static str) -> Self {
+        CanaryProvider {
+            inner,
+            header: HeaderName::from_static(header),
+            value: HeaderValue::from_static(value),
+        }
+    }
+}
+
+impl<B, P> ProvideRule<Request<B>> for CanaryProvider<P>
+where
+    P: ProvideRule<Request<B>>,
+{
+    fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+        if req.headers().get(&self.header) != Some(&self.value) {
+            return Ok(None);
+        }
+        self.inner.provide(req)
+    }
+
+    fn provide_many<'a>(&self, req: &'a Request<B>) -> ProvideRulesResult<'a> {
+        if req.headers().get(&self.header) != Some(&self.value) {
+            return Ok(Vec::new());
+        }
+        self.inner.provide_many(req)
+    }
+}
+
+/// Naming of the rate-limit headers, see [`HeaderStyle::insert`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HeaderStyle {
+    /// `X-RateLimit-Limit`, `X-RateLimit-Remaining`, and `X-RateLimit-Reset`.
+    Legacy,
+    /// `RateLimit-Limit`, `RateLimit-Remaining`, and `RateLimit-Reset`
+    /// as per the IETF draft.
+    #[default]
+    Ietf,
+    /// Both of the above, for APIs with diverse clients.
+    Both,
+}
+
+impl HeaderStyle {
+    const LEGACY: [&'static str; 3] = [
+        "x-ratelimit-limit",
+        "x-ratelimit-remaining",
+        "x-ratelimit-reset",
+    ];
+    const IETF: [&'static str; 3] = ["ratelimit-limit", "ratelimit-remaining", "ratelimit-reset"];
+
+    /// Insert the limit, the remaining quota, and the number of seconds until
+    /// the quota is fully reset, named according to the style.
+    ///
+    /// This is meant for use in the success or error handler, e.g.:
+    ///```
+    /// use http::{HeaderMap, Response};
+    /// use tower_redis_cell::http::HeaderStyle;
+    /// use tower_redis_cell::{RateLimitConfig, RequestAllowedDetails};
+    /// # use tower_redis_cell::{ProvideRule, ProvideRuleResult};
+    /// # struct Provider;
+    /// # impl ProvideRule<()> for Provider {
+    /// #     fn provide<'a>(&self, _: &'a ()) -> ProvideRuleResult<'a> { Ok(None) }
+    /// # }
+    ///
+    /// let config = RateLimitConfig::<_, (), Response<String>, Response<String>>::with_default_http_handler(Provider)
+    ///     .on_success(|details: RequestAllowedDetails, resp: &mut Response<String>| {
+    ///         let details = details.details;
+    ///         let headers = resp.headers_mut();
+    ///         HeaderStyle::Both.insert(headers, details.total, details.remaining, details.reset_after);
+    ///     });
+    ///
+    /// let mut headers = HeaderMap::new();
+    /// HeaderStyle::Legacy.insert(&mut headers, 10, 9, 6);
+    /// assert_eq!(headers["x-ratelimit-remaining"], "9");
+    /// assert!(!headers.contains_key("ratelimit-remaining"));
+    ///
+    /// let mut headers = HeaderMap::new();
+    /// HeaderStyle::Ietf.insert(&mut headers, 10, 9, 6);
+    /// assert_eq!(headers["ratelimit-limit"], "10");
+    /// assert!(!headers.contains_key("x-ratelimit-limit"));
+    ///
+    /// let mut headers = HeaderMap::new();
+    /// HeaderStyle::Both.insert(&mut headers, 10, 9, 6);
+    /// assert_eq!(headers["ratelimit-reset"], "6");
+    /// assert_eq!(headers["x-ratelimit-reset"], "6");
+    ///```
+    pub fn insert(&self, headers: &mut HeaderMap, limit: usize, remaining: usize, reset: u64) {
+        let names: &[[&'static str; 3]] = match self {
+            HeaderStyle::Legacy => &[Self::LEGACY],
+            HeaderStyle::Ietf => &[Self::IETF],
+            HeaderStyle::Both => &[Self::IETF, Self::LEGACY],
+        };
+        for [limit_name, remaining_name, reset_name] in names {
+            headers.insert(*limit_name, HeaderValue::from(limit));
+            headers.insert(*remaining_name, HeaderValue::from(remaining));
+            headers.insert(*reset_name, HeaderValue::from(reset));
+        }
+    }
+}
+
+/// The `RateLimit` and `RateLimit-Policy` headers as per the IETF draft, e.g.
+/// `RateLimit: limit=10, remaining=9, reset=6` and `RateLimit-Policy: 100;w=60`.
+fn ratelimit_headers(
+    policy: &Policy,
+    limit: usize,
+    remaining: usize,
+    reset: u64,
+) -> impl Iterator<Item = (HeaderName, HeaderValue)> + use<> {
+    let ratelimit = format!("limit={}, remaining={}, reset={}", limit, remaining, reset);
+    let ratelimit_policy = format!("{};w={}", policy.tokens, policy.period.as_secs());
+    [
+        (HeaderName::from_static("ratelimit"), ratelimit),
+        (
+            HeaderName::from_static("ratelimit-policy"),
+            ratelimit_policy,
+        ),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name, HeaderValue::from_str(&value).expect("visible ASCII")))
+}
+
+impl RequestAllowedDetails {
+    /// The `RateLimit` and `RateLimit-Policy` headers as per the
+    /// [IETF draft](https://datatracker.ietf.org/doc/draft-ietf-httpapi-ratelimit-headers/).
+    ///
+    ///```
+    /// # use axum::http::{Request, Response, StatusCode};
+    /// # use tower_redis_cell::{Error, RateLimitConfig};
+    /// # #[derive(Clone)]
+    /// # struct Global;
+    /// let config = RateLimitConfig::new(Global, |err: Error, _req: &Request<()>| {
+    ///     let mut resp = Response::new(());
+    ///     *resp.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    ///     if let Error::RateLimit(details) = err {
+    ///         resp.headers_mut().extend(details.ratelimit_headers());
+    ///     }
+    ///     resp
+    /// })
+    /// .on_success(|details, resp: &mut Response<()>| {
+    ///     resp.headers_mut().extend(details.ratelimit_headers());
+    /// });
+    /// # let _: RateLimitConfig<_, _, _, _> = config;
+    ///```
+    pub fn ratelimit_headers(&self) -> impl Iterator<Item = (HeaderName, HeaderValue)> + use<> {
+        let details = &self.details;
+        ratelimit_headers(
+            &self.policy,
+            details.total,
+            details.remaining,
+            details.reset_after,
+        )
+    }
+}
+
+impl RequestBlockedDetails<'_> {
+    /// The `RateLimit` and `RateLimit-Policy` headers as per the IETF draft,
+    /// see [`RequestAllowedDetails::ratelimit_headers`].
+    pub fn ratelimit_headers(&self) -> impl Iterator<Item = (HeaderName, HeaderValue)> + use<> {
+        let details = &self.details;
+        ratelimit_headers(
+            &self.rule.policy,
+            details.total,
+            details.remaining,
+            details.reset_after,
+        )
+    }
+}
+
+/// The `Retry-After` header for a blocked verdict, e.g. one returned by
+/// [`rate_limit_check`](crate::rate_limit_check) outside of any service.
+///
+/// The backend's `retry_after` is used as is: within a service, prefer
+/// [`RequestBlockedDetails::retry_after`], which honors the rule's bounds.
+pub trait RetryAfterHeader {
+    fn retry_after_header(&self) -> (HeaderName, HeaderValue);
+}
+
+impl RetryAfterHeader for BlockedDetails {
+    fn retry_after_header(&self) -> (HeaderName, HeaderValue) {
+        (header::RETRY_AFTER, HeaderValue::from(self.retry_after))
+    }
+}
+
+/// The limit, remaining quota, and reset headers (see [`HeaderStyle`]) for
+/// a verdict, along with `Retry-After` if blocked, e.g. for a hand-written
+/// hyper service built around [`rate_limit_check`](crate::rate_limit_check).
+///
+///```no_run
+/// use axum::body::Body;
+/// use axum::http::{Request, Response, StatusCode};
+/// use axum::{Router, routing::post};
+/// use tower_redis_cell::http::{BufferBodyLayer, BufferedBody};
+/// use tower_redis_cell::redis_cell::Policy;
+/// use tower_redis_cell::{Error, ProvideRule, ProvideRuleResult, RateLimitConfig, RateLimitLayer, Rule};
+///
+/// #[derive(Clone)]
+/// struct ByAccount;
+///
+/// impl<B> ProvideRule<Request<B>> for ByAccount {
+///     fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+///         let body = req.extensions().get::<BufferedBody>().ok_or("body is not buffered")?;
+///         let body: serde_json::Value = serde_json::from_slice(&body.0).map_err(|e| e.to_string())?;
+///         let account = body["account_id"].as_str().ok_or("'account_id' is missing")?;
+///         Ok(Some(Rule::new(account.to_string(), Policy::from_tokens_per_second(10))))
+///     }
+/// }
+///
+/// # async fn run() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let connection = redis::aio::ConnectionManager::new(client).await.unwrap();
+///
+/// let config = RateLimitConfig::new(ByAccount, |err: Error, _req: &Request<Body>| {
+///     let mut resp = Response::new(Body::from(err.to_string()));
+///     *resp.status_mut() = StatusCode::BAD_REQUEST;
+///     resp
+/// });
+/// let app: Router = Router::new()
+///     .route("/rpc", post(|body: String| async move { body }))
+///     .layer(RateLimitLayer::new(config, connection))
+///     .layer(BufferBodyLayer::new(1024));
+/// # }
+///```
+pub trait RateLimitHeaders {
+    fn ratelimit_header_map(&self, style: HeaderStyle) -> HeaderMap;
+}
+
+impl RateLimitHeaders for AllowedDetails {
+    fn ratelimit_header_map(&self, style: HeaderStyle) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        style.insert(&mut headers, self.total, self.remaining, self.reset_after);
+        headers
+    }
+}
+
+impl RateLimitHeaders for BlockedDetails {
+    fn ratelimit_header_map(&self, style: HeaderStyle) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        style.insert(&mut headers, self.total, self.remaining, self.reset_after);
+        let (name, value) = self.retry_after_header();
+        headers.insert(name, value);
+        headers
+    }
+}
+
+impl RateLimitHeaders for Verdict {
+    fn ratelimit_header_map(&self, style: HeaderStyle) -> HeaderMap {
+        match self {
+            Verdict::Allowed(details) => details.ratelimit_header_map(style),
+            Verdict::Blocked(details) => details.ratelimit_header_map(style),
+        }
+    }
+}
+
+/// Whether this is an HTTP/1.1 WebSocket upgrade request, i.e. whether the
+/// `Connection` header lists `upgrade` and the `Upgrade` header lists
+/// `websocket` (both case-insensitively).
+pub fn is_websocket_upgrade<B>(req: &Request<B>) -> bool {
+    let lists = |name: HeaderName, token: &str| {
+        req.headers().get_all(name).iter().any(|value| {
+            value.to_str().is_ok_and(|value| {
+                value
+                    .split(',')
+                    .any(|item| item.trim().eq_ignore_ascii_case(token))
+            })
+        })
+    };
+    lists(header::CONNECTION, "upgrade") && lists(header::UPGRADE, "websocket")
+}
+
+/// Rule provider for WebSocket endpoints.
+///
+/// Once the connection is upgraded, frames are no longer HTTP requests and
+/// never reach the layer, so it is the upgrade request (see
+/// [`is_websocket_upgrade`]) which is to be limited, e.g. with a policy
+/// for connection establishment. Other requests to the same route are left
+/// unruled unless a provider for them is set with [`WebSocketProvider::otherwise`].
+///
+///```
+/// use http::Request;
+/// use tower_redis_cell::http::WebSocketProvider;
+/// use tower_redis_cell::redis_cell::Policy;
+/// use tower_redis_cell::{ProvideRule, ProvideRuleResult, Rule};
+///
+/// struct Connects;
+///
+/// impl<B> ProvideRule<Request<B>> for Connects {
+///     fn provide<'a>(&self, _: &'a Request<B>) -> ProvideRuleResult<'a> {
+///         Ok(Some(Rule::new("ws-connect", Policy::from_tokens_per_minute(10))))
+///     }
+/// }
+///
+/// let provider = WebSocketProvider::new(Connects);
+///
+/// let req = Request::builder()
+///     .uri("/ws")
+///     .header("connection", "keep-alive, Upgrade")
+///     .header("upgrade", "websocket")
+///     .body(())
+///     .unwrap();
+/// let rule = provider.provide(&req).unwrap().unwrap();
+/// assert_eq!(rule.key.to_string(), "ws-connect");
+///
+/// let req = Request::builder().uri("/ws").body(()).unwrap();
+/// assert!(provider.provide(&req).unwrap().is_none());
+///```
+#[derive(Debug, Clone)]
+pub struct WebSocketProvider<U, O = Unruled> {
+    upgrade: U,
+    other: O,
+}
+
+impl<U> WebSocketProvider<U> {
+    pub fn new(upgrade: U) -> Self {
+        WebSocketProvider {
+            upgrade,
+            other: Unruled,
+        }
+    }
+}
+
+impl<U, O> WebSocketProvider<U, O> {
+    /// Use `other` for requests which are not WebSocket upgrades.
+    pub fn otherwise<P>(self, other: P) -> WebSocketProvider<U, P> {
+        WebSocketProvider {
+            upgrade: self.upgrade,
+            other,
+        }
+    }
+}
+
+impl<B, U, O> ProvideRule<Request<B>> for WebSocketProvider<U, O>
+where
+    U: ProvideRule<Request<B>>,
+    O: ProvideRule<Request<B>>,
+{
+    fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+        if is_websocket_upgrade(req) {
+            self.upgrade.provide(req)
+        } else {
+            self.other.provide(req)
+        }
+    }
+
+    fn provide_many<'a>(&self, req: &'a Request<B>) -> ProvideRulesResult<'a> {
+        if is_websocket_upgrade(req) {
+            self.upgrade.provide_many(req)
+        } else {
+            self.other.provide_many(req)
+        }
+    }
+}
+
+/// Rule provider leaving every request unruled.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unruled;
+
+impl<B> ProvideRule<Request<B>> for Unruled {
+    fn provide<'a>(&self, _: &'a Request<B>) -> ProvideRuleResult<'a> {
+        Ok(None)
+    }
+}
+
+/// Request extension holding the buffered body, see [`BufferBodyLayer`].
+#[derive(Debug, Clone)]
+pub struct BufferedBody(pub Bytes);
+
+/// Layer buffering request bodies, so that rules can be derived from them.
+///
+/// For RPC-style endpoints, the identity of the caller is often found in the
+/// body (e.g. `{"account_id": ...}`) rather than in the headers. Reading the
+/// body from a rule provider would consume it though, so this layer - which
+/// goes _outside_ of the rate-limiting one - reads the body in full, stores
+/// it as a [`BufferedBody`] extension for the provider to parse, and hands
+/// the request down with the very same body.
+///
+/// At most `limit` bytes are buffered: a request with a larger body is
+/// rejected with `413 Payload Too Large`, and a body which fails to be read
+/// with `400 Bad Request`.
+///
+///```no_run
+/// use http::{Response, StatusCode};
+/// use tower_redis_cell::http::{HeaderStyle, RateLimitHeaders};
+/// use tower_redis_cell::redis_cell::{Policy, Verdict};
+/// use tower_redis_cell::{Rule, rate_limit_check};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let mut connection = client.get_multiplexed_async_connection().await.unwrap();
+///
+/// let rule = Rule::new("user123", Policy::from_tokens_per_second(10));
+/// let verdict = rate_limit_check(&mut connection, &rule).await.unwrap();
+///
+/// let mut resp = Response::new(());
+/// if let Verdict::Blocked(_) = verdict {
+///     *resp.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+/// }
+/// // `x-ratelimit-limit`, `x-ratelimit-remaining`, `x-ratelimit-reset`,
+/// // and `retry-after` if blocked
+/// resp.headers_mut().extend(verdict.ratelimit_header_map(HeaderStyle::Legacy));
+/// # }
+///```
+#[derive(Debug, Clone)]
+pub struct BufferBodyLayer {
+    limit: usize,
+}
+
+impl BufferBodyLayer {
+    pub fn new(limit: usize) -> Self {
+        BufferBodyLayer { limit }
+    }
+}
+
+impl<S> Layer<S> for BufferBodyLayer {
+    type Service = BufferBody<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        BufferBody {
+            inner,
+            limit: self.limit,
+        }
+    }
+}
+
+/// Service returned by [`BufferBodyLayer`].
+#[derive(Debug, Clone)]
+pub struct BufferBody<S> {
+    inner: S,
+    limit: usize,
+}
+
+impl<S, B, RB> Service<Request<B>> for BufferBody<S>
+where
+    S: Service<Request<B>, Response = Response<RB>> + Clone + Send + 'static,
+    S::Future: Send,
+    B: HttpBody + From<Bytes> + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    RB: Default,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Response, S::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let mut inner = self.inner.clone();
+        let limit = self.limit;
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            let body = match Limited::new(body, limit).collect().await {
+                Ok(collected) => collected.to_bytes(),
+                Err(err) => {
+                    let mut resp = Response::new(RB::default());
+                    *resp.status_mut() = if err.is::<LengthLimitError>() {
+                        StatusCode::PAYLOAD_TOO_LARGE
+                    } else {
+                        StatusCode::BAD_REQUEST
+                    };
+                    return Ok(resp);
+                }
+            };
+            parts.extensions.insert(BufferedBody(body.clone()));
+            inner.call(Request::from_parts(parts, B::from(body))).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitConfig;
+    use crate::rate_limit_check;
+    use crate::service::RateLimitLayer;
+    use crate::testing::{Fixed, LocalGcra, MockConnection};
+    use axum::body::{Body, to_bytes};
+    use std::convert::Infallible;
+    use tower::{ServiceExt as _, service_fn};
+
+    #[cfg(feature = "axum")]
+    #[tokio::test]
+    async fn rate_limit_layer_maps_the_errors_onto_responses() {
+        #[derive(Clone)]
+        struct ByApiKey;
+
+        impl<B> ProvideRule<Request<B>> for ByApiKey {
+            fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+                let key = req
+                    .headers()
+                    .get("x-api-key")
+                    .and_then(|val| val.to_str().ok())
+                    .ok_or("'x-api-key' header is missing")?;
+                Ok(Some(Rule::new(key, Policy::from_tokens_per_second(10))))
+            }
+        }
+
+        let app = axum::Router::new()
+            .route("/", axum::routing::get(|| async { "Hello, World!" }))
+            .layer(rate_limit_layer(ByApiKey, MockConnection::blocked(10, 5)));
+
+        let resp = app
+            .clone()
+            .oneshot(Request::new(Body::empty()))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+
+        let req = Request::builder()
+            .header("x-api-key", "secret")
+            .body(Body::empty())
+            .unwrap();
+        let resp = app.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers()["retry-after"], "5");
+        assert_eq!(resp.headers()["ratelimit-remaining"], "0");
+    }
+
+    #[tokio::test]
+    async fn sets_the_ietf_draft_headers() {
+        let rule = Rule::new("global", Policy::from_tokens_per_hour(1).max_burst(0));
+        let config = RateLimitConfig::new(Fixed(rule), |err: Error, _req: &Request<()>| {
+            let mut resp = Response::new(());
+            *resp.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+            if let Error::RateLimit(details) = err {
+                resp.headers_mut().extend(details.ratelimit_headers());
+            }
+            resp
+        })
+        .on_success(|details, resp: &mut Response<()>| {
+            resp.headers_mut().extend(details.ratelimit_headers());
+        });
+        let svc = RateLimitLayer::new(config, LocalGcra::new()).layer(service_fn(|_req| async {
+            Ok::<_, Infallible>(Response::new(()))
+        }));
+
+        let resp = svc.clone().oneshot(Request::new(())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers()["ratelimit"],
+            "limit=1, remaining=0, reset=3600"
+        );
+        assert_eq!(resp.headers()["ratelimit-policy"], "1;w=3600");
+
+        let resp = svc.oneshot(Request::new(())).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            resp.headers()["ratelimit"],
+            "limit=1, remaining=0, reset=3600"
+        );
+    }
+
+    #[tokio::test]
+    async fn sets_the_headers_of_a_verdict() {
+        let mut connection = MockConnection::blocked(10, 5).reset_after(6);
+        let rule = Rule::new("user123", Policy::from_tokens_per_second(10));
+        let verdict = rate_limit_check(&mut connection, &rule).await.unwrap();
+
+        let headers = verdict.ratelimit_header_map(HeaderStyle::Legacy);
+        assert_eq!(headers["x-ratelimit-limit"], "10");
+        assert_eq!(headers["x-ratelimit-remaining"], "0");
+        assert_eq!(headers["x-ratelimit-reset"], "6");
+        assert_eq!(headers["retry-after"], "5");
+    }
+
+    #[tokio::test]
+    async fn buffers_the_body_for_the_rule_provider() {
+        #[derive(Clone)]
+        struct ByAccount;
+
+        impl<B> ProvideRule<Request<B>> for ByAccount {
+            fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+                let body = req
+                    .extensions()
+                    .get::<BufferedBody>()
+                    .ok_or("body is not buffered")?;
+                let body: serde_json::Value =
+                    serde_json::from_slice(&body.0).map_err(|e| e.to_string())?;
+                let account = body["account_id"]
+                    .as_str()
+                    .ok_or("'account_id' is missing")?;
+                Ok(Some(Rule::new(
+                    account.to_string(),
+                    Policy::from_tokens_per_second(10),
+                )))
+            }
+        }
+
+        let connection = LocalGcra::new();
+        let config = RateLimitConfig::new(ByAccount, |err: Error, _req: &Request<Body>| {
+            let mut resp = Response::new(Body::from(err.to_string()));
+            *resp.status_mut() = StatusCode::BAD_REQUEST;
+            resp
+        });
+        let svc = BufferBodyLayer::new(1024).layer(
+            RateLimitLayer::new(config, connection.clone()).layer(service_fn(
+                |req: Request<Body>| async { Ok::<_, Infallible>(Response::new(req.into_body())) },
+            )),
+        );
+
+        let payload = r#"{"account_id": "acme", "method": "ping"}"#;
+        let req = Request::post("/rpc").body(Body::from(payload)).unwrap();
+        let resp = svc.clone().oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        // the service has got the full body
+        assert_eq!(to_bytes(resp.into_body(), 1024).await.unwrap(), payload);
+        assert_eq!(connection.keys(), ["acme"]);
+
+        let req = Request::post("/rpc")
+            .body(Body::from(vec![b' '; 2048]))
+            .unwrap();
+        let resp = svc.oneshot(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(connection.calls(), 1);
+    }
+
+    #[test]
+    fn retry_after_duration_matches_the_header() {
+        let reply = redis::Value::Array([1, 10, 0, 7, 60].map(redis::Value::Int).to_vec());
+        let Verdict::Blocked(details) = redis::from_redis_value(&reply).unwrap() else {
+            unreachable!("the reply is that of a blocked request");
+        };
+        let (_, header) = details.retry_after_header();
+        // bounds do not apply to the raw duration
+        let rule = Rule::new("user123", Policy::from_tokens_per_minute(10)).max_retry_after(3);
+        let details = RequestBlockedDetails { details, rule };
+        let secs = details.retry_after_duration().as_secs();
+        assert_eq!(header.to_str().unwrap(), secs.to_string());
+        assert_eq!(details.retry_after(), 3);
+    }
+}
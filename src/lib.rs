@@ -50,7 +50,7 @@
 //! [RateLimitLayer]. Note that we are using [`ConnectionManager`](redis::aio::ConnectionManager)
 //! in this example, but dy default anything [`ConnectionLike`](https://docs.rs/redis/latest/redis/aio/trait.ConnectionLike.html)
 //! will do. There is also an option to use a pool, but you will need to enable
-//! a corresponding feature for that (currently, `deadpool` is supported).
+//! a corresponding feature for that (currently, `deadpool`, `bb8` and `mobc` are supported).
 //!
 //!```no_run
 //! # use axum::http::Request;
@@ -96,7 +96,7 @@
 //!                 );
 //!                 (
 //!                     StatusCode::TOO_MANY_REQUESTS,
-//!                     AppendHeaders([(header::RETRY_AFTER, err.details.retry_after)]),
+//!                     AppendHeaders([(header::RETRY_AFTER, err.retry_after())]),
 //!                     Body::from("too many requests"),
 //!                 )
 //!                     .into_response()
@@ -121,25 +121,196 @@
 //! a free standing function. Also, you can optionally provide [`RateLimitConfig::on_success`]
 //! and [`RateLimitConfig::on_unruled`] handlers, which both provide a mutable access
 //! to the response, and so - if needed - you can set any additional headers.
+//!
+//! Note that subscriptions, e.g. to keyspace notifications, need a connection
+//! of their own: on the connection you hand over to the layer, a RESP3 push
+//! message delivered where the `CL.THROTTLE` reply is expected is reported as
+//! [`Error::Redis`].
+//!
+//! If the defaults are good enough for you, enable the `http` feature and use
+//! `RateLimitConfig::with_default_http_handler` instead of writing an error
+//! handler by hand. With `axum`, `http::rate_limit_layer` goes one step
+//! further and builds the whole layer, rate limit headers included.
+//!
+//! ## Features
+//!
+//! Everything beyond the core service is opt-in, and every feature is meant to
+//! compose with any other (this is checked in CI with `cargo hack`). Each of the
+//! snippets below only compiles with the corresponding feature enabled:
+//!
+//! - `tokio-comp` (default): run `redis` on the Tokio runtime.
+//! - `deadpool`: rate limit with a pooled connection via the `deadpool` module.
+//!
+#![cfg_attr(feature = "deadpool", doc = "```")]
+#![cfg_attr(not(feature = "deadpool"), doc = "```compile_fail")]
+//! use tower_redis_cell::deadpool::RateLimitLayer;
+//! ```
+//!
+//! - `bb8`: same as `deadpool`, but with a `bb8` pool via the `bb8` module.
+//!
+#![cfg_attr(feature = "bb8", doc = "```")]
+#![cfg_attr(not(feature = "bb8"), doc = "```compile_fail")]
+//! use tower_redis_cell::bb8::RateLimitLayer;
+//! ```
+//!
+//! - `mobc`: same as `deadpool`, but with a `mobc` pool via the `mobc` module.
+//!
+#![cfg_attr(feature = "mobc", doc = "```")]
+#![cfg_attr(not(feature = "mobc"), doc = "```compile_fail")]
+//! use tower_redis_cell::mobc::{RateLimitLayer, RedisConnectionManager};
+//! ```
+//!
+//! - `uuid`: use `uuid::Uuid` values as keys.
+//!
+#![cfg_attr(feature = "uuid", doc = "```")]
+#![cfg_attr(not(feature = "uuid"), doc = "```compile_fail")]
+//! fn is_uuid(key: &tower_redis_cell::redis_cell::Key) -> bool {
+//!     matches!(key, tower_redis_cell::redis_cell::Key::Uuid(_))
+//! }
+//! ```
+//!
+//! - `http`: default error handlers, rate limit headers, and `http`-aware rule providers.
+//!
+#![cfg_attr(feature = "http", doc = "```")]
+#![cfg_attr(not(feature = "http"), doc = "```compile_fail")]
+//! let style = tower_redis_cell::http::HeaderStyle::default();
+//! ```
+//!
+//! - `axum`: everything from `http` plus a ready-made layer for `axum`.
+//!
+#![cfg_attr(feature = "axum", doc = "```")]
+#![cfg_attr(not(feature = "axum"), doc = "```compile_fail")]
+//! use tower_redis_cell::http::rate_limit_layer;
+//! ```
+//!
+//! - `governor-compat`: migrate from `tower_governor` quotas and key extractors.
+//!
+#![cfg_attr(feature = "governor-compat", doc = "```")]
+#![cfg_attr(not(feature = "governor-compat"), doc = "```compile_fail")]
+//! use tower_redis_cell::governor::policy_from_quota;
+//! ```
+//!
+//! - `filter`: a `tower::filter` predicate as an alternative to the layer.
+//!
+#![cfg_attr(feature = "filter", doc = "```")]
+#![cfg_attr(not(feature = "filter"), doc = "```compile_fail")]
+//! use tower_redis_cell::filter::RateLimitPredicate;
+//! ```
+//!
+//! - `test-util`: an in-process GCRA to test rules without a Redis Cell instance.
+//!
+#![cfg_attr(feature = "test-util", doc = "```")]
+#![cfg_attr(not(feature = "test-util"), doc = "```compile_fail")]
+//! let gcra = tower_redis_cell::testing::LocalGcra::new();
+//! ```
+//!
+//! - `tracing`: record verdicts on a tracing span.
+//!
+#![cfg_attr(feature = "tracing", doc = "```")]
+#![cfg_attr(not(feature = "tracing"), doc = "```compile_fail")]
+//! let mode = tower_redis_cell::TracingMode::default();
+//! ```
+//!
+//! - `metrics`: count the verdicts and time the round trips to Redis, see the
+//!   `metrics` module.
+//!
+#![cfg_attr(feature = "metrics", doc = "```")]
+#![cfg_attr(not(feature = "metrics"), doc = "```compile_fail")]
+//! use tower_redis_cell::metrics::{ALLOWED_TOTAL, BLOCKED_TOTAL};
+//! ```
+//!
+//! - `sha2`: hash keys with SHA-256 before sending them to Redis.
+//!
+#![cfg_attr(feature = "sha2", doc = "```")]
+#![cfg_attr(not(feature = "sha2"), doc = "```compile_fail")]
+//! let transform = tower_redis_cell::KeyTransform::Sha256Hex;
+//! ```
+//!
+//! - `local-cache`: remember blocked keys in process to spare the round trips
+//!   while they stay blocked.
+//!
+#![cfg_attr(feature = "local-cache", doc = "```")]
+#![cfg_attr(not(feature = "local-cache"), doc = "```compile_fail")]
+//! # use tower_redis_cell::RateLimitConfig;
+//! fn cached<PR>(config: RateLimitConfig<PR, (), (), ()>) -> RateLimitConfig<PR, (), (), ()> {
+//!     config.blocked_cache(10_000)
+//! }
+//! ```
 
 // #![deny(missing_docs)]
 #![cfg_attr(docsrs, feature(doc_cfg))]
 
 mod config;
+mod connection;
+mod decision;
 mod error;
+mod history;
+mod limiter;
+mod load;
+mod local;
+mod macros;
+mod policy;
+mod provider;
 mod rule;
 mod service;
+mod verdict;
+
+pub mod admin;
 
-pub use config::RateLimitConfig;
+#[cfg(feature = "http")]
+#[cfg_attr(docsrs, doc(cfg(feature = "http")))]
+pub mod http;
+
+#[cfg(feature = "filter")]
+#[cfg_attr(docsrs, doc(cfg(feature = "filter")))]
+pub mod filter;
+
+#[cfg(any(test, feature = "test-util"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+pub mod testing;
+
+#[cfg(feature = "governor-compat")]
+#[cfg_attr(docsrs, doc(cfg(feature = "governor-compat")))]
+pub mod governor;
+
+#[cfg(feature = "metrics")]
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+pub mod metrics;
+
+#[cfg(feature = "tracing")]
+pub use config::TracingMode;
+pub use config::{
+    FailMode, KeyLogPolicy, KeyTransform, Mode, REDIS_PERMIT_WAIT, RETRY_DELAY, RateLimitConfig,
+    RateLimitConfigBuilder,
+};
+pub use connection::{LazyConnection, MutexConnection, ReplicaConnection};
+pub use decision::{DecisionEvent, DecisionStream};
 pub use error::{Error, ProvideRuleError};
+pub use limiter::{CellThrottle, Limiter};
+pub use load::LoadSignal;
+pub use policy::{DisplayPolicy, PolicyError, PolicyExt, PolicyWarning};
+pub use provider::{CachedBy, Or, ProvideRuleExt, Scheduled};
 pub use rule::{
-    ProvideRule, ProvideRuleResult, RequestAllowedDetails, RequestBlockedDetails, Rule,
+    AsyncProvideRule, KeyExt, ProvideRule, ProvideRuleResult, ProvideRulesResult,
+    RequestAllowedDetails, RequestBlockedDetails, Rule,
+};
+pub use service::{
+    RateLimit, RateLimitLayer, ResponseFuture, rate_limit, rate_limit_check, rate_limit_check_many,
 };
-pub use service::{RateLimit, RateLimitLayer};
 
 #[cfg(feature = "deadpool")]
 pub mod deadpool {
     pub use crate::service::deadpool::{RateLimit, RateLimitLayer};
 }
 
+#[cfg(feature = "bb8")]
+pub mod bb8 {
+    pub use crate::service::bb8::{RateLimit, RateLimitLayer};
+}
+
+#[cfg(feature = "mobc")]
+pub mod mobc {
+    pub use crate::service::mobc::{RateLimit, RateLimitLayer, RedisConnectionManager};
+}
+
 pub use redis_cell_rs as redis_cell;
@@ -0,0 +1,139 @@
+use crate::verdict;
+use redis::{Cmd as RedisCmd, RedisResult, Value};
+use redis_cell_rs::{Cmd, Key, Policy, Verdict};
+
+/// Backend-side rate-limiting algorithm.
+///
+/// A limiter knows how to build the command which checks a key against
+/// a policy, and how to turn the reply to that command into a [`Verdict`].
+/// Everything else (rule providers, handlers, connection management) is
+/// shared between limiters, so you can plug in an alternative implementation,
+/// e.g. a sliding window implemented as a Lua function, with
+/// [`RateLimitConfig::limiter`](crate::RateLimitConfig::limiter).
+///
+///```
+/// use redis::{Cmd, FromRedisValue, RedisResult, Value};
+/// use tower_redis_cell::Limiter;
+/// use tower_redis_cell::redis_cell::{Key, Policy, Verdict};
+///
+/// struct SlidingWindow;
+///
+/// impl Limiter for SlidingWindow {
+///     fn command(&self, key: &Key<'_>, policy: &Policy) -> Cmd {
+///         let mut cmd = redis::cmd("FCALL");
+///         cmd.arg("sliding_window")
+///             .arg(1)
+///             .arg(key)
+///             .arg(policy.tokens)
+///             .arg(policy.period.as_secs())
+///             .arg(policy.apply);
+///         cmd
+///     }
+///
+///     fn verdict(&self, reply: &Value) -> RedisResult<Verdict> {
+///         // the function replies in the same shape as CL.THROTTLE
+///         Verdict::from_redis_value(reply)
+///     }
+/// }
+///```
+pub trait Limiter {
+    /// Build the command checking `key` against `policy`.
+    fn command(&self, key: &Key<'_>, policy: &Policy) -> RedisCmd;
+
+    /// Decode the reply to the command built with [`Limiter::command`].
+    fn verdict(&self, reply: &Value) -> RedisResult<Verdict>;
+}
+
+/// The default limiter issuing Redis Cell's `CL.THROTTLE`.
+#[derive(Debug, Clone, Default)]
+pub struct CellThrottle {
+    strict_decode: bool,
+    command_name: Option<&'static str>,
+}
+
+impl CellThrottle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reject replies which deviate from the documented shape (five integers)
+    /// in any way.
+    ///
+    /// By default, trailing elements are ignored and integers sent as strings
+    /// are accepted. Turning strict decoding on in staging helps catch module
+    /// upgrades that change the reply before they break silently.
+    pub fn strict_decode(mut self, strict: bool) -> Self {
+        self.strict_decode = strict;
+        self
+    }
+
+    /// Issue the command under another name than `CL.THROTTLE`, e.g. when it
+    /// has been renamed with `rename-command` or a fork of the module is used.
+    ///
+    /// The arguments and the reply are expected to be the same as those of
+    /// `CL.THROTTLE`.
+    ///
+    ///```
+    /// use redis::Arg;
+    /// use tower_redis_cell::redis_cell::{Key, Policy};
+    /// use tower_redis_cell::{CellThrottle, Limiter};
+    ///
+    /// let limiter = CellThrottle::new().command_name("THROTTLE");
+    /// let cmd = limiter.command(&Key::from("user123"), &Policy::from_tokens_per_second(1));
+    /// let args: Vec<_> = cmd.args_iter().collect();
+    /// assert_eq!(args[..2], [Arg::Simple(&b"THROTTLE"[..]), Arg::Simple(&b"user123"[..])]);
+    ///```
+    pub fn command_name(mut self, name: &'static str) -> Self {
+        self.command_name = Some(name);
+        self
+    }
+
+    /// The raw "limited" flag, i.e. the first element of a `CL.THROTTLE`
+    /// reply, decoded as leniently (or strictly) as [`Limiter::verdict`] does.
+    ///
+    /// The flag alone decides between [`Verdict::Allowed`] and
+    /// [`Verdict::Blocked`], whatever the remaining tokens: a request taking
+    /// the very last token is allowed with none remaining, while the next one
+    /// is blocked, also with none remaining.
+    ///
+    ///```
+    /// use redis::Value;
+    /// use tower_redis_cell::redis_cell::Verdict;
+    /// use tower_redis_cell::{CellThrottle, Limiter};
+    ///
+    /// let limiter = CellThrottle::new();
+    /// let reply = |values: [i64; 5]| Value::Array(values.map(Value::Int).to_vec());
+    ///
+    /// let last_token = reply([0, 10, 0, -1, 60]);
+    /// assert!(!limiter.limited(&last_token).unwrap());
+    /// assert!(matches!(limiter.verdict(&last_token).unwrap(), Verdict::Allowed(d) if d.remaining == 0));
+    ///
+    /// let over_limit = reply([1, 10, 0, 6, 60]);
+    /// assert!(limiter.limited(&over_limit).unwrap());
+    /// assert!(matches!(limiter.verdict(&over_limit).unwrap(), Verdict::Blocked(d) if d.remaining == 0));
+    ///
+    /// assert!(limiter.limited(&reply([2, 10, 0, 6, 60])).is_err());
+    ///```
+    pub fn limited(&self, reply: &Value) -> RedisResult<bool> {
+        verdict::limited(reply, self.strict_decode)
+    }
+}
+
+impl Limiter for CellThrottle {
+    fn command(&self, key: &Key<'_>, policy: &Policy) -> RedisCmd {
+        let Some(name) = self.command_name else {
+            return Cmd::new(key, policy).into();
+        };
+        let mut cmd = redis::cmd(name);
+        cmd.arg(key)
+            .arg(policy.burst)
+            .arg(policy.tokens)
+            .arg(policy.period.as_secs())
+            .arg(policy.apply);
+        cmd
+    }
+
+    fn verdict(&self, reply: &Value) -> RedisResult<Verdict> {
+        verdict::decode(reply, self.strict_decode)
+    }
+}
@@ -0,0 +1,25 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Shared measure of the current system load, e.g. CPU usage in percent or
+/// queue depth, see [`RateLimitConfig::policy_adjuster`](crate::RateLimitConfig::policy_adjuster).
+///
+/// The signal is updated by the application (say, from a background task
+/// sampling the metric) and read on every request, so it is just an atomic
+/// behind an [`Arc`]. Clones share the value.
+#[derive(Debug, Clone, Default)]
+pub struct LoadSignal(Arc<AtomicU64>);
+
+impl LoadSignal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&self, load: u64) {
+        self.0.store(load, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
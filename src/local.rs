@@ -0,0 +1,136 @@
+use redis_cell_rs::AllowedDetails;
+#[cfg(feature = "local-cache")]
+use redis_cell_rs::BlockedDetails;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Entry {
+    details: AllowedDetails,
+    synced_at: Instant,
+    pending: usize,
+}
+
+/// Per-key estimate of the bucket state, see [`RateLimitConfig::local_counter`](crate::RateLimitConfig::local_counter).
+pub(crate) struct LocalCounter {
+    every: usize,
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+impl LocalCounter {
+    pub(crate) fn new(every: usize) -> Self {
+        LocalCounter {
+            every,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Allow the request without consulting Redis, if the estimate permits.
+    ///
+    /// The request is only allowed locally if fewer than `every - 1` requests
+    /// have been allowed so far since the last sync, and if the tokens owed
+    /// by all of them, as well as by the request which is going to charge
+    /// them, still fit into what Redis reported as remaining.
+    pub(crate) fn try_allow(&self, key: &str, apply: usize) -> Option<AllowedDetails> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.get_mut(key)?;
+        let owed = apply.saturating_mul(entry.pending + 1);
+        if entry.pending + 1 >= self.every || owed.saturating_add(apply) > entry.details.remaining {
+            return None;
+        }
+        entry.pending += 1;
+        let mut details = entry.details.clone();
+        details.remaining -= owed;
+        Some(details)
+    }
+
+    /// Forget the estimate, returning the number of requests allowed locally
+    /// since the last sync, which are yet to be charged in Redis.
+    ///
+    /// Until the next sync, requests for this key go to Redis.
+    pub(crate) fn take(&self, key: &str) -> usize {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(key).map_or(0, |entry| entry.pending)
+    }
+
+    /// Start over with the state Redis has just reported.
+    pub(crate) fn sync(&self, key: String, details: AllowedDetails) {
+        let mut entries = self.entries.lock().unwrap();
+        // an estimate is of no use once the bucket has been fully replenished,
+        // so purge those before the map would otherwise grow
+        if entries.len() == entries.capacity() {
+            entries.retain(|_, entry| {
+                entry.synced_at.elapsed() < Duration::from_secs(entry.details.reset_after)
+            });
+        }
+        let entry = Entry {
+            details,
+            synced_at: Instant::now(),
+            pending: 0,
+        };
+        entries.insert(key, entry);
+    }
+}
+
+/// Keys known to be blocked, see [`RateLimitConfig::blocked_cache`](crate::RateLimitConfig::blocked_cache).
+#[cfg(feature = "local-cache")]
+pub(crate) struct BlockedCache {
+    capacity: usize,
+    entries: Mutex<HashMap<String, (Instant, BlockedDetails)>>,
+}
+
+#[cfg(feature = "local-cache")]
+impl BlockedCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        BlockedCache {
+            capacity,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The block still in force for the key, if any, with the retry-after
+    /// and reset-after counted down since Redis reported them.
+    pub(crate) fn get(&self, key: &str) -> Option<BlockedDetails> {
+        let mut entries = self.entries.lock().unwrap();
+        let (blocked_at, details) = entries.get(key)?;
+        let elapsed = blocked_at.elapsed();
+        let Some(left) = Duration::from_secs(details.retry_after).checked_sub(elapsed) else {
+            entries.remove(key);
+            return None;
+        };
+        if left.is_zero() {
+            entries.remove(key);
+            return None;
+        }
+        let mut details = details.clone();
+        details.retry_after = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+        details.reset_after = details.reset_after.saturating_sub(elapsed.as_secs());
+        Some(details)
+    }
+
+    /// Remember the block Redis has just reported, evicting the expired ones
+    /// and then the one lifted the soonest should the cache be full.
+    pub(crate) fn block(&self, key: String, details: BlockedDetails) {
+        if details.retry_after == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        let lifted_at = |(blocked_at, details): &(Instant, BlockedDetails)| {
+            *blocked_at + Duration::from_secs(details.retry_after)
+        };
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let now = Instant::now();
+            entries.retain(|_, entry| lifted_at(entry) > now);
+        }
+        if entries.len() >= self.capacity && !entries.contains_key(&key) {
+            let soonest = entries
+                .iter()
+                .min_by_key(|(_, entry)| lifted_at(entry))
+                .map(|(key, _)| key.clone());
+            if let Some(soonest) = soonest {
+                entries.remove(&soonest);
+            }
+        }
+        entries.insert(key, (Instant::now(), details));
+    }
+}
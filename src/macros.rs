@@ -0,0 +1,63 @@
+/// Build a [`Policy`](crate::redis_cell::Policy) from a rate written out in
+/// a human-friendly way.
+///
+/// The rate is given as `tokens / count unit`, where the unit is one of
+/// `s` (`sec`, `second`, `seconds`), `min` (`minute`, `minutes`), `h`
+/// (`hour`, `hours`), `d` (`day`, `days`), `w` (`week`, `weeks`), and
+/// `month` (`months`), optionally followed by the maximum burst. A month is
+/// taken to be 30 days, since Redis Cell only knows periods in whole seconds
+/// and so has no notion of calendar months: a monthly quota is a rolling one.
+/// The macro expands to calls to the `const` constructors, and so can be used
+/// to define constants.
+///
+///```
+/// use std::time::Duration;
+/// use tower_redis_cell::rate;
+/// use tower_redis_cell::redis_cell::Policy;
+///
+/// const API: Policy = rate!(100 / 1 min, burst = 20);
+/// assert_eq!(API.tokens, 100);
+/// assert_eq!(API.period, Duration::from_secs(60));
+/// assert_eq!(API.burst, 20);
+///
+/// let policy = rate!(5 / 1 s);
+/// assert_eq!((policy.tokens, policy.burst), (5, 0));
+/// assert_eq!(policy.period, Duration::from_secs(1));
+/// assert_eq!(rate!(5 / 30 seconds).period, Duration::from_secs(30));
+/// assert_eq!(rate!(1_000 / 2 hours).period, Duration::from_secs(7_200));
+/// assert_eq!(rate!(10 / 1 day).period, Duration::from_secs(86_400));
+/// assert_eq!(rate!(50 / 1 week).period, Duration::from_secs(7 * 86_400));
+///
+/// const MONTHLY_QUOTA: Policy = rate!(10_000 / 1 month, burst = 10_000);
+/// assert_eq!(MONTHLY_QUOTA.period, Duration::from_secs(30 * 86_400));
+///```
+#[macro_export]
+macro_rules! rate {
+    ($tokens:tt / $count:tt $unit:ident $(,)?) => {
+        $crate::redis_cell::Policy::from_tokens_per_period(
+            $tokens,
+            ::std::time::Duration::from_secs($count * $crate::rate!(@secs $unit)),
+        )
+    };
+    ($tokens:tt / $count:tt $unit:ident, burst = $burst:expr $(,)?) => {
+        $crate::rate!($tokens / $count $unit).max_burst($burst)
+    };
+    (@secs s) => { 1 };
+    (@secs sec) => { 1 };
+    (@secs second) => { 1 };
+    (@secs seconds) => { 1 };
+    (@secs min) => { 60 };
+    (@secs minute) => { 60 };
+    (@secs minutes) => { 60 };
+    (@secs h) => { 3_600 };
+    (@secs hour) => { 3_600 };
+    (@secs hours) => { 3_600 };
+    (@secs d) => { 86_400 };
+    (@secs day) => { 86_400 };
+    (@secs days) => { 86_400 };
+    (@secs w) => { 604_800 };
+    (@secs week) => { 604_800 };
+    (@secs weeks) => { 604_800 };
+    (@secs month) => { 2_592_000 };
+    (@secs months) => { 2_592_000 };
+}
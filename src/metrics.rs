@@ -0,0 +1,168 @@
+//! Metrics emitted by the services via the [`metrics`](https://docs.rs/metrics)
+//! facade, to be exported with any compatible recorder, e.g. Prometheus.
+//!
+//! The verdict counters are labeled with the policy's name and the rule's
+//! resource, both falling back to `"unnamed"` when not set, as well as with the
+//! rule's own [labels](crate::Rule::labels). Policy names, resources, and rule
+//! labels are meant to be few, and so are safe as labels, unlike keys.
+//!
+//!```
+//! use metrics_util::debugging::DebuggingRecorder;
+//! use tower_redis_cell::metrics::ALLOWED_TOTAL;
+//!
+//! let recorder = DebuggingRecorder::new();
+//! let snapshotter = recorder.snapshotter();
+//! recorder.install().unwrap();
+//!
+//! // ...serve some requests...
+//!
+//! let metrics = snapshotter.snapshot().into_vec();
+//! let allowed = metrics.iter().filter(|(key, ..)| key.key().name() == ALLOWED_TOTAL);
+//!```
+
+use crate::rule::Rule;
+use ::metrics::Label;
+use redis_cell_rs::Verdict;
+use std::time::Duration;
+
+/// Counter of the requests allowed, per policy, resource, and rule labels.
+pub const ALLOWED_TOTAL: &str = "ratelimit_allowed_total";
+
+/// Counter of the requests blocked (also in shadow mode), per policy,
+/// resource, and rule labels.
+pub const BLOCKED_TOTAL: &str = "ratelimit_blocked_total";
+
+/// Counter of the requests let through in [shadow mode](crate::Mode::Shadow)
+/// which would have been blocked otherwise, per policy, resource, and rule
+/// labels. These are counted in [`BLOCKED_TOTAL`] as well.
+pub const WOULD_BLOCK_TOTAL: &str = "ratelimit_would_block_total";
+
+/// Histogram of the round trips to Redis, in seconds.
+pub const REDIS_DURATION_SECONDS: &str = "ratelimit_redis_duration_seconds";
+
+const UNNAMED: &str = "unnamed";
+
+fn labels(rule: &Rule<'_>) -> Vec<Label> {
+    let mut labels = vec![
+        Label::new("policy", rule.policy.name.unwrap_or(UNNAMED)),
+        Label::new("resource", rule.resource.unwrap_or(UNNAMED)),
+    ];
+    labels.extend(
+        rule.labels
+            .iter()
+            .map(|&(name, value)| Label::new(name, value)),
+    );
+    labels
+}
+
+pub(crate) fn record_verdict(rule: &Rule<'_>, verdict: &Verdict) {
+    let name = match verdict {
+        Verdict::Allowed(_) => ALLOWED_TOTAL,
+        Verdict::Blocked(_) => BLOCKED_TOTAL,
+    };
+    ::metrics::counter!(name, labels(rule)).increment(1);
+}
+
+pub(crate) fn record_would_block(rule: &Rule<'_>) {
+    ::metrics::counter!(WOULD_BLOCK_TOTAL, labels(rule)).increment(1);
+}
+
+pub(crate) fn record_duration(duration: Duration) {
+    ::metrics::histogram!(REDIS_DURATION_SECONDS).record(duration);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitConfig;
+    use crate::error::Error;
+    use crate::service::RateLimitLayer;
+    use crate::testing::{Fixed, MockConnection};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use redis_cell_rs::Policy;
+    use std::convert::Infallible;
+    use tower::{Layer as _, ServiceExt as _, service_fn};
+
+    #[test]
+    fn labels_the_verdicts_with_the_rule_labels() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let rule = Rule::new("user123", Policy::from_tokens_per_second(10).name("basic"))
+            .resource("search")
+            .labels(&[("region", "eu-west-1"), ("plan", "premium")]);
+        let reply = redis::Value::Array([0, 10, 9, -1, 1].map(redis::Value::Int).to_vec());
+        let verdict: Verdict = redis::from_redis_value(&reply).unwrap();
+        ::metrics::with_local_recorder(&recorder, || record_verdict(&rule, &verdict));
+
+        let metrics = snapshotter.snapshot().into_vec();
+        let (key, _, _, value) = &metrics[0];
+        assert_eq!(key.key().name(), ALLOWED_TOTAL);
+        let labels: Vec<_> = key.key().labels().map(|l| (l.key(), l.value())).collect();
+        assert_eq!(
+            labels,
+            [
+                ("policy", "basic"),
+                ("resource", "search"),
+                ("region", "eu-west-1"),
+                ("plan", "premium"),
+            ]
+        );
+        assert_eq!(*value, DebugValue::Counter(1));
+    }
+
+    #[tokio::test]
+    async fn records_the_verdicts_of_the_service() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let _guard = ::metrics::set_default_local_recorder(&recorder);
+
+        let policy = Policy::from_tokens_per_second(10).name("basic");
+        let rule = Rule::new("user123", policy).resource("search");
+        let config = RateLimitConfig::new(Fixed(rule), |_err: Error, _req: &String| String::new());
+        let svc = RateLimitLayer::new(config, MockConnection::allowed(10, 9))
+            .layer(service_fn(|req: String| async { Ok::<_, Infallible>(req) }));
+        svc.oneshot("user123".to_string()).await.unwrap();
+
+        let metrics = snapshotter.snapshot().into_vec();
+        let (key, _, _, value) = metrics
+            .iter()
+            .find(|(key, ..)| key.key().name() == ALLOWED_TOTAL)
+            .unwrap();
+        let labels: Vec<_> = key.key().labels().map(|l| (l.key(), l.value())).collect();
+        assert_eq!(labels, [("policy", "basic"), ("resource", "search")]);
+        assert_eq!(*value, DebugValue::Counter(1));
+        assert!(
+            metrics
+                .iter()
+                .all(|(key, ..)| key.key().name() != BLOCKED_TOTAL)
+        );
+        assert!(
+            metrics
+                .iter()
+                .any(|(key, ..)| key.key().name() == REDIS_DURATION_SECONDS)
+        );
+    }
+
+    #[tokio::test]
+    async fn counts_the_requests_which_would_block() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        let _guard = ::metrics::set_default_local_recorder(&recorder);
+
+        let rule = Rule::new("user123", Policy::from_tokens_per_second(10).name("basic"));
+        let config = RateLimitConfig::new(Fixed(rule), |_err: Error, _req: &String| String::new())
+            .mode(crate::Mode::Shadow);
+        let svc = RateLimitLayer::new(config, MockConnection::blocked(10, 1))
+            .layer(service_fn(|req: String| async { Ok::<_, Infallible>(req) }));
+        assert_eq!(svc.oneshot("user123".to_string()).await.unwrap(), "user123");
+
+        let metrics = snapshotter.snapshot().into_vec();
+        let (key, _, _, value) = metrics
+            .iter()
+            .find(|(key, ..)| key.key().name() == WOULD_BLOCK_TOTAL)
+            .unwrap();
+        let labels: Vec<_> = key.key().labels().map(|l| (l.key(), l.value())).collect();
+        assert_eq!(labels, [("policy", "basic"), ("resource", "unnamed")]);
+        assert_eq!(*value, DebugValue::Counter(1));
+    }
+}
@@ -0,0 +1,323 @@
+use redis_cell_rs::Policy;
+use std::fmt::Display;
+use std::time::Duration;
+
+/// Issues spotted by [`PolicyExt::sanity_check`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PolicyWarning {
+    SubSecondPeriod,
+    AlwaysBlocks,
+    NeverLimits,
+}
+
+impl Display for PolicyWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PolicyWarning::SubSecondPeriod => {
+                "policy period is not a whole number of seconds and will be truncated"
+            }
+            PolicyWarning::AlwaysBlocks => {
+                "policy applies more tokens than the bucket can ever hold, so it always blocks"
+            }
+            PolicyWarning::NeverLimits => {
+                "policy emits tokens faster than once a nanosecond, so it never limits"
+            }
+        })
+    }
+}
+
+impl std::error::Error for PolicyWarning {}
+
+/// Policy values Redis Cell would reject, see [`PolicyExt::validate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PolicyError {
+    /// The value does not fit into the signed 64-bit integer argument
+    /// of the throttle command.
+    OutOfRange { field: &'static str, value: u64 },
+    /// The period is shorter than a second, and so would be sent as zero.
+    ZeroPeriod,
+    /// The policy emits no tokens at all.
+    ZeroTokens,
+}
+
+impl Display for PolicyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyError::OutOfRange { field, value } => write!(
+                f,
+                "policy {} of {} exceeds the maximum of {} accepted by Redis Cell",
+                field,
+                value,
+                i64::MAX
+            ),
+            PolicyError::ZeroPeriod => {
+                f.write_str("policy period is shorter than the one second Redis Cell can express")
+            }
+            PolicyError::ZeroTokens => f.write_str("policy emits no tokens"),
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+/// Extra functionality for [`Policy`].
+pub trait PolicyExt {
+    /// Check the policy for values that make it misbehave.
+    ///
+    /// The policies known up front, i.e. those registered with
+    /// [`RateLimitConfig::policies`](crate::RateLimitConfig::policies), are
+    /// checked when the layer is built, and a warning is logged for each one
+    /// that misbehaves if the `tracing` feature is on. Others are best checked
+    /// at startup or in tests, e.g.:
+    ///```
+    /// use std::time::Duration;
+    /// use tower_redis_cell::redis_cell::Policy;
+    /// use tower_redis_cell::{PolicyExt, PolicyWarning};
+    ///
+    /// let policy = Policy::from_tokens_per_minute(100).max_burst(10);
+    /// assert_eq!(policy.sanity_check(), Ok(()));
+    ///
+    /// let policy = Policy::from_tokens_per_period(1, Duration::from_millis(1500));
+    /// assert_eq!(policy.sanity_check(), Err(PolicyWarning::SubSecondPeriod));
+    ///
+    /// let policy = Policy::from_tokens_per_second(10).max_burst(1).apply_tokens(3);
+    /// assert_eq!(policy.sanity_check(), Err(PolicyWarning::AlwaysBlocks));
+    ///
+    /// let policy = Policy::from_tokens_per_second(usize::MAX);
+    /// assert_eq!(policy.sanity_check(), Err(PolicyWarning::NeverLimits));
+    ///```
+    fn sanity_check(&self) -> Result<(), PolicyWarning>;
+
+    /// Check that the policy can be sent to Redis Cell at all.
+    ///
+    /// Unlike [`PolicyExt::sanity_check`], which spots policies that work
+    /// but misbehave, this rejects values the module would refuse with an
+    /// opaque error. The service runs this check before issuing the command
+    /// and reports failures as [`Error::Policy`](crate::Error::Policy).
+    ///
+    ///```
+    /// use std::time::Duration;
+    /// use tower_redis_cell::PolicyExt;
+    /// use tower_redis_cell::redis_cell::Policy;
+    ///
+    /// let policy = Policy::from_tokens_per_day(1000);
+    /// assert!(policy.validate().is_ok());
+    ///
+    /// let policy = Policy::from_tokens_per_period(1, Duration::from_secs(u64::MAX));
+    /// let err = policy.validate().unwrap_err();
+    /// assert_eq!(
+    ///     err.to_string(),
+    ///     "policy period of 18446744073709551615 exceeds the maximum of 9223372036854775807 accepted by Redis Cell",
+    /// );
+    ///```
+    fn validate(&self) -> Result<(), PolicyError>;
+
+    /// Build a policy like [`Policy::new`] does, but reject one which cannot
+    /// be sent to Redis Cell, see [`PolicyExt::validate`].
+    ///
+    /// The module takes the period in whole seconds, and so a sub-second period
+    /// (which [`Policy::new`] accepts) would go out as zero. Note that periods
+    /// above a second are still truncated, see [`PolicyExt::sanity_check`].
+    ///
+    ///```
+    /// use std::time::Duration;
+    /// use tower_redis_cell::redis_cell::Policy;
+    /// use tower_redis_cell::{PolicyError, PolicyExt};
+    ///
+    /// let policy = Policy::try_new(10, 100, Duration::from_secs(60), 1).unwrap();
+    /// assert_eq!((policy.burst, policy.tokens, policy.period.as_secs()), (10, 100, 60));
+    ///
+    /// let err = Policy::try_new(10, 100, Duration::from_millis(500), 1).unwrap_err();
+    /// assert_eq!(err, PolicyError::ZeroPeriod);
+    ///
+    /// let err = Policy::try_new(10, 0, Duration::from_secs(60), 1).unwrap_err();
+    /// assert_eq!(err, PolicyError::ZeroTokens);
+    ///```
+    fn try_new(
+        burst: usize,
+        tokens: usize,
+        period: Duration,
+        apply: usize,
+    ) -> Result<Policy, PolicyError>
+    where
+        Self: Sized;
+
+    /// Describe the policy's rate in words, e.g. for a friendly `429` message.
+    ///
+    ///```
+    /// use std::time::Duration;
+    /// use tower_redis_cell::PolicyExt;
+    /// use tower_redis_cell::redis_cell::Policy;
+    ///
+    /// let policy = Policy::from_tokens_per_hour(5);
+    /// assert_eq!(policy.describe(), "5 requests per hour");
+    ///
+    /// let policy = Policy::from_tokens_per_second(1).max_burst(9);
+    /// assert_eq!(policy.describe(), "1 request per second with bursts of up to 10 requests");
+    ///
+    /// let policy = Policy::from_tokens_per_period(100, Duration::from_secs(120));
+    /// assert_eq!(policy.describe(), "100 requests per 2 minutes");
+    ///
+    /// let policy = Policy::from_tokens_per_period(30, Duration::from_secs(90));
+    /// assert_eq!(policy.describe(), "30 requests per 90 seconds");
+    ///```
+    fn describe(&self) -> String;
+
+    /// Combine two policies into the stricter one.
+    ///
+    /// Rates are compared as tokens per period, with both periods normalized
+    /// to nanoseconds, and the policy with the lower rate is picked (in case
+    /// of a tie, the one with the smaller burst). Bursts are not comparable
+    /// to rates, so the result takes the smaller of the two bursts, which
+    /// approximates - but is not exactly equivalent to - checking both
+    /// policies one after another.
+    ///
+    ///```
+    /// use tower_redis_cell::PolicyExt;
+    /// use tower_redis_cell::redis_cell::Policy;
+    ///
+    /// let per_second = Policy::from_tokens_per_second(2).max_burst(5);
+    /// let per_minute = Policy::from_tokens_per_minute(100).max_burst(20);
+    /// let stricter = per_second.min(per_minute);
+    /// assert_eq!((stricter.tokens, stricter.period.as_secs()), (100, 60));
+    /// assert_eq!(stricter.burst, 5);
+    ///
+    /// let stricter = per_minute.min(per_second);
+    /// assert_eq!((stricter.tokens, stricter.period.as_secs(), stricter.burst), (100, 60, 5));
+    ///```
+    fn min(self, other: Policy) -> Policy;
+
+    /// Render the policy compactly, e.g. for logs: `Policy` itself lives in
+    /// `redis-cell-rs` and only implements `Debug`.
+    ///
+    ///```
+    /// use std::time::Duration;
+    /// use tower_redis_cell::PolicyExt;
+    /// use tower_redis_cell::redis_cell::Policy;
+    ///
+    /// let policy = Policy::from_tokens_per_minute(30).max_burst(15).name("basic");
+    /// assert_eq!(policy.display().to_string(), "basic: 30 tokens / 60s (burst 15, apply 1)");
+    ///
+    /// let policy = Policy::from_tokens_per_period(3, Duration::from_millis(1500));
+    /// assert_eq!(policy.display().to_string(), "3 tokens / 1.5s (burst 0, apply 1)");
+    ///```
+    fn display(&self) -> DisplayPolicy<'_>;
+}
+
+/// Helper rendering a policy, see [`PolicyExt::display`].
+#[derive(Debug, Clone, Copy)]
+pub struct DisplayPolicy<'a>(&'a Policy);
+
+impl Display for DisplayPolicy<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let policy = self.0;
+        if let Some(name) = policy.name {
+            write!(f, "{}: ", name)?;
+        }
+        write!(
+            f,
+            "{} tokens / {:?} (burst {}, apply {})",
+            policy.tokens, policy.period, policy.burst, policy.apply
+        )
+    }
+}
+
+impl PolicyExt for Policy {
+    fn sanity_check(&self) -> Result<(), PolicyWarning> {
+        if self.period.subsec_nanos() != 0 {
+            return Err(PolicyWarning::SubSecondPeriod);
+        }
+        // Redis Cell's bucket holds `burst + 1` tokens
+        if self.apply > self.burst.saturating_add(1) {
+            return Err(PolicyWarning::AlwaysBlocks);
+        }
+        let period = self.period.as_secs() as u128 * 1_000_000_000;
+        if self.tokens != 0 && period / (self.tokens as u128) == 0 {
+            return Err(PolicyWarning::NeverLimits);
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), PolicyError> {
+        let fields = [
+            ("burst", self.burst as u64),
+            ("tokens", self.tokens as u64),
+            ("period", self.period.as_secs()),
+            ("apply", self.apply as u64),
+        ];
+        for (field, value) in fields {
+            if value > i64::MAX as u64 {
+                return Err(PolicyError::OutOfRange { field, value });
+            }
+        }
+        if self.period.as_secs() == 0 {
+            return Err(PolicyError::ZeroPeriod);
+        }
+        if self.tokens == 0 {
+            return Err(PolicyError::ZeroTokens);
+        }
+        Ok(())
+    }
+
+    fn try_new(
+        burst: usize,
+        tokens: usize,
+        period: Duration,
+        apply: usize,
+    ) -> Result<Policy, PolicyError> {
+        let policy = Policy::new(burst, tokens, period, apply);
+        policy.validate().map(|()| policy)
+    }
+
+    fn min(self, other: Policy) -> Policy {
+        // a / b < c / d <=> a * d < c * b for positive periods
+        let rate =
+            |policy: &Policy, other: &Policy| policy.tokens as u128 * other.period.as_nanos();
+        let burst = self.burst.min(other.burst);
+        let stricter = match rate(&self, &other).cmp(&rate(&other, &self)) {
+            std::cmp::Ordering::Less => self,
+            std::cmp::Ordering::Greater => other,
+            std::cmp::Ordering::Equal if self.burst <= other.burst => self,
+            std::cmp::Ordering::Equal => other,
+        };
+        stricter.max_burst(burst)
+    }
+
+    fn display(&self) -> DisplayPolicy<'_> {
+        DisplayPolicy(self)
+    }
+
+    fn describe(&self) -> String {
+        const UNITS: [(u64, &str); 4] = [
+            (60 * 60 * 24, "day"),
+            (60 * 60, "hour"),
+            (60, "minute"),
+            (1, "second"),
+        ];
+        let secs = self.period.as_secs();
+        let (count, unit) = UNITS
+            .iter()
+            .find(|(unit_secs, _)| secs >= *unit_secs && secs % unit_secs == 0)
+            .map(|(unit_secs, unit)| (secs / unit_secs, *unit))
+            .unwrap_or((secs, "second"));
+        let period = match count {
+            1 => unit.to_string(),
+            count => format!("{} {}s", count, unit),
+        };
+        let mut description = format!("{} per {}", requests(self.tokens as u64), period);
+        if self.burst > 0 {
+            description.push_str(" with bursts of up to ");
+            description.push_str(&requests(self.burst as u64 + 1));
+        }
+        description
+    }
+}
+
+fn requests(count: u64) -> String {
+    match count {
+        1 => "1 request".to_string(),
+        count => format!("{} requests", count),
+    }
+}
@@ -0,0 +1,369 @@
+use crate::rule::{ProvideRule, ProvideRuleResult, ProvideRulesResult, Rule};
+use redis_cell_rs::Policy;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Combinators for rule providers.
+pub trait ProvideRuleExt<R>: ProvideRule<R> + Sized {
+    /// Cache the rules resolved by this provider.
+    ///
+    /// Requests for which `key_fn` returns equal values within `ttl` will be
+    /// resolved to the same rule without consulting this provider again. At
+    /// most `capacity` entries are kept, with the expired and then the oldest
+    /// ones evicted first. Errors are never cached.
+    ///
+    ///```
+    /// use std::sync::Arc;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::time::Duration;
+    /// use tower_redis_cell::redis_cell::Policy;
+    /// use tower_redis_cell::{ProvideRule, ProvideRuleExt, ProvideRuleResult, Rule};
+    ///
+    /// #[derive(Clone, Default)]
+    /// struct ExpensiveProvider(Arc<AtomicUsize>);
+    ///
+    /// impl ProvideRule<String> for ExpensiveProvider {
+    ///     fn provide<'a>(&self, req: &'a String) -> ProvideRuleResult<'a> {
+    ///         self.0.fetch_add(1, Ordering::Relaxed);
+    ///         Ok(Some(Rule::new(req.as_str(), Policy::from_tokens_per_second(1))))
+    ///     }
+    /// }
+    ///
+    /// let calls = Arc::new(AtomicUsize::new(0));
+    /// let provider = ExpensiveProvider(calls.clone()).cached_by(
+    ///     |req: &String| req.clone(),
+    ///     Duration::from_secs(60),
+    ///     1_000,
+    /// );
+    ///
+    /// let req = "user123".to_string();
+    /// for _ in 0..3 {
+    ///     let rule = provider.provide(&req).unwrap().unwrap();
+    ///     assert_eq!(rule.key.to_string(), "user123");
+    /// }
+    /// assert_eq!(calls.load(Ordering::Relaxed), 1);
+    ///```
+    fn cached_by<F, K>(self, key_fn: F, ttl: Duration, capacity: usize) -> CachedBy<Self, F, K>
+    where
+        F: Fn(&R) -> K,
+        K: Hash + Eq + Clone,
+    {
+        CachedBy {
+            inner: self,
+            key_fn,
+            ttl,
+            capacity,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Switch the policy of the provided rules at the given points in time,
+    /// e.g. when a tenant's new plan kicks in at midnight.
+    ///
+    /// The rule's policy is replaced with the policy of the latest transition
+    /// that has already taken effect, while before the first transition the
+    /// policy chosen by this provider is used as is. The system clock is
+    /// consulted by default, see [`Scheduled::with_clock`] to inject another one.
+    ///
+    ///```
+    /// use std::sync::{Arc, Mutex};
+    /// use std::time::{Duration, SystemTime};
+    /// use tower_redis_cell::redis_cell::Policy;
+    /// use tower_redis_cell::{ProvideRule, ProvideRuleExt, ProvideRuleResult, Rule};
+    ///
+    /// struct Tenant;
+    ///
+    /// impl ProvideRule<()> for Tenant {
+    ///     fn provide<'a>(&self, _: &'a ()) -> ProvideRuleResult<'a> {
+    ///         Ok(Some(Rule::new("tenant", Policy::from_tokens_per_minute(10))))
+    ///     }
+    /// }
+    ///
+    /// let midnight = SystemTime::UNIX_EPOCH + Duration::from_secs(1_767_225_600);
+    /// let now = Arc::new(Mutex::new(midnight - Duration::from_secs(1)));
+    /// let clock = {
+    ///     let now = Arc::clone(&now);
+    ///     move || *now.lock().unwrap()
+    /// };
+    /// let provider = Tenant
+    ///     .scheduled([(midnight, Policy::from_tokens_per_minute(100))])
+    ///     .with_clock(clock);
+    ///
+    /// assert_eq!(provider.provide(&()).unwrap().unwrap().policy.tokens, 10);
+    /// *now.lock().unwrap() = midnight;
+    /// assert_eq!(provider.provide(&()).unwrap().unwrap().policy.tokens, 100);
+    ///```
+    fn scheduled<I>(self, transitions: I) -> Scheduled<Self, fn() -> SystemTime>
+    where
+        I: IntoIterator<Item = (SystemTime, Policy)>,
+    {
+        let mut transitions: Vec<_> = transitions.into_iter().collect();
+        transitions.sort_by_key(|(effective_at, _)| *effective_at);
+        Scheduled {
+            inner: self,
+            transitions: transitions.into(),
+            clock: SystemTime::now,
+        }
+    }
+
+    /// Consult `other` for the requests this provider has no rule for, e.g.
+    /// key anonymous traffic by IP and authenticated one by API key.
+    ///
+    /// Only `Ok(None)` falls through to `other`, while an error from this
+    /// provider is returned as is, without consulting `other`.
+    ///
+    ///```
+    /// use axum::http::Request;
+    /// use tower_redis_cell::redis_cell::Policy;
+    /// use tower_redis_cell::{ProvideRule, ProvideRuleExt, ProvideRuleResult, Rule};
+    ///
+    /// struct ByApiKey;
+    ///
+    /// impl ProvideRule<Request<()>> for ByApiKey {
+    ///     fn provide<'a>(&self, req: &'a Request<()>) -> ProvideRuleResult<'a> {
+    ///         let Some(api_key) = req.headers().get("x-api-key") else { return Ok(None) };
+    ///         let api_key = api_key.to_str().map_err(|_| "invalid api key")?;
+    ///         Ok(Some(Rule::new(api_key, Policy::from_tokens_per_second(100))))
+    ///     }
+    /// }
+    ///
+    /// struct Anonymous;
+    ///
+    /// impl ProvideRule<Request<()>> for Anonymous {
+    ///     fn provide<'a>(&self, _: &'a Request<()>) -> ProvideRuleResult<'a> {
+    ///         Ok(Some(Rule::new("anonymous", Policy::from_tokens_per_second(1))))
+    ///     }
+    /// }
+    ///
+    /// let provider = ByApiKey.or(Anonymous);
+    ///
+    /// let req = Request::get("/").header("x-api-key", "key123").body(()).unwrap();
+    /// assert_eq!(provider.provide(&req).unwrap().unwrap().key.to_string(), "key123");
+    ///
+    /// let req = Request::get("/").body(()).unwrap();
+    /// assert_eq!(provider.provide(&req).unwrap().unwrap().key.to_string(), "anonymous");
+    ///
+    /// let req = Request::get("/").header("x-api-key", "k\u{e9}y").body(()).unwrap();
+    /// assert!(provider.provide(&req).is_err());
+    ///```
+    fn or<O>(self, other: O) -> Or<Self, O>
+    where
+        O: ProvideRule<R>,
+    {
+        Or {
+            first: self,
+            second: other,
+        }
+    }
+}
+
+impl<R, P> ProvideRuleExt<R> for P where P: ProvideRule<R> {}
+
+type CachedRules<K> = HashMap<K, (Instant, Vec<Rule<'static>>)>;
+
+/// Rule provider returned by [`ProvideRuleExt::cached_by`].
+pub struct CachedBy<P, F, K> {
+    inner: P,
+    key_fn: F,
+    ttl: Duration,
+    capacity: usize,
+    cache: Arc<Mutex<CachedRules<K>>>,
+}
+
+impl<P, F, K> Clone for CachedBy<P, F, K>
+where
+    P: Clone,
+    F: Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            key_fn: self.key_fn.clone(),
+            ttl: self.ttl,
+            capacity: self.capacity,
+            cache: Arc::clone(&self.cache),
+        }
+    }
+}
+
+impl<R, P, F, K> ProvideRule<R> for CachedBy<P, F, K>
+where
+    P: ProvideRule<R>,
+    F: Fn(&R) -> K,
+    K: Hash + Eq + Clone,
+{
+    fn provide<'a>(&self, req: &'a R) -> ProvideRuleResult<'a> {
+        self.provide_many(req).map(|rules| rules.into_iter().next())
+    }
+
+    fn provide_many<'a>(&self, req: &'a R) -> ProvideRulesResult<'a> {
+        let key = (self.key_fn)(req);
+        let now = Instant::now();
+        {
+            let cache = self.cache.lock().expect("not poisoned");
+            let cached = cache
+                .get(&key)
+                .filter(|(cached_at, _)| now.duration_since(*cached_at) < self.ttl);
+            if let Some((_, rules)) = cached {
+                return Ok(rules.clone());
+            }
+        }
+        let rules: Vec<_> = self
+            .inner
+            .provide_many(req)?
+            .into_iter()
+            .map(Rule::into_owned)
+            .collect();
+        if self.capacity == 0 {
+            return Ok(rules);
+        }
+        let mut cache = self.cache.lock().expect("not poisoned");
+        if cache.len() >= self.capacity && !cache.contains_key(&key) {
+            cache.retain(|_, (cached_at, _)| now.duration_since(*cached_at) < self.ttl);
+        }
+        if cache.len() >= self.capacity && !cache.contains_key(&key) {
+            let oldest = cache
+                .iter()
+                .min_by_key(|(_, (cached_at, _))| *cached_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                cache.remove(&oldest);
+            }
+        }
+        cache.insert(key, (now, rules.clone()));
+        Ok(rules)
+    }
+}
+
+/// Rule provider returned by [`ProvideRuleExt::scheduled`].
+#[derive(Debug, Clone)]
+pub struct Scheduled<P, C> {
+    inner: P,
+    transitions: Arc<[(SystemTime, Policy)]>,
+    clock: C,
+}
+
+impl<P, C> Scheduled<P, C> {
+    /// Use `clock` instead of [`SystemTime::now`] to tell the current time.
+    pub fn with_clock<T>(self, clock: T) -> Scheduled<P, T>
+    where
+        T: Fn() -> SystemTime,
+    {
+        Scheduled {
+            inner: self.inner,
+            transitions: self.transitions,
+            clock,
+        }
+    }
+}
+
+impl<R, P, C> ProvideRule<R> for Scheduled<P, C>
+where
+    P: ProvideRule<R>,
+    C: Fn() -> SystemTime,
+{
+    fn provide<'a>(&self, req: &'a R) -> ProvideRuleResult<'a> {
+        let rule = self.inner.provide(req)?;
+        Ok(rule.map(|rule| self.reschedule(rule)))
+    }
+
+    fn provide_many<'a>(&self, req: &'a R) -> ProvideRulesResult<'a> {
+        let rules = self.inner.provide_many(req)?;
+        Ok(rules
+            .into_iter()
+            .map(|rule| self.reschedule(rule))
+            .collect())
+    }
+}
+
+impl<P, C> Scheduled<P, C>
+where
+    C: Fn() -> SystemTime,
+{
+    fn reschedule<'a>(&self, mut rule: Rule<'a>) -> Rule<'a> {
+        let now = (self.clock)();
+        let active = self
+            .transitions
+            .partition_point(|(effective_at, _)| *effective_at <= now);
+        if let Some((_, policy)) = active.checked_sub(1).map(|i| self.transitions[i]) {
+            rule.policy = policy;
+        }
+        rule
+    }
+}
+
+/// Rule provider returned by [`ProvideRuleExt::or`].
+#[derive(Debug, Clone)]
+pub struct Or<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<R, A, B> ProvideRule<R> for Or<A, B>
+where
+    A: ProvideRule<R>,
+    B: ProvideRule<R>,
+{
+    fn provide<'a>(&self, req: &'a R) -> ProvideRuleResult<'a> {
+        match self.first.provide(req)? {
+            Some(rule) => Ok(Some(rule)),
+            None => self.second.provide(req),
+        }
+    }
+
+    fn provide_many<'a>(&self, req: &'a R) -> ProvideRulesResult<'a> {
+        let rules = self.first.provide_many(req)?;
+        if rules.is_empty() {
+            return self.second.provide_many(req);
+        }
+        Ok(rules)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Pair;
+
+    impl ProvideRule<()> for Pair {
+        fn provide<'a>(&self, req: &'a ()) -> ProvideRuleResult<'a> {
+            Ok(self.provide_many(req)?.into_iter().next())
+        }
+
+        fn provide_many<'a>(&self, _: &'a ()) -> ProvideRulesResult<'a> {
+            let policy = Policy::from_tokens_per_second(1);
+            Ok(vec![Rule::new("a", policy), Rule::new("b", policy)])
+        }
+    }
+
+    struct Nothing;
+
+    impl ProvideRule<()> for Nothing {
+        fn provide<'a>(&self, _: &'a ()) -> ProvideRuleResult<'a> {
+            Ok(None)
+        }
+    }
+
+    fn keys(rules: Vec<Rule<'_>>) -> Vec<String> {
+        rules.iter().map(|rule| rule.key.to_string()).collect()
+    }
+
+    #[test]
+    fn wrappers_forward_provide_many() {
+        let policy = Policy::from_tokens_per_minute(1);
+        let provider = Nothing
+            .or(Pair)
+            .scheduled([(SystemTime::UNIX_EPOCH, policy)])
+            .cached_by(|_| (), Duration::from_secs(60), 1);
+        let rules = provider.provide_many(&()).unwrap();
+        assert!(rules.iter().all(|rule| rule.policy.period == policy.period));
+        assert_eq!(keys(rules), ["a", "b"]);
+        // served from the cache
+        assert_eq!(keys(provider.provide_many(&()).unwrap()), ["a", "b"]);
+        let rule = provider.provide(&()).unwrap().unwrap();
+        assert_eq!(rule.key.to_string(), "a");
+    }
+}
@@ -1,5 +1,9 @@
 use crate::ProvideRuleError;
+use crate::policy::PolicyExt as _;
 use redis_cell_rs::{AllowedDetails, BlockedDetails, Key, Policy};
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 #[derive(Debug, Clone)]
 #[non_exhaustive]
@@ -7,6 +11,21 @@ pub struct Rule<'a> {
     pub key: Key<'a>,
     pub policy: Policy,
     pub resource: Option<&'static str>,
+    /// Floor for the retry-after advertised to the client, in seconds.
+    pub min_retry_after: Option<u64>,
+    /// Ceiling for the retry-after advertised to the client, in seconds.
+    pub max_retry_after: Option<u64>,
+    /// Do not run the success handler for requests allowed by this rule.
+    pub skip_success_handler: bool,
+    /// Extra dimensions for observability, see [`Rule::labels`].
+    pub labels: &'static [(&'static str, &'static str)],
+    /// Policy to try once the primary one blocks, see [`Rule::fallback`].
+    pub fallback: Option<Policy>,
+    /// Resource whose registered policy is yet to be looked up, see
+    /// [`Rule::for_resource`].
+    pub(crate) unresolved: Option<&'static str>,
+    /// Tokens to charge instead of the policy's `apply`, see [`Rule::apply`].
+    pub(crate) apply: Option<usize>,
 }
 
 impl<'a> Rule<'a> {
@@ -18,18 +37,412 @@ impl<'a> Rule<'a> {
             key: key.into(),
             policy,
             resource: None,
+            min_retry_after: None,
+            max_retry_after: None,
+            skip_success_handler: false,
+            labels: &[],
+            fallback: None,
+            unresolved: None,
+            apply: None,
         }
     }
 
+    /// A rule with a key computed in the provider, e.g. from several request
+    /// parts, which does not borrow from the request.
+    ///
+    /// This is [`Rule::new`] with a [`Key::String`], so the rule can be
+    /// returned from [`ProvideRule::provide`] whatever the request's lifetime.
+    ///
+    ///```
+    /// use axum::http::Request;
+    /// use tower_redis_cell::redis_cell::Policy;
+    /// use tower_redis_cell::{ProvideRule, ProvideRuleResult, Rule};
+    ///
+    /// #[derive(Clone)]
+    /// struct ByUserAndRoute;
+    ///
+    /// impl<B> ProvideRule<Request<B>> for ByUserAndRoute {
+    ///     fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+    ///         let user = req.headers().get("x-user-id").and_then(|v| v.to_str().ok());
+    ///         let Some(user) = user else { return Ok(None) };
+    ///         let route = req.uri().path();
+    ///         let policy = Policy::from_tokens_per_second(10);
+    ///         Ok(Some(Rule::owned(format!("{user}:{route}"), policy)))
+    ///     }
+    /// }
+    ///
+    /// let req = Request::get("/search").header("x-user-id", "user123").body(()).unwrap();
+    /// let rule = ByUserAndRoute.provide(&req).unwrap().unwrap();
+    /// assert_eq!(rule.key.to_string(), "user123:/search");
+    ///```
+    pub fn owned(key: impl Into<String>, policy: Policy) -> Rule<'static> {
+        Rule::new(Key::String(key.into()), policy)
+    }
+
+    /// A rule for a resource whose policy is looked up in the ones registered
+    /// with [`RateLimitConfig::policies`](crate::RateLimitConfig::policies).
+    ///
+    /// The request is failed with [`Error::ProvideRule`](crate::Error::ProvideRule)
+    /// if no policy has been registered for the resource, and so is the check
+    /// with [`rate_limit_check`](crate::rate_limit_check), which has got no
+    /// policies to look the resource up in.
+    pub fn for_resource<K>(key: K, resource: &'static str) -> Self
+    where
+        K: Into<Key<'a>>,
+    {
+        // a placeholder, replaced with the registered policy once looked up
+        let mut rule = Self::new(key, Policy::from_tokens_per_second(1)).resource(resource);
+        rule.unresolved = Some(resource);
+        rule
+    }
+
     pub fn resource(mut self, resource_name: &'static str) -> Self {
         self.resource = Some(resource_name);
         self
     }
+
+    /// Charge `tokens` for this request instead of the policy's `apply`, e.g.
+    /// N tokens for a batch of N items.
+    ///
+    /// This holds for a rule [`for_resource`](Rule::for_resource) just the
+    /// same, the override being applied once its policy has been looked up.
+    /// A cost derived by [`RateLimitConfig::cost`](crate::RateLimitConfig::cost)
+    /// still takes precedence.
+    ///
+    ///```
+    /// use tower_redis_cell::Rule;
+    /// use tower_redis_cell::redis_cell::Policy;
+    ///
+    /// let items = ["a", "b", "c"];
+    /// let policy = Policy::from_tokens_per_minute(100).max_burst(100);
+    /// let rule = Rule::new("user123", policy).apply(items.len());
+    ///```
+    pub fn apply(mut self, tokens: usize) -> Self {
+        self.apply = Some(tokens);
+        self
+    }
+
+    /// The rule's policy with the [`apply`](Rule::apply) override, if any.
+    pub(crate) fn charged_policy(&self) -> Policy {
+        match self.apply {
+            Some(tokens) => self.policy.apply_tokens(tokens),
+            None => self.policy,
+        }
+    }
+
+    /// Advertise a retry-after of at least `secs` seconds when this rule blocks.
+    ///
+    /// Takes precedence over [`RateLimitConfig::min_retry_after`](crate::RateLimitConfig::min_retry_after).
+    pub fn min_retry_after(mut self, secs: u64) -> Self {
+        self.min_retry_after = Some(secs);
+        self
+    }
+
+    /// Advertise a retry-after of at most `secs` seconds when this rule blocks.
+    ///
+    /// Takes precedence over [`RateLimitConfig::max_retry_after`](crate::RateLimitConfig::max_retry_after).
+    pub fn max_retry_after(mut self, secs: u64) -> Self {
+        self.max_retry_after = Some(secs);
+        self
+    }
+
+    /// Skip the [success handler](crate::RateLimitConfig::on_success) for
+    /// requests allowed by this rule, e.g. for a streaming endpoint where the
+    /// headers have already been sent.
+    ///
+    /// The [`on_response`](crate::RateLimitConfig::on_response) hook still runs.
+    ///
+    ///```
+    /// use axum::http::Request;
+    /// use tower_redis_cell::redis_cell::Policy;
+    /// use tower_redis_cell::{ProvideRule, ProvideRuleResult, Rule};
+    ///
+    /// #[derive(Clone)]
+    /// struct ByRoute;
+    ///
+    /// impl<B> ProvideRule<Request<B>> for ByRoute {
+    ///     fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+    ///         let rule = Rule::new(req.uri().path(), Policy::from_tokens_per_second(10));
+    ///         match req.uri().path() {
+    ///             "/stream" => Ok(Some(rule.skip_success_handler())),
+    ///             _ => Ok(Some(rule)),
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let req = Request::builder().uri("/stream").body(()).unwrap();
+    /// assert!(ByRoute.provide(&req).unwrap().unwrap().skip_success_handler);
+    ///```
+    pub fn skip_success_handler(mut self) -> Self {
+        self.skip_success_handler = true;
+        self
+    }
+
+    /// Attach labels (e.g. region or plan tier) to the decisions made for
+    /// this rule, see [`DecisionEvent::labels`](crate::DecisionEvent::labels).
+    /// With the `metrics` and `tracing` features on, they also label the verdict
+    /// counters and get recorded on the span.
+    ///
+    /// Labels are meant for dashboards, so keep their cardinality low: a plan
+    /// tier is a good label, while a user ID is not (that is what the key is for).
+    ///
+    ///```
+    /// use tower_redis_cell::Rule;
+    /// use tower_redis_cell::redis_cell::Policy;
+    ///
+    /// const LABELS: &[(&str, &str)] = &[("region", "eu-west-1"), ("plan", "premium")];
+    ///
+    /// let rule = Rule::new("tenant", Policy::from_tokens_per_second(100)).labels(LABELS);
+    /// assert_eq!(rule.labels, LABELS);
+    ///```
+    pub fn labels(mut self, labels: &'static [(&'static str, &'static str)]) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Try the request against `policy` once the rule's own policy blocks it,
+    /// e.g. to have premium users fall back to the standard tier rather than
+    /// being rejected straight away.
+    ///
+    /// The fallback policy is tracked in a cell of its own (the rule's key with
+    /// a `:fallback` suffix), so that the primary cell stays intact. Once the
+    /// fallback is tried, the rule carries its key and policy from there on:
+    /// the verdict is recorded (and traced) against the fallback cell, the
+    /// success handler sees the fallback policy, and should the fallback block
+    /// as well, the error carries its key and details.
+    ///
+    ///```
+    /// use tower_redis_cell::Rule;
+    /// use tower_redis_cell::redis_cell::Policy;
+    ///
+    /// const PREMIUM: Policy = Policy::from_tokens_per_minute(1000).name("premium");
+    /// const STANDARD: Policy = Policy::from_tokens_per_minute(10).name("standard");
+    ///
+    /// // checked against `user123:fallback` once `user123` is exhausted
+    /// let rule = Rule::new("user123", PREMIUM).fallback(STANDARD);
+    /// assert_eq!(rule.fallback.unwrap().name, Some("standard"));
+    ///```
+    pub fn fallback(mut self, policy: Policy) -> Self {
+        self.fallback = Some(policy);
+        self
+    }
+
+    /// Clamp the retry-after reported by the backend to this rule's bounds.
+    ///
+    /// Only what is advertised to the client is affected, the bucket state
+    /// stays intact. Should the bounds contradict each other, the ceiling wins.
+    ///
+    ///```
+    /// use tower_redis_cell::Rule;
+    /// use tower_redis_cell::redis_cell::Policy;
+    ///
+    /// const POLICY: Policy = Policy::from_tokens_per_minute(10);
+    ///
+    /// let free = Rule::new("free-tenant", POLICY).min_retry_after(30);
+    /// let premium = Rule::new("premium-tenant", POLICY).min_retry_after(5);
+    /// assert_eq!(free.clamp_retry_after(2), 30);
+    /// assert_eq!(premium.clamp_retry_after(2), 5);
+    ///
+    /// let capped = Rule::new("tenant", POLICY).max_retry_after(60);
+    /// assert_eq!(capped.clamp_retry_after(3600), 60);
+    ///```
+    pub fn clamp_retry_after(&self, retry_after: u64) -> u64 {
+        let retry_after = retry_after.max(self.min_retry_after.unwrap_or(0));
+        retry_after.min(self.max_retry_after.unwrap_or(u64::MAX))
+    }
+
+    /// Detach the rule from the request it has been provided for.
+    pub fn into_owned(self) -> Rule<'static> {
+        Rule {
+            key: key_into_owned(self.key),
+            policy: self.policy,
+            resource: self.resource,
+            min_retry_after: self.min_retry_after,
+            max_retry_after: self.max_retry_after,
+            skip_success_handler: self.skip_success_handler,
+            labels: self.labels,
+            unresolved: self.unresolved,
+            apply: self.apply,
+            fallback: self.fallback,
+        }
+    }
+}
+
+pub(crate) fn key_into_owned(key: Key<'_>) -> Key<'static> {
+    match key {
+        Key::String(value) => Key::String(value),
+        Key::Str(value) => Key::String(value.to_owned()),
+        Key::Usize(value) => Key::Usize(value),
+        Key::Isize(value) => Key::Isize(value),
+        #[cfg(feature = "uuid")]
+        Key::Uuid(value) => Key::Uuid(value),
+        Key::Pair(value1, value2) => Key::Pair(
+            Cow::Owned(value1.into_owned()),
+            Cow::Owned(value2.into_owned()),
+        ),
+        Key::Triple(value1, value2, value3) => Key::Triple(
+            Cow::Owned(value1.into_owned()),
+            Cow::Owned(value2.into_owned()),
+            Cow::Owned(value3.into_owned()),
+        ),
+        other => Key::String(other.to_string()),
+    }
+}
+
+/// Extra constructors for [`Key`].
+pub trait KeyExt: Sized {
+    /// Key a client by the IP address of its socket, **dropping the port**.
+    ///
+    /// The port of a client socket is ephemeral, so keying by it would give
+    /// every connection a bucket of its own, see [`KeyExt::socket_with_port`]
+    /// should that really be wanted.
+    ///
+    ///```
+    /// use std::net::SocketAddr;
+    /// use tower_redis_cell::KeyExt;
+    /// use tower_redis_cell::redis_cell::Key;
+    ///
+    /// let addr: SocketAddr = "192.0.2.1:51234".parse().unwrap();
+    /// assert_eq!(Key::from_socket_addr(addr).to_string(), "192.0.2.1");
+    /// assert_eq!(Key::socket_with_port(addr).to_string(), "192.0.2.1:51234");
+    ///
+    /// let addr: SocketAddr = "[2001:db8::1]:51234".parse().unwrap();
+    /// assert_eq!(Key::from_socket_addr(addr).to_string(), "2001:db8::1");
+    ///```
+    fn from_socket_addr(addr: SocketAddr) -> Self;
+
+    /// Key by the full socket address, port included.
+    fn socket_with_port(addr: SocketAddr) -> Self;
+}
+
+impl KeyExt for Key<'_> {
+    fn from_socket_addr(addr: SocketAddr) -> Self {
+        Key::String(addr.ip().to_string())
+    }
+
+    fn socket_with_port(addr: SocketAddr) -> Self {
+        Key::String(addr.to_string())
+    }
 }
 
 pub type ProvideRuleResult<'a> = Result<Option<Rule<'a>>, ProvideRuleError<'a>>;
+pub type ProvideRulesResult<'a> = Result<Vec<Rule<'a>>, ProvideRuleError<'a>>;
+
 pub trait ProvideRule<R> {
     fn provide<'a>(&self, req: &'a R) -> ProvideRuleResult<'a>;
+
+    /// Provide several independent rules for the request, e.g. one per API key
+    /// and one per IP address, which must all allow the request.
+    ///
+    /// The services issue the commands for all the rules in one round trip
+    /// (a pipeline). Should several rules block, the one asking to wait the
+    /// longest is reported to the error handler, so that the client does not
+    /// retry too early, and its key identifies the limit that got exhausted.
+    /// Note that the rules which allow the request still take their tokens.
+    /// If all of them allow it, the success handler sees the one with the
+    /// fewest tokens remaining. Every rule goes through the same steps a
+    /// single one does, i.e. its [`fallback`](Rule::fallback), the
+    /// [local counter](crate::RateLimitConfig::local_counter), the blocked
+    /// cache of the `local-cache` feature, and the
+    /// [post-charge](crate::RateLimitConfig::post_charge) all apply.
+    ///
+    /// Defaults to the rule from [`ProvideRule::provide`], if any. The
+    /// providers of this crate wrapping another one (e.g.
+    /// [`ProvideRuleExt::or`](crate::ProvideRuleExt::or)) forward this method
+    /// to the wrapped provider, so a provider overriding it should still
+    /// implement [`ProvideRule::provide`] sensibly, e.g. with its first rule.
+    ///
+    ///```
+    /// use axum::http::Request;
+    /// use tower_redis_cell::redis_cell::{Key, Policy};
+    /// use tower_redis_cell::{ProvideRule, ProvideRuleResult, ProvideRulesResult, Rule};
+    ///
+    /// #[derive(Clone)]
+    /// struct ByKeyAndIp;
+    ///
+    /// impl<B> ProvideRule<Request<B>> for ByKeyAndIp {
+    ///     fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+    ///         Ok(self.provide_many(req)?.into_iter().next())
+    ///     }
+    ///
+    ///     fn provide_many<'a>(&self, req: &'a Request<B>) -> ProvideRulesResult<'a> {
+    ///         let api_key = req.headers()["x-api-key"].to_str().unwrap();
+    ///         let ip = req.headers()["x-forwarded-for"].to_str().unwrap();
+    ///         Ok(vec![
+    ///             Rule::new(Key::pair("key", api_key), Policy::from_tokens_per_minute(100)),
+    ///             Rule::new(format!("ip:{}", ip), Policy::from_tokens_per_minute(10)),
+    ///         ])
+    ///     }
+    /// }
+    ///```
+    fn provide_many<'a>(&self, req: &'a R) -> ProvideRulesResult<'a> {
+        self.provide(req).map(|rule| rule.into_iter().collect())
+    }
+}
+
+/// Rule provider which needs to wait for something, e.g. for the caller's
+/// tier to be looked up in a database, before deciding on the rule.
+///
+/// Every [`ProvideRule`] is an `AsyncProvideRule` too, so the services accept
+/// either. Note that the provider is consulted on every request, so an
+/// expensive lookup is best cached.
+///
+///```
+/// use axum::http::Request;
+/// use std::collections::HashMap;
+/// use std::sync::Arc;
+/// use tokio::sync::RwLock;
+/// use tower_redis_cell::redis_cell::Policy;
+/// use tower_redis_cell::{AsyncProvideRule, ProvideRuleResult, Rule};
+///
+/// // stands in for a database
+/// #[derive(Clone, Default)]
+/// struct Tiers(Arc<RwLock<HashMap<String, Policy>>>);
+///
+/// impl<B: Sync> AsyncProvideRule<Request<B>> for Tiers {
+///     async fn provide<'a>(&'a self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+///         let user = req
+///             .headers()
+///             .get("x-user-id")
+///             .and_then(|val| val.to_str().ok())
+///             .ok_or("'x-user-id' header is missing")?;
+///         let tiers = self.0.read().await;
+///         let policy = tiers.get(user).ok_or("unknown user")?;
+///         Ok(Some(Rule::new(user, *policy)))
+///     }
+/// }
+///```
+pub trait AsyncProvideRule<R> {
+    fn provide<'a>(&'a self, req: &'a R)
+    -> impl Future<Output = ProvideRuleResult<'a>> + Send + 'a;
+
+    /// See [`ProvideRule::provide_many`].
+    fn provide_many<'a>(
+        &'a self,
+        req: &'a R,
+    ) -> impl Future<Output = ProvideRulesResult<'a>> + Send + 'a {
+        let rule = self.provide(req);
+        async move { rule.await.map(|rule| rule.into_iter().collect()) }
+    }
+}
+
+impl<R, P> AsyncProvideRule<R> for P
+where
+    P: ProvideRule<R>,
+{
+    fn provide<'a>(
+        &'a self,
+        req: &'a R,
+    ) -> impl Future<Output = ProvideRuleResult<'a>> + Send + 'a {
+        std::future::ready(ProvideRule::provide(self, req))
+    }
+
+    fn provide_many<'a>(
+        &'a self,
+        req: &'a R,
+    ) -> impl Future<Output = ProvideRulesResult<'a>> + Send + 'a {
+        std::future::ready(ProvideRule::provide_many(self, req))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -39,6 +452,49 @@ pub struct RequestBlockedDetails<'a> {
     pub rule: Rule<'a>,
 }
 
+impl RequestBlockedDetails<'_> {
+    /// Detach the details from the request they have been produced for.
+    pub fn into_owned(self) -> RequestBlockedDetails<'static> {
+        RequestBlockedDetails {
+            details: self.details,
+            rule: self.rule.into_owned(),
+        }
+    }
+
+    /// Number of seconds to advertise to the client, i.e. the backend's
+    /// `retry_after` clamped to the rule's bounds, see [`Rule::clamp_retry_after`].
+    pub fn retry_after(&self) -> u64 {
+        self.rule.clamp_retry_after(self.details.retry_after)
+    }
+
+    /// The `retry_after` reported by Redis as a [`Duration`], e.g. for a gRPC
+    /// `RetryInfo`.
+    ///
+    /// Unlike [`Self::retry_after`], this is not clamped to the rule's bounds.
+    ///
+    ///```
+    /// use std::time::Duration;
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct ByCaller;
+    ///
+    /// // a non-HTTP service, with the retry delay to be put into the status
+    /// type Reply = Result<&'static str, Duration>;
+    /// let config: RateLimitConfig<_, String, Reply, Reply> =
+    ///     RateLimitConfig::new(ByCaller, |err: Error, _req: &String| match err {
+    ///         Error::RateLimit(details) => Err(details.retry_after_duration()),
+    ///         _ => Err(Duration::ZERO),
+    ///     });
+    ///```
+    pub fn retry_after_duration(&self) -> Duration {
+        Duration::from_secs(self.details.retry_after)
+    }
+
+    /// Describe the limit that has been hit in words, see [`PolicyExt::describe`](crate::PolicyExt::describe).
+    pub fn limit_description(&self) -> String {
+        self.rule.policy.describe()
+    }
+}
+
 #[derive(Debug, Clone)]
 #[non_exhaustive]
 pub struct RequestAllowedDetails {
@@ -46,3 +502,268 @@ pub struct RequestAllowedDetails {
     pub policy: Policy,
     pub resource: Option<&'static str>,
 }
+
+impl RequestAllowedDetails {
+    /// The bucket's capacity, i.e. the `total` (index 1) in the Redis Cell reply.
+    ///
+    ///```
+    /// use tower_redis_cell::{Error, RateLimitConfig};
+    /// # struct ByCaller;
+    ///
+    /// let config: RateLimitConfig<_, String, _, _> =
+    ///     RateLimitConfig::new(ByCaller, |_err: Error, _req: &String| String::new())
+    ///         .on_success(|details, resp: &mut String| {
+    ///             *resp = format!(
+    ///                 "limit={} remaining={} reset={}",
+    ///                 details.limit(),
+    ///                 details.remaining(),
+    ///                 details.reset_after(),
+    ///             );
+    ///         });
+    ///```
+    pub fn limit(&self) -> usize {
+        self.details.total
+    }
+
+    /// Tokens left in the bucket, i.e. the `remaining` (index 2) in the Redis Cell reply.
+    pub fn remaining(&self) -> usize {
+        self.details.remaining
+    }
+
+    /// Seconds until the bucket is full again, i.e. the `reset_after` (index 4)
+    /// in the Redis Cell reply.
+    pub fn reset_after(&self) -> u64 {
+        self.details.reset_after
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fixed, LocalGcra, MockConnection};
+    use crate::{Error, RateLimitConfig, RateLimitLayer};
+    use std::convert::Infallible;
+    use tower::{Layer as _, ServiceExt as _, service_fn};
+
+    macro_rules! pong {
+        () => {
+            service_fn(|_req: String| async { Ok::<_, Infallible>("pong".to_string()) })
+        };
+    }
+
+    #[tokio::test]
+    async fn skips_the_success_handler() {
+        let policy = Policy::from_tokens_per_second(10);
+        let allowed = |rule: Rule<'static>| {
+            let config =
+                RateLimitConfig::new(Fixed(rule), |err: Error, _: &String| err.to_string())
+                    .on_success(|details, resp: &mut String| {
+                        resp.push_str(&format!(" ({})", details.remaining()))
+                    });
+            RateLimitLayer::new(config, MockConnection::allowed(10, 9)).layer(pong!())
+        };
+
+        let svc = allowed(Rule::new("user123", policy));
+        assert_eq!(svc.oneshot("user123".into()).await.unwrap(), "pong (9)");
+        let svc = allowed(Rule::new("user123", policy).skip_success_handler());
+        assert_eq!(svc.oneshot("user123".into()).await.unwrap(), "pong");
+    }
+
+    #[derive(Clone)]
+    struct ByKeyAndIp;
+
+    impl ProvideRule<String> for ByKeyAndIp {
+        fn provide<'a>(&self, req: &'a String) -> ProvideRuleResult<'a> {
+            Ok(ProvideRule::provide_many(self, req)?.into_iter().next())
+        }
+
+        fn provide_many<'a>(&self, ip: &'a String) -> ProvideRulesResult<'a> {
+            Ok(vec![
+                Rule::new(
+                    "key:secret",
+                    Policy::from_tokens_per_minute(100).max_burst(99),
+                ),
+                Rule::new(
+                    format!("ip:{}", ip),
+                    Policy::from_tokens_per_minute(1).max_burst(0),
+                ),
+            ])
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_the_rule_which_blocks() {
+        let redis = LocalGcra::new();
+        let config = RateLimitConfig::new(ByKeyAndIp, |err: Error, _: &String| match err {
+            Error::RateLimit(details) => details.rule.key.to_string(),
+            err => err.to_string(),
+        });
+        let svc = RateLimitLayer::new(config, redis.clone()).layer(pong!());
+
+        let ip = "203.0.113.7".to_string();
+        assert_eq!(svc.clone().oneshot(ip.clone()).await.unwrap(), "pong");
+        assert_eq!(svc.oneshot(ip).await.unwrap(), "ip:203.0.113.7");
+        // in a single round trip each
+        assert_eq!(redis.calls(), 4);
+        assert_eq!(redis.keys(), ["ip:203.0.113.7", "key:secret"]);
+    }
+
+    /// Provides the same rules for every request.
+    #[derive(Clone)]
+    struct Many(Vec<Rule<'static>>);
+
+    impl ProvideRule<String> for Many {
+        fn provide<'a>(&self, _: &'a String) -> ProvideRuleResult<'a> {
+            Ok(self.0.first().cloned())
+        }
+
+        fn provide_many<'a>(&self, _: &'a String) -> ProvideRulesResult<'a> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn blocked(err: Error, _: &String) -> String {
+        match err {
+            Error::RateLimit(details) => format!("{} {}", details.rule.key, details.retry_after()),
+            err => err.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_the_rule_blocking_for_the_longest() {
+        let rules = Many(vec![
+            Rule::new("second", Policy::from_tokens_per_second(1).max_burst(0)),
+            Rule::new("minute", Policy::from_tokens_per_minute(1).max_burst(0)),
+        ]);
+        let config = RateLimitConfig::new(rules, blocked);
+        let svc = RateLimitLayer::new(config, LocalGcra::new()).layer(pong!());
+
+        assert_eq!(svc.clone().oneshot("user123".into()).await.unwrap(), "pong");
+        assert_eq!(svc.oneshot("user123".into()).await.unwrap(), "minute 60");
+    }
+
+    #[tokio::test]
+    async fn falls_back_for_each_of_the_rules() {
+        let policy = Policy::from_tokens_per_minute(1).max_burst(0);
+        let rules = Many(vec![
+            Rule::new("key", policy).fallback(policy),
+            Rule::new("ip", Policy::from_tokens_per_minute(100).max_burst(99)),
+        ]);
+        let redis = LocalGcra::new();
+        let config = RateLimitConfig::new(rules, blocked);
+        let svc = RateLimitLayer::new(config, redis.clone()).layer(pong!());
+
+        assert_eq!(svc.clone().oneshot("user123".into()).await.unwrap(), "pong");
+        assert_eq!(svc.clone().oneshot("user123".into()).await.unwrap(), "pong");
+        assert_eq!(
+            svc.oneshot("user123".into()).await.unwrap(),
+            "key:fallback 60"
+        );
+        assert_eq!(redis.keys(), ["ip", "key", "key:fallback"]);
+    }
+
+    #[tokio::test]
+    async fn post_charges_each_of_the_rules() {
+        let policies = [
+            Policy::from_tokens_per_minute(10).max_burst(9),
+            Policy::from_tokens_per_minute(100).max_burst(99),
+        ];
+        let rules = Many(vec![
+            Rule::new("key", policies[0]),
+            Rule::new("ip", policies[1]),
+        ]);
+        let mut redis = LocalGcra::new();
+        let config = RateLimitConfig::new(rules, blocked).post_charge(|_: &String| 2);
+        let svc = RateLimitLayer::new(config, redis.clone()).layer(pong!());
+        assert_eq!(svc.oneshot("user123".into()).await.unwrap(), "pong");
+
+        for (key, policy, remaining) in [("key", policies[0], 7), ("ip", policies[1], 97)] {
+            let peek = Rule::new(key, policy.apply_tokens(0));
+            let verdict = crate::rate_limit_check(&mut redis, &peek).await.unwrap();
+            assert!(
+                matches!(verdict, redis_cell_rs::Verdict::Allowed(details) if details.remaining == remaining)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_each_of_the_rules_locally() {
+        let rules = Many(vec![
+            Rule::new("key", Policy::from_tokens_per_minute(100).max_burst(99)),
+            Rule::new("ip", Policy::from_tokens_per_minute(100).max_burst(99)),
+        ]);
+        let redis = LocalGcra::new();
+        let config = RateLimitConfig::new(rules, blocked).local_counter(5);
+        let svc = RateLimitLayer::new(config, redis.clone()).layer(pong!());
+
+        for _ in 0..3 {
+            assert_eq!(svc.clone().oneshot("user123".into()).await.unwrap(), "pong");
+        }
+        // only the first request has gone to Redis
+        assert_eq!(redis.calls(), 2);
+    }
+
+    #[derive(Clone)]
+    struct Tiers;
+
+    impl AsyncProvideRule<String> for Tiers {
+        async fn provide<'a>(&'a self, user: &'a String) -> ProvideRuleResult<'a> {
+            tokio::task::yield_now().await;
+            match user.as_str() {
+                "alice" => Ok(Some(Rule::new(
+                    user.as_str(),
+                    Policy::from_tokens_per_second(10),
+                ))),
+                _ => Err("unknown user".into()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn waits_for_an_async_provider() {
+        let config = RateLimitConfig::new(Tiers, |err: Error, _: &String| match err {
+            Error::ProvideRule(_) => "unauthorized".to_string(),
+            err => err.to_string(),
+        });
+        let svc = RateLimitLayer::new(config, MockConnection::allowed(10, 9)).layer(pong!());
+
+        assert_eq!(svc.clone().oneshot("alice".into()).await.unwrap(), "pong");
+        assert_eq!(svc.oneshot("mallory".into()).await.unwrap(), "unauthorized");
+    }
+
+    #[tokio::test]
+    async fn reports_the_retry_after_duration_unclamped() {
+        let rule = Rule::new("user123", Policy::from_tokens_per_second(10)).max_retry_after(1);
+        let config = RateLimitConfig::new(Fixed(rule), |err: Error, _: &String| match err {
+            Error::RateLimit(details) => {
+                Err((details.retry_after(), details.retry_after_duration()))
+            }
+            _ => Err((0, Duration::ZERO)),
+        });
+        let svc = RateLimitLayer::new(config, MockConnection::blocked(10, 5)).layer(service_fn(
+            |_req: String| async { Ok::<_, Infallible>(Ok("pong")) },
+        ));
+
+        let resp = svc.oneshot("user123".into()).await.unwrap();
+        assert_eq!(resp, Err((1, Duration::from_secs(5))));
+    }
+
+    #[tokio::test]
+    async fn exposes_the_reply_to_the_success_handler() {
+        let rule = Rule::new("user123", Policy::from_tokens_per_second(10));
+        let config = RateLimitConfig::new(Fixed(rule), |err: Error, _: &String| err.to_string())
+            .on_success(|details, resp: &mut String| {
+                *resp = format!(
+                    "limit={} remaining={} reset={}",
+                    details.limit(),
+                    details.remaining(),
+                    details.reset_after(),
+                );
+            });
+        let connection = MockConnection::allowed(10, 7).reset_after(3);
+        let svc = RateLimitLayer::new(config, connection).layer(pong!());
+
+        let resp = svc.oneshot("user123".into()).await.unwrap();
+        assert_eq!(resp, "limit=10 remaining=7 reset=3");
+    }
+}
@@ -1,17 +1,76 @@
 use crate::config;
-use crate::error::Error;
-use crate::rule;
-use redis::{FromRedisValue, aio::ConnectionLike};
+use crate::connection::LazyConnection;
+use crate::error::{Error, ProvideRuleError};
+use crate::limiter::{CellThrottle, Limiter};
+use crate::policy::PolicyExt as _;
+use crate::rule::{self, key_into_owned};
+use redis::aio::ConnectionLike;
 pub use redis_cell_rs as redis_cell;
+use std::convert::Infallible;
+use std::fmt::Display;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 use std::{pin::Pin, sync::Arc};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
-pub struct RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, C> {
+/// The future returned by the rate-limiting services.
+///
+/// The future is boxed and `Send`, so that the services can be used with
+/// multi-threaded runtimes and frameworks like `axum`. This requires the
+/// inner service (as well as its future, response, and error) and the rule
+/// provider to be `Send` too, and so a service whose future is not `Send`,
+/// e.g. because it holds an [`Rc`](std::rc::Rc) across an `.await`, cannot
+/// be wrapped:
+///
+///```compile_fail
+/// # use axum::http::{Request, Response};
+/// # use redis::aio::ConnectionManager;
+/// # use std::convert::Infallible;
+/// # use std::rc::Rc;
+/// # use tower::{Layer as _, Service as _, service_fn};
+/// # use tower_redis_cell::{Error, ProvideRule, ProvideRuleResult, RateLimitConfig, RateLimitLayer};
+/// #
+/// # #[derive(Clone)]
+/// # struct Unruled;
+/// # impl<B> ProvideRule<Request<B>> for Unruled {
+/// #     fn provide<'a>(&self, _: &'a Request<B>) -> ProvideRuleResult<'a> { Ok(None) }
+/// # }
+/// #
+/// # fn layer(connection: ConnectionManager) {
+/// let config = RateLimitConfig::new(Unruled, |_err: Error, _req: &Request<()>| Response::new(()));
+/// let mut svc = RateLimitLayer::new(config, connection).layer(service_fn(|_req| async {
+///     let not_send = Rc::new(());
+///     std::future::ready(()).await;
+///     drop(not_send);
+///     Ok::<_, Infallible>(Response::new(()))
+/// }));
+///
+/// // error: future cannot be sent between threads safely
+/// let _ = svc.call(Request::new(()));
+/// # }
+///```
+///
+/// Wrapping such a service in a [`Buffer`](https://docs.rs/tower/latest/tower/buffer/struct.Buffer.html) (which drives it
+/// on a task of its own) is one way out.
+pub type ResponseFuture<Resp, Err> = Pin<Box<dyn Future<Output = Result<Resp, Err>> + Send>>;
+
+/// Rate-limiting middleware, see [`RateLimitLayer`].
+///
+/// The inner service is cloned on every call, blocked requests included: the
+/// service readied in `poll_ready` is the one to be called (some, like a
+/// `ConcurrencyLimit`, hold a reservation made there), and so a clone takes its
+/// place before the verdict is known. An inner service which is expensive to
+/// clone is best put behind a [`Buffer`](https://docs.rs/tower/latest/tower/buffer/struct.Buffer.html),
+/// whose clones are cheap. The same holds for the pooled services.
+pub struct RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, C, E = Infallible> {
     inner: S,
-    config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy>>,
+    config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>,
     connection: C,
+    in_flight: InFlight,
 }
 
-impl<S, PR, ReqTy, RespTy, IntoRespTy, C> Clone for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, C>
+impl<S, PR, ReqTy, RespTy, IntoRespTy, C, E> Clone
+    for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, C, E>
 where
     S: Clone,
     C: Clone,
@@ -21,31 +80,33 @@ where
             inner: self.inner.clone(),
             config: Arc::clone(&self.config),
             connection: self.connection.clone(),
+            in_flight: self.in_flight.clone(),
         }
     }
 }
 
-impl<S, PR, ReqTy, RespTy, IntoRespTy, C> RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, C> {
+impl<S, PR, ReqTy, RespTy, IntoRespTy, C, E> RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, C, E> {
     pub fn new<RLC>(inner: S, config: RLC, connection: C) -> Self
     where
-        RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy>>>,
+        RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>>,
     {
         RateLimit {
             inner,
             config: config.into(),
             connection,
+            in_flight: InFlight::default(),
         }
     }
 }
 
-impl<S, PR, ReqTy, RespTy, IntoRespTy, C> tower::Service<ReqTy>
-    for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, C>
+impl<S, PR, ReqTy, RespTy, IntoRespTy, C, E> tower::Service<ReqTy>
+    for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, C, E>
 where
-    S: tower::Service<ReqTy, Response = RespTy> + Clone + Send + 'static,
+    S: tower::Service<ReqTy, Response = RespTy, Error = E> + Clone + Send + 'static,
     S::Future: Send + 'static,
-    S::Error: Send,
+    E: Send + 'static,
     S::Response: Send,
-    PR: rule::ProvideRule<ReqTy> + Clone + Send + Sync + 'static,
+    PR: rule::AsyncProvideRule<ReqTy> + Clone + Send + Sync + 'static,
     ReqTy: Send + 'static,
     IntoRespTy: Into<RespTy> + 'static,
     RespTy: 'static,
@@ -53,102 +114,387 @@ where
 {
     type Response = S::Response;
     type Error = S::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+    type Future = ResponseFuture<S::Response, S::Error>;
 
     fn poll_ready(
         &mut self,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Result<(), Self::Error>> {
+        std::task::ready!(
+            self.in_flight
+                .poll_acquire(self.config.in_flight.as_ref(), cx)
+        );
         self.inner.poll_ready(cx)
     }
 
     fn call(&mut self, req: ReqTy) -> Self::Future {
-        let mut connection = self.connection.clone();
-        let mut inner = self.inner.clone();
+        let connection = self.connection.clone();
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
         let config = self.config.clone();
+        let connect = move || std::future::ready(Ok(connection.clone()));
+        Box::pin(self.in_flight.guard(check(config, inner, req, connect)))
+    }
+}
 
-        Box::pin(async move {
-            let maybe_rule = match config.rule_provider.provide(&req) {
-                Ok(rule) => rule,
-                Err(e) => {
-                    let config::OnError::Sync(ref h) = config.on_error;
-                    let resp = h(Error::ProvideRule(e), &req);
-                    return Ok(resp.into());
-                }
-            };
-            let rule = match maybe_rule {
-                Some(rule) => rule,
-                None => {
-                    return inner
-                        .call(req)
-                        .await
-                        .map(|mut resp| match &config.on_unruled {
-                            config::OnUnruled::Noop => resp,
-                            config::OnUnruled::Sync(h) => {
-                                h(&mut resp);
-                                resp
-                            }
-                        });
-                }
-            };
-            let policy = rule.policy;
-            let cmd = redis_cell::Cmd::new(&rule.key, &policy);
-
-            let redis_response = match connection.req_packed_command(&cmd.into()).await {
-                Ok(res) => res,
-                Err(redis_err) => {
-                    let config::OnError::Sync(ref h) = config.on_error;
-                    let handled = h(redis_err.into(), &req);
-                    return Ok(handled.into());
-                }
-            };
-            let redis_cell_verdict = match redis_cell::Verdict::from_redis_value(&redis_response) {
+/// Permit to one of the calls allowed in flight at once, acquired by the
+/// service in `poll_ready`, see [`RateLimitConfig::max_in_flight`](config::RateLimitConfig::max_in_flight).
+#[derive(Default)]
+pub(crate) struct InFlight {
+    acquiring: Option<Pin<Box<dyn Future<Output = OwnedSemaphorePermit> + Send + Sync>>>,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl InFlight {
+    pub(crate) fn poll_acquire(
+        &mut self,
+        semaphore: Option<&Arc<Semaphore>>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<()> {
+        let Some(semaphore) = semaphore else {
+            return std::task::Poll::Ready(());
+        };
+        if self.permit.is_some() {
+            return std::task::Poll::Ready(());
+        }
+        let acquiring = self.acquiring.get_or_insert_with(|| {
+            let semaphore = Arc::clone(semaphore);
+            Box::pin(async move { semaphore.acquire_owned().await.expect("never closed") })
+        });
+        let permit = std::task::ready!(acquiring.as_mut().poll(cx));
+        self.acquiring = None;
+        self.permit = Some(permit);
+        std::task::Poll::Ready(())
+    }
+
+    /// Hold the permit acquired in `poll_ready` until `future` completes.
+    pub(crate) fn guard<F>(&mut self, future: F) -> impl Future<Output = F::Output> + use<F>
+    where
+        F: Future,
+    {
+        let permit = self.permit.take();
+        async move {
+            let output = future.await;
+            drop(permit);
+            output
+        }
+    }
+}
+
+// permits are not shared between the clones, each acquires its own
+impl Clone for InFlight {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// Slots mirroring the capacity of a pool, one of which a pooled service
+/// acquires in `poll_ready`, see [`RateLimitConfig::pool_readiness`](config::RateLimitConfig::pool_readiness).
+#[cfg(any(feature = "deadpool", feature = "bb8"))]
+fn pool_slots(enabled: bool, max_size: usize) -> Option<Arc<Semaphore>> {
+    enabled.then(|| Arc::new(Semaphore::new(max_size)))
+}
+
+/// The rate-limiting logic shared by all the services in this crate, which
+/// only differ in how they procure a connection.
+pub(crate) async fn check<S, PR, ReqTy, RespTy, IntoRespTy, E, F, Fut, Conn>(
+    config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>,
+    mut inner: S,
+    req: ReqTy,
+    connect: F,
+) -> Result<RespTy, S::Error>
+where
+    S: tower::Service<ReqTy, Response = RespTy, Error = E>,
+    PR: rule::AsyncProvideRule<ReqTy>,
+    IntoRespTy: Into<RespTy>,
+    F: Fn() -> Fut,
+    Fut: Future<Output = Result<Conn, Error<'static>>>,
+    Conn: ConnectionLike,
+{
+    let rules = match rule::AsyncProvideRule::provide_many(&config.rule_provider, &req).await {
+        Ok(rules) if rules.is_empty() => {
+            return inner.call(req).await.map(|mut resp| {
+                config.handle_unruled(&mut resp);
+                resp
+            });
+        }
+        Ok(rules) => rules,
+        Err(e) => return config.handle_error(Error::ProvideRule(e), &req),
+    };
+    let rules = match rules
+        .into_iter()
+        .map(|rule| config.resolved(rule))
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(rules) => rules,
+        Err(e) => return config.handle_error(Error::ProvideRule(e), &req),
+    };
+    #[cfg(feature = "tracing")]
+    let span = config.span();
+    let started = Instant::now();
+    let fail_open = config.fail_mode == config::FailMode::Allow;
+    let mut checks: Vec<_> = rules
+        .into_iter()
+        .map(|rule| Checked::new(config.prepared(rule, &req)))
+        .collect();
+    #[cfg(feature = "local-cache")]
+    if let Some(ref cache) = config.blocked_cache {
+        for checked in checks
+            .iter_mut()
+            .filter(|checked| checked.rule.fallback.is_none())
+        {
+            checked.verdict = cache
+                .get(&checked.rule.key.to_string())
+                .map(redis_cell::Verdict::Blocked);
+        }
+    }
+    // a block known locally spares the connection as well as the round trip
+    let mut connection = if checks.iter().any(|checked| checked.verdict.is_some()) {
+        None
+    } else {
+        for checked in &mut checks {
+            count_locally(&config, checked);
+        }
+        let mut connection = match connect().await {
+            Ok(connection) => connection,
+            Err(_) if fail_open => return pass_through(&config, inner, req).await,
+            Err(e) => return config.handle_error(e, &req),
+        };
+        let unsettled: Vec<_> = (0..checks.len())
+            .filter(|&i| checks[i].verdict.is_none())
+            .collect();
+        for &i in &unsettled {
+            if let Err(e) = checks[i].policy.validate() {
+                return config.handle_error(Error::Policy(e), &req);
+            }
+        }
+        // the commands for several rules go in one round trip
+        let verdicts = match *unsettled.as_slice() {
+            [] => Ok(Vec::new()),
+            [i] => {
+                let checked = &checks[i];
+                throttle(&config, &mut connection, &checked.rule.key, &checked.policy)
+                    .await
+                    .map(|verdict| vec![verdict])
+            }
+            _ => {
+                let cells: Vec<_> = unsettled
+                    .iter()
+                    .map(|&i| (&checks[i].rule.key, checks[i].policy))
+                    .collect();
+                throttle_many(&config, &mut connection, &cells).await
+            }
+        };
+        let verdicts = match verdicts {
+            Ok(verdicts) => verdicts,
+            Err(_) if fail_open => return pass_through(&config, inner, req).await,
+            Err(e) => return config.handle_error(e, &req),
+        };
+        for (i, verdict) in unsettled.into_iter().zip(verdicts) {
+            let verdict = match settle(&config, &mut connection, &mut checks[i], verdict).await {
                 Ok(verdict) => verdict,
-                Err(redis_err) => {
-                    let config::OnError::Sync(ref h) = config.on_error;
-                    let handled = h(Error::Redis(redis_err), &req);
-                    return Ok(handled.into());
-                }
+                Err(e @ Error::Policy(_)) => return config.handle_error(e, &req),
+                Err(_) if fail_open => return pass_through(&config, inner, req).await,
+                Err(e) => return config.handle_error(e, &req),
             };
-            match redis_cell_verdict {
-                redis_cell::Verdict::Blocked(details) => {
-                    let config::OnError::Sync(ref h) = config.on_error;
-                    let handled = h(
-                        Error::RateLimit(rule::RequestBlockedDetails { rule, details }),
-                        &req,
-                    );
-                    Ok(handled.into())
-                }
-                redis_cell::Verdict::Allowed(details) => {
-                    let policy = rule.policy;
-                    let resource = rule.resource;
-                    inner
-                        .call(req)
-                        .await
-                        .map(|mut resp| match &config.on_success {
-                            config::OnSuccess::Noop => resp,
-                            config::OnSuccess::Sync(h) => {
-                                let details = rule::RequestAllowedDetails {
-                                    details,
-                                    policy,
-                                    resource,
-                                };
-                                h(details, &mut resp);
-                                resp
-                            }
-                        })
+            checks[i].verdict = Some(verdict);
+        }
+        Some(connection)
+    };
+    let decisive = decisive(&checks);
+    // the cells to charge after the fact, should the request be allowed
+    let mut cells = Vec::new();
+    let mut decided = None;
+    for (i, checked) in checks.into_iter().enumerate() {
+        let (rule, Some(verdict)) = (checked.rule, checked.verdict) else {
+            continue;
+        };
+        if config.post_charge.is_some() {
+            cells.push((rule::key_into_owned(rule.key.clone()), rule.policy));
+        }
+        if i == decisive {
+            decided = Some((rule, verdict));
+            continue;
+        }
+        config.record(&rule.key, &verdict);
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_verdict(&rule, &verdict);
+        if let Some(ref decisions) = config.decisions {
+            let redacted_key = config.render_key(&rule.key.to_string());
+            decisions.send(&rule, redacted_key, &verdict, started.elapsed());
+        }
+    }
+    let (rule, verdict) = decided.expect("decisive verdict");
+    config.record(&rule.key, &verdict);
+    #[cfg(feature = "tracing")]
+    config.trace(&span, &rule, &verdict);
+    #[cfg(feature = "metrics")]
+    crate::metrics::record_verdict(&rule, &verdict);
+    if let Some(ref decisions) = config.decisions {
+        let redacted_key = config.render_key(&rule.key.to_string());
+        decisions.send(&rule, redacted_key, &verdict, started.elapsed());
+    }
+    if let Some(ref on_verdict) = config.on_verdict {
+        on_verdict(&verdict, &req);
+    }
+    match verdict {
+        redis_cell::Verdict::Blocked(_) if config.mode == config::Mode::Shadow => {
+            config.would_block_total.fetch_add(1, Ordering::Relaxed);
+            #[cfg(feature = "metrics")]
+            crate::metrics::record_would_block(&rule);
+            pass_through(&config, inner, req).await
+        }
+        redis_cell::Verdict::Blocked(details) => {
+            let rule = config.retry_after_bounds(rule);
+            let err = Error::RateLimit(rule::RequestBlockedDetails { rule, details });
+            config.handle_error(err, &req)
+        }
+        redis_cell::Verdict::Allowed(details) => {
+            let details = rule::RequestAllowedDetails {
+                details,
+                policy: rule.policy,
+                resource: rule.resource,
+            };
+            let skip_success_handler = rule.skip_success_handler;
+            let mut resp = inner.call(req).await?;
+            // an allowed request has always been checked over the connection
+            if let (Some(cost), Some(connection)) = (&config.post_charge, &mut connection) {
+                let tokens = cost(&resp);
+                for (key, policy) in cells {
+                    charge(&config, connection, &key, policy, tokens).await;
                 }
             }
-        })
+            if skip_success_handler {
+                config.handle_response(&mut resp);
+            } else {
+                config.handle_success(details, &mut resp);
+            }
+            Ok(resp)
+        }
+    }
+}
+
+/// A rule on its way to a verdict, see [`check`].
+struct Checked<'a> {
+    rule: rule::Rule<'a>,
+    /// The policy to throttle with, which also charges for the requests
+    /// allowed locally since the last sync.
+    policy: redis_cell::Policy,
+    /// The requests allowed locally since the last sync, yet to be charged.
+    pending: usize,
+    verdict: Option<redis_cell::Verdict>,
+}
+
+impl<'a> Checked<'a> {
+    fn new(rule: rule::Rule<'a>) -> Self {
+        Checked {
+            policy: rule.policy,
+            rule,
+            pending: 0,
+            verdict: None,
+        }
+    }
+}
+
+/// Allow the request for the rule without Redis, should the local counter
+/// permit, see [`RateLimitConfig::local_counter`](config::RateLimitConfig::local_counter).
+fn count_locally<PR, ReqTy, RespTy, IntoRespTy, E>(
+    config: &config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>,
+    checked: &mut Checked<'_>,
+) {
+    let Some(ref counter) = config.local_counter else {
+        return;
+    };
+    let key = checked.rule.key.to_string();
+    let policy = checked.policy;
+    match counter.try_allow(&key, policy.apply) {
+        Some(details) => checked.verdict = Some(redis_cell::Verdict::Allowed(details)),
+        // charge the requests allowed locally along with this one
+        None => {
+            checked.pending = counter.take(&key);
+            checked.policy = policy.apply_tokens(policy.apply.saturating_mul(checked.pending + 1));
+        }
     }
 }
 
-pub struct RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, C> {
-    config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy>>,
+/// Follow up on the verdict Redis has reported for the rule: sync the local
+/// counter, cache a block, and give the fallback policy a try.
+async fn settle<PR, ReqTy, RespTy, IntoRespTy, E, Conn>(
+    config: &config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>,
+    connection: &mut Conn,
+    checked: &mut Checked<'_>,
+    verdict: redis_cell::Verdict,
+) -> Result<redis_cell::Verdict, Error<'static>>
+where
+    Conn: ConnectionLike,
+{
+    let rule = &mut checked.rule;
+    if let (Some(counter), redis_cell::Verdict::Allowed(details)) =
+        (&config.local_counter, &verdict)
+    {
+        counter.sync(rule.key.to_string(), details.clone());
+    }
+    // a blocked batch takes no tokens, yet the requests allowed locally have
+    // been let through and still owe theirs
+    if checked.pending > 0 && matches!(verdict, redis_cell::Verdict::Blocked(_)) {
+        let owed = rule.policy.apply.saturating_mul(checked.pending);
+        charge(config, connection, &rule.key, rule.policy, owed).await;
+    }
+    #[cfg(feature = "local-cache")]
+    if let (Some(cache), None, redis_cell::Verdict::Blocked(details)) =
+        (&config.blocked_cache, rule.fallback, &verdict)
+    {
+        cache.block(rule.key.to_string(), details.clone());
+    }
+    match (verdict, rule.fallback) {
+        // give the request a second chance against the fallback's own cell
+        (redis_cell::Verdict::Blocked(_), Some(fallback)) => {
+            fallback.validate().map_err(Error::Policy)?;
+            rule.key = redis_cell::Key::String(format!("{}:fallback", rule.key));
+            rule.policy = fallback;
+            throttle(config, connection, &rule.key, &fallback).await
+        }
+        (verdict, _) => Ok(verdict),
+    }
+}
+
+/// The rule whose verdict decides on the request: the one blocking for the
+/// longest, so that the client does not retry before all of them would let
+/// it through, or else the one with the fewest tokens left.
+fn decisive(checks: &[Checked<'_>]) -> usize {
+    let verdicts = || {
+        checks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, checked)| Some((i, checked.verdict.as_ref()?)))
+    };
+    let blocking = verdicts()
+        .filter_map(|(i, verdict)| match verdict {
+            redis_cell::Verdict::Blocked(details) => Some((i, details.retry_after)),
+            redis_cell::Verdict::Allowed(_) => None,
+        })
+        // the first one among those blocking for as long
+        .min_by_key(|&(_, retry_after)| std::cmp::Reverse(retry_after))
+        .map(|(i, _)| i);
+    blocking
+        .or_else(|| {
+            verdicts()
+                .min_by_key(|(_, verdict)| match verdict {
+                    redis_cell::Verdict::Allowed(details) => details.remaining,
+                    redis_cell::Verdict::Blocked(details) => details.remaining,
+                })
+                .map(|(i, _)| i)
+        })
+        .expect("at least one verdict")
+}
+
+pub struct RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, C, E = Infallible> {
+    config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>,
     connection: C,
 }
 
-impl<PR, ReqTy, RespTy, IntoRespTy, C> Clone for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, C>
+impl<PR, ReqTy, RespTy, IntoRespTy, C, E> Clone
+    for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, C, E>
 where
     C: Clone,
 {
@@ -160,47 +506,409 @@ where
     }
 }
 
-impl<S, PR, ReqTy, RespTy, IntoRespTy, C> tower::Layer<S>
-    for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, C>
+impl<S, PR, ReqTy, RespTy, IntoRespTy, C, E> tower::Layer<S>
+    for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, C, E>
 where
+    S: Clone,
     C: Clone,
 {
-    type Service = RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, C>;
+    type Service = RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, C, E>;
     fn layer(&self, inner: S) -> Self::Service {
         RateLimit::new(inner, Arc::clone(&self.config), self.connection.clone())
     }
 }
 
-impl<PR, ReqTy, RespTy, IntoRespTy, C> RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, C> {
+impl<PR, ReqTy, RespTy, IntoRespTy, C, E> RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, C, E> {
     pub fn new<RLC>(config: RLC, connection: C) -> Self
     where
-        RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy>>>,
+        RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>>,
     {
-        RateLimitLayer {
-            config: config.into(),
-            connection,
+        let config: Arc<config::RateLimitConfig<_, _, _, _, _>> = config.into();
+        #[cfg(feature = "tracing")]
+        config.warn_misconfigured();
+        RateLimitLayer { config, connection }
+    }
+}
+
+impl<PR, ReqTy, RespTy, IntoRespTy, C, E>
+    RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, LazyConnection<C>, E>
+where
+    C: Clone + Send + Sync + 'static,
+{
+    /// Create a layer with a connection that is yet to be established.
+    ///
+    /// The `connection` future is resolved when the first request hits the
+    /// service, and the connection is then reused for all the subsequent
+    /// requests. An error establishing the connection is reported to the
+    /// error handler as [`Error::Redis`].
+    ///
+    ///```no_run
+    /// # use axum::http::{Request, StatusCode};
+    /// # use axum::response::IntoResponse;
+    /// # use tower_redis_cell::{ProvideRule, ProvideRuleResult, RateLimitConfig};
+    /// # #[derive(Clone)]
+    /// # struct RuleProvider;
+    /// # impl<T> ProvideRule<Request<T>> for RuleProvider {
+    /// #    fn provide<'a>(&self, req: &'a Request<T>) -> ProvideRuleResult<'a> { todo!() }
+    /// # }
+    /// use axum::{Router, routing::get};
+    /// use tower_redis_cell::RateLimitLayer;
+    ///
+    /// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+    /// let config = RateLimitConfig::new(RuleProvider, |_err, _req| {
+    ///     StatusCode::TOO_MANY_REQUESTS.into_response()
+    /// });
+    /// let layer = RateLimitLayer::from_future(config, redis::aio::ConnectionManager::new(client));
+    /// let app: Router = Router::new()
+    ///     .route("/", get(|| async { "Hello, World!" }))
+    ///     .layer(layer);
+    ///```
+    pub fn from_future<RLC, F, CE>(config: RLC, connection: F) -> Self
+    where
+        RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>>,
+        F: Future<Output = Result<C, CE>> + Send + 'static,
+        CE: Display,
+    {
+        RateLimitLayer::new(config, LazyConnection::new(connection))
+    }
+}
+
+/// Create a [`RateLimitLayer`] in one go.
+///
+/// This is a shortcut for [`RateLimitConfig::new`](config::RateLimitConfig::new)
+/// followed by [`RateLimitLayer::new`] for when you only need a rule provider
+/// and an error handler. Use the config builder for anything more advanced.
+///
+///```no_run
+/// # use axum::http::Request;
+/// # use tower_redis_cell::{ProvideRule, ProvideRuleResult};
+/// # #[derive(Clone)]
+/// # struct RuleProvider;
+/// # impl<T> ProvideRule<Request<T>> for RuleProvider {
+/// #    fn provide<'a>(&self, req: &'a Request<T>) -> ProvideRuleResult<'a> { todo!() }
+/// # }
+/// use axum::http::StatusCode;
+/// use axum::response::IntoResponse;
+/// use axum::{Router, routing::get};
+///
+/// # async fn run() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let connection = redis::aio::ConnectionManager::new(client).await.unwrap();
+///
+/// let layer = tower_redis_cell::rate_limit(
+///     RuleProvider,
+///     |_err, _req| StatusCode::TOO_MANY_REQUESTS.into_response(),
+///     connection,
+/// );
+/// let app: Router = Router::new()
+///     .route("/", get(|| async { "Hello, World!" }))
+///     .layer(layer);
+/// # }
+///```
+pub fn rate_limit<PR, EH, ReqTy, RespTy, IntoRespTy, C, E>(
+    rule_provider: PR,
+    error_handler: EH,
+    connection: C,
+) -> RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, C, E>
+where
+    EH: Fn(Error, &ReqTy) -> IntoRespTy + Send + Sync + 'static,
+{
+    let config = config::RateLimitConfig::new(rule_provider, error_handler);
+    RateLimitLayer::new(config, connection)
+}
+
+/// Issue the rate-limiting command for `key` and decode the reply.
+async fn throttle<PR, ReqTy, RespTy, IntoRespTy, E, Conn>(
+    config: &config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>,
+    connection: &mut Conn,
+    key: &redis_cell::Key<'_>,
+    policy: &redis_cell::Policy,
+) -> Result<redis_cell::Verdict, Error<'static>>
+where
+    Conn: ConnectionLike,
+{
+    let cmd = config.limiter.command(key, policy);
+    let _permit = redis_permit(config).await?;
+    let mut attempt = 0;
+    let reply = loop {
+        #[cfg(feature = "metrics")]
+        let sent = Instant::now();
+        let reply = connection.req_packed_command(&cmd).await;
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_duration(sent.elapsed());
+        match reply {
+            Err(ref err) if attempt < config.retries && retryable(err) => {
+                attempt += 1;
+                tokio::time::sleep(config::RETRY_DELAY).await;
+            }
+            reply => break reply,
+        }
+    };
+    let verdict = reply.and_then(|reply| config.limiter.verdict(&reply));
+    verdict.map_err(|source| Error::Redis {
+        source,
+        command: Some(config.command_context(&cmd)),
+    })
+}
+
+/// Wait for room to issue a command, see [`RateLimitConfig::max_concurrent_redis`](config::RateLimitConfig::max_concurrent_redis).
+async fn redis_permit<PR, ReqTy, RespTy, IntoRespTy, E>(
+    config: &config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>,
+) -> Result<Option<tokio::sync::SemaphorePermit<'_>>, Error<'static>> {
+    let Some(ref permits) = config.redis_permits else {
+        return Ok(None);
+    };
+    match tokio::time::timeout(config.redis_permit_wait, permits.acquire()).await {
+        Ok(permit) => Ok(Some(permit.expect("never closed"))),
+        Err(_) => Err(Error::Overloaded),
+    }
+}
+
+/// Charge `tokens` after the fact, draining the bucket should they not fit,
+/// see [`RateLimitConfig::post_charge`](config::RateLimitConfig::post_charge).
+async fn charge<PR, ReqTy, RespTy, IntoRespTy, E, Conn>(
+    config: &config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>,
+    connection: &mut Conn,
+    key: &redis_cell::Key<'_>,
+    policy: redis_cell::Policy,
+    tokens: usize,
+) where
+    Conn: ConnectionLike,
+{
+    if tokens == 0 {
+        return;
+    }
+    let verdict = throttle(config, connection, key, &policy.apply_tokens(tokens)).await;
+    if let Ok(redis_cell::Verdict::Blocked(details)) = verdict {
+        if details.remaining > 0 {
+            let policy = policy.apply_tokens(details.remaining);
+            let _ = throttle(config, connection, key, &policy).await;
+        }
+    }
+}
+
+/// Issue the rate-limiting commands for all the `rules` in one round trip and
+/// decode the replies.
+async fn throttle_many<PR, ReqTy, RespTy, IntoRespTy, E, Conn>(
+    config: &config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>,
+    connection: &mut Conn,
+    cells: &[(&redis_cell::Key<'_>, redis_cell::Policy)],
+) -> Result<Vec<redis_cell::Verdict>, Error<'static>>
+where
+    Conn: ConnectionLike,
+{
+    let _permit = redis_permit(config).await?;
+    let mut attempt = 0;
+    loop {
+        #[cfg(feature = "metrics")]
+        let sent = Instant::now();
+        let verdicts = pipelined(connection, &*config.limiter, cells, |key| {
+            config.render_key(key)
+        })
+        .await;
+        #[cfg(feature = "metrics")]
+        crate::metrics::record_duration(sent.elapsed());
+        match verdicts {
+            Err(Error::Redis { ref source, .. })
+                if attempt < config.retries && retryable(source) =>
+            {
+                attempt += 1;
+                tokio::time::sleep(config::RETRY_DELAY).await;
+            }
+            verdicts => return verdicts,
         }
     }
 }
 
+/// Whether the error is down to the connection rather than to the command,
+/// and so the command is worth retrying, see [`RateLimitConfig::retries`](config::RateLimitConfig::retries).
+fn retryable(err: &redis::RedisError) -> bool {
+    err.is_io_error()
+        || err.is_connection_dropped()
+        || err.is_connection_refusal()
+        || err.is_timeout()
+}
+
+/// Pipeline the commands for the `rules`, attributing a reply which cannot be
+/// decoded (or is an error) to the command, and so the key, it belongs to.
+async fn pipelined<C, L, F>(
+    connection: &mut C,
+    limiter: &L,
+    cells: &[(&redis_cell::Key<'_>, redis_cell::Policy)],
+    render_key: F,
+) -> Result<Vec<redis_cell::Verdict>, Error<'static>>
+where
+    C: ConnectionLike,
+    L: Limiter + ?Sized,
+    F: Fn(&str) -> Option<String>,
+{
+    let mut pipe = redis::pipe();
+    for (key, policy) in cells {
+        pipe.add_command(limiter.command(key, policy));
+    }
+    let replies = connection
+        .req_packed_commands(&pipe, 0, cells.len())
+        .await
+        .map_err(|source| Error::Redis {
+            source,
+            command: None,
+        })?;
+    let mut verdicts = Vec::with_capacity(cells.len());
+    for (reply, cmd) in replies.into_iter().zip(pipe.cmd_iter()) {
+        let verdict = reply
+            .extract_error()
+            .and_then(|reply| limiter.verdict(&reply))
+            .map_err(|source| Error::Redis {
+                source,
+                command: Some(config::command_context(cmd, &render_key)),
+            })?;
+        verdicts.push(verdict);
+    }
+    Ok(verdicts)
+}
+
+/// Pass the request through to the inner service without a verdict to report,
+/// i.e. with only the `on_response` hook applied.
+async fn pass_through<S, PR, ReqTy, RespTy, IntoRespTy, E>(
+    config: &config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>,
+    mut inner: S,
+    req: ReqTy,
+) -> Result<RespTy, S::Error>
+where
+    S: tower::Service<ReqTy, Response = RespTy, Error = E>,
+{
+    let mut resp = inner.call(req).await?;
+    config.handle_response(&mut resp);
+    Ok(resp)
+}
+
+/// Check the rule against Redis Cell outside of any service.
+///
+/// This is the rate-limiting core without the Tower machinery, for use with
+/// frameworks which are not built on Tower (e.g. actix), or anywhere else a
+/// one-off check is needed. The rule is checked with the default
+/// [`CellThrottle`](crate::CellThrottle) limiter, and the key is hashed in
+/// [`Error::Redis`] as per the default [`KeyLogPolicy`](crate::KeyLogPolicy).
+///
+///```no_run
+/// use tower_redis_cell::redis_cell::{Policy, Verdict};
+/// use tower_redis_cell::{Rule, rate_limit_check};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let mut connection = client.get_multiplexed_async_connection().await.unwrap();
+///
+/// let rule = Rule::new("user123", Policy::from_tokens_per_minute(10));
+/// match rate_limit_check(&mut connection, &rule).await.unwrap() {
+///     Verdict::Allowed(details) => println!("{} tokens left", details.remaining),
+///     Verdict::Blocked(details) => println!("retry in {}s", details.retry_after),
+/// }
+/// # }
+///```
+pub async fn rate_limit_check<C>(
+    connection: &mut C,
+    rule: &rule::Rule<'_>,
+) -> Result<redis_cell::Verdict, Error<'static>>
+where
+    C: ConnectionLike,
+{
+    unresolved(rule).map_err(Error::ProvideRule)?;
+    let policy = rule.charged_policy();
+    policy.validate().map_err(Error::Policy)?;
+    let limiter = CellThrottle::new();
+    let cmd = limiter.command(&rule.key, &policy);
+    let render_key = |key: &str| config::KeyLogPolicy::default().render(key);
+    let context = || Some(config::command_context(&cmd, render_key));
+    let reply = connection
+        .req_packed_command(&cmd)
+        .await
+        .map_err(|source| Error::Redis {
+            source,
+            command: context(),
+        })?;
+    limiter.verdict(&reply).map_err(|source| Error::Redis {
+        source,
+        command: context(),
+    })
+}
+
+/// Fail a rule whose policy is to be looked up by resource, as there are no
+/// policies to look it up in outside of the service.
+fn unresolved(rule: &rule::Rule<'_>) -> Result<(), ProvideRuleError<'static>> {
+    let Some(resource) = rule.unresolved else {
+        return Ok(());
+    };
+    let detail = format!("no policy registered for resource {:?}", resource);
+    Err(ProvideRuleError::new(
+        key_into_owned(rule.key.clone()),
+        detail,
+    ))
+}
+
+/// Check several rules against Redis Cell in one round trip, outside of any
+/// service, see [`rate_limit_check`].
+///
+/// The verdicts are in the order of the rules. Should a reply not be a valid
+/// verdict, the [`Error::Redis`] reported names the command, and so the
+/// (hashed) key, it was for.
+///
+///```no_run
+/// use tower_redis_cell::redis_cell::{Policy, Verdict};
+/// use tower_redis_cell::{Rule, rate_limit_check_many};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+/// let mut connection = client.get_multiplexed_async_connection().await.unwrap();
+///
+/// let rules = [
+///     Rule::new("global", Policy::from_tokens_per_second(1000)),
+///     Rule::new("user123", Policy::from_tokens_per_minute(10)),
+/// ];
+/// let verdicts = rate_limit_check_many(&mut connection, &rules).await.unwrap();
+/// let allowed = verdicts.iter().all(|verdict| matches!(verdict, Verdict::Allowed(_)));
+/// # }
+///```
+pub async fn rate_limit_check_many<C>(
+    connection: &mut C,
+    rules: &[rule::Rule<'_>],
+) -> Result<Vec<redis_cell::Verdict>, Error<'static>>
+where
+    C: ConnectionLike,
+{
+    let mut cells = Vec::with_capacity(rules.len());
+    for rule in rules {
+        unresolved(rule).map_err(Error::ProvideRule)?;
+        let policy = rule.charged_policy();
+        policy.validate().map_err(Error::Policy)?;
+        cells.push((&rule.key, policy));
+    }
+    let render_key = |key: &str| config::KeyLogPolicy::default().render(key);
+    pipelined(connection, &CellThrottle::new(), &cells, render_key).await
+}
+
 #[cfg(feature = "deadpool")]
 #[cfg_attr(docsrs, doc(cfg(feature = "deadpool")))]
 pub mod deadpool {
+    use super::{InFlight, ResponseFuture, check, pool_slots};
     use crate::config;
     use crate::error::Error;
     use crate::rule;
-    use redis::{FromRedisValue, aio::ConnectionLike};
-    pub use redis_cell_rs as redis_cell;
-    use redis_cell_rs::Verdict;
-    use std::{pin::Pin, sync::Arc};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use tokio::sync::Semaphore;
 
-    pub struct RateLimit<S, PR, ReqTy, RespTy, IntoRespTy> {
+    pub struct RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E = Infallible> {
         inner: S,
-        config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy>>,
+        config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>,
         pool: deadpool_redis::Pool,
+        in_flight: InFlight,
+        pool_slots: Option<Arc<Semaphore>>,
+        pool_slot: InFlight,
     }
 
-    impl<S, PR, ReqTy, RespTy, IntoRespTy> Clone for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy>
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> Clone for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E>
     where
         S: Clone,
     {
@@ -209,167 +917,795 @@ pub mod deadpool {
                 inner: self.inner.clone(),
                 config: Arc::clone(&self.config),
                 pool: self.pool.clone(),
+                in_flight: self.in_flight.clone(),
+                pool_slots: self.pool_slots.clone(),
+                pool_slot: self.pool_slot.clone(),
             }
         }
     }
 
-    impl<S, PR, ReqTy, RespTy, IntoRespTy> RateLimit<S, PR, ReqTy, RespTy, IntoRespTy> {
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E> {
         pub fn new<RLC>(inner: S, config: RLC, pool: deadpool_redis::Pool) -> Self
         where
-            RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy>>>,
+            RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>>,
         {
+            let config: Arc<config::RateLimitConfig<_, _, _, _, _>> = config.into();
+            let pool_slots = pool_slots(config.pool_readiness, pool.status().max_size);
             RateLimit {
                 inner,
-                config: config.into(),
+                config,
                 pool,
+                in_flight: InFlight::default(),
+                pool_slots,
+                pool_slot: InFlight::default(),
             }
         }
     }
 
-    impl<S, PR, ReqTy, RespTy, IntoRespTy> tower::Service<ReqTy>
-        for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy>
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> tower::Service<ReqTy>
+        for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E>
     where
-        S: tower::Service<ReqTy, Response = RespTy> + Clone + Send + 'static,
+        S: tower::Service<ReqTy, Response = RespTy, Error = E> + Clone + Send + 'static,
         S::Future: Send + 'static,
-        S::Error: Send,
+        E: Send + 'static,
         S::Response: Send,
-        PR: rule::ProvideRule<ReqTy> + Clone + Send + Sync + 'static,
+        PR: rule::AsyncProvideRule<ReqTy> + Clone + Send + Sync + 'static,
         ReqTy: Send + 'static,
         IntoRespTy: Into<RespTy> + 'static,
         RespTy: 'static,
     {
         type Response = S::Response;
         type Error = S::Error;
-        type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+        type Future = ResponseFuture<S::Response, S::Error>;
 
         fn poll_ready(
             &mut self,
             cx: &mut std::task::Context<'_>,
         ) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::ready!(self.pool_slot.poll_acquire(self.pool_slots.as_ref(), cx));
+            std::task::ready!(
+                self.in_flight
+                    .poll_acquire(self.config.in_flight.as_ref(), cx)
+            );
             self.inner.poll_ready(cx)
         }
 
         fn call(&mut self, req: ReqTy) -> Self::Future {
             let pool = self.pool.clone();
-            let mut inner = self.inner.clone();
+            let clone = self.inner.clone();
+            let inner = std::mem::replace(&mut self.inner, clone);
             let config = self.config.clone();
-
-            Box::pin(async move {
-                let maybe_rule = match config.rule_provider.provide(&req) {
-                    Ok(rule) => rule,
-                    Err(e) => {
-                        let config::OnError::Sync(ref h) = config.on_error;
-                        let resp = h(Error::ProvideRule(e), &req);
-                        return Ok(resp.into());
-                    }
-                };
-                let rule = match maybe_rule {
-                    Some(rule) => rule,
-                    None => {
-                        return inner
-                            .call(req)
-                            .await
-                            .map(|mut resp| match &config.on_unruled {
-                                config::OnUnruled::Noop => resp,
-                                config::OnUnruled::Sync(h) => {
-                                    h(&mut resp);
-                                    resp
-                                }
-                            });
-                    }
-                };
-                let policy = rule.policy;
-                let cmd = redis_cell::Cmd::new(&rule.key, &policy);
-
-                let mut connection = match pool.get().await {
-                    Ok(conn) => conn,
-                    Err(deadpool_err) => {
-                        let config::OnError::Sync(ref h) = config.on_error;
-                        let handled = h(deadpool_err.into(), &req);
-                        return Ok(handled.into());
-                    }
-                };
-                let redis_response = match connection.req_packed_command(&cmd.into()).await {
-                    Ok(res) => res,
-                    Err(redis_err) => {
-                        let config::OnError::Sync(ref h) = config.on_error;
-                        let handled = h(redis_err.into(), &req);
-                        return Ok(handled.into());
-                    }
-                };
-                let redis_cell_verdict = match Verdict::from_redis_value(&redis_response) {
-                    Ok(verdict) => verdict,
-                    Err(redis_err) => {
-                        let config::OnError::Sync(ref h) = config.on_error;
-                        let handled = h(Error::Redis(redis_err), &req);
-                        return Ok(handled.into());
-                    }
-                };
-                match redis_cell_verdict {
-                    redis_cell::Verdict::Blocked(details) => {
-                        let config::OnError::Sync(ref h) = config.on_error;
-                        let handled = h(
-                            Error::RateLimit(rule::RequestBlockedDetails { rule, details }),
-                            &req,
-                        );
-                        Ok(handled.into())
-                    }
-                    redis_cell::Verdict::Allowed(details) => {
-                        let policy = rule.policy;
-                        let resource = rule.resource;
-                        inner
-                            .call(req)
-                            .await
-                            .map(|mut resp| match &config.on_success {
-                                config::OnSuccess::Noop => resp,
-                                config::OnSuccess::Sync(h) => {
-                                    let details = rule::RequestAllowedDetails {
-                                        details,
-                                        policy,
-                                        resource,
-                                    };
-                                    h(details, &mut resp);
-                                    resp
-                                }
-                            })
-                    }
-                }
-            })
+            let connect = move || {
+                let pool = pool.clone();
+                async move { pool.get().await.map_err(Error::from) }
+            };
+            let checked = self.in_flight.guard(check(config, inner, req, connect));
+            Box::pin(self.pool_slot.guard(checked))
         }
     }
 
-    pub struct RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy> {
-        config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy>>,
+    pub struct RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E = Infallible> {
+        config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>,
         pool: deadpool_redis::Pool,
+        pool_slots: Option<Arc<Semaphore>>,
     }
 
-    impl<PR, ReqTy, RespTy, IntoRespTy> Clone for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy> {
+    impl<PR, ReqTy, RespTy, IntoRespTy, E> Clone for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E> {
         fn clone(&self) -> Self {
             Self {
                 config: Arc::clone(&self.config),
                 pool: self.pool.clone(),
+                pool_slots: self.pool_slots.clone(),
             }
         }
     }
 
-    impl<S, PR, ReqTy, RespTy, IntoRespTy> tower::Layer<S>
-        for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy>
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> tower::Layer<S>
+        for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E>
+    where
+        S: Clone,
     {
-        type Service = RateLimit<S, PR, ReqTy, RespTy, IntoRespTy>;
+        type Service = RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E>;
         fn layer(&self, inner: S) -> Self::Service {
-            RateLimit::new(inner, Arc::clone(&self.config), self.pool.clone())
+            // the services share the slots, as they share the pool
+            RateLimit {
+                pool_slots: self.pool_slots.clone(),
+                ..RateLimit::new(inner, Arc::clone(&self.config), self.pool.clone())
+            }
         }
     }
 
-    impl<PR, ReqTy, RespTy, IntoRespTy> RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy> {
+    impl<PR, ReqTy, RespTy, IntoRespTy, E> RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E> {
         pub fn new<RLC>(config: RLC, pool: deadpool_redis::Pool) -> Self
         where
-            RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy>>>,
+            RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>>,
+        {
+            let config: Arc<config::RateLimitConfig<_, _, _, _, _>> = config.into();
+            #[cfg(feature = "tracing")]
+            config.warn_misconfigured();
+            let pool_slots = pool_slots(config.pool_readiness, pool.status().max_size);
+            RateLimitLayer {
+                config,
+                pool,
+                pool_slots,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "bb8")]
+#[cfg_attr(docsrs, doc(cfg(feature = "bb8")))]
+pub mod bb8 {
+    use super::{InFlight, ResponseFuture, check, pool_slots};
+    use crate::config;
+    use crate::error::Error;
+    use crate::rule;
+    use bb8_redis::RedisConnectionManager;
+    use redis::aio::ConnectionLike;
+    use redis::{Cmd, Pipeline, RedisFuture, Value};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use tokio::sync::Semaphore;
+
+    type Pool = bb8::Pool<RedisConnectionManager>;
+
+    /// Connection checked out of the pool for the duration of a single check.
+    struct PooledConnection(bb8::PooledConnection<'static, RedisConnectionManager>);
+
+    impl ConnectionLike for PooledConnection {
+        fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+            self.0.req_packed_command(cmd)
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            cmd: &'a Pipeline,
+            offset: usize,
+            count: usize,
+        ) -> RedisFuture<'a, Vec<Value>> {
+            self.0.req_packed_commands(cmd, offset, count)
+        }
+
+        fn get_db(&self) -> i64 {
+            self.0.get_db()
+        }
+    }
+
+    pub struct RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E = Infallible> {
+        inner: S,
+        config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>,
+        pool: Pool,
+        in_flight: InFlight,
+        pool_slots: Option<Arc<Semaphore>>,
+        pool_slot: InFlight,
+    }
+
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> Clone for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E>
+    where
+        S: Clone,
+    {
+        fn clone(&self) -> Self {
+            Self {
+                inner: self.inner.clone(),
+                config: Arc::clone(&self.config),
+                pool: self.pool.clone(),
+                in_flight: self.in_flight.clone(),
+                pool_slots: self.pool_slots.clone(),
+                pool_slot: self.pool_slot.clone(),
+            }
+        }
+    }
+
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E> {
+        pub fn new<RLC>(inner: S, config: RLC, pool: Pool) -> Self
+        where
+            RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>>,
+        {
+            let config: Arc<config::RateLimitConfig<_, _, _, _, _>> = config.into();
+            let pool_slots = pool_slots(config.pool_readiness, pool.config().max_size as usize);
+            RateLimit {
+                inner,
+                config,
+                pool,
+                in_flight: InFlight::default(),
+                pool_slots,
+                pool_slot: InFlight::default(),
+            }
+        }
+    }
+
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> tower::Service<ReqTy>
+        for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E>
+    where
+        S: tower::Service<ReqTy, Response = RespTy, Error = E> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        E: Send + 'static,
+        S::Response: Send,
+        PR: rule::AsyncProvideRule<ReqTy> + Clone + Send + Sync + 'static,
+        ReqTy: Send + 'static,
+        IntoRespTy: Into<RespTy> + 'static,
+        RespTy: 'static,
+    {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = ResponseFuture<S::Response, S::Error>;
+
+        fn poll_ready(
+            &mut self,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            std::task::ready!(self.pool_slot.poll_acquire(self.pool_slots.as_ref(), cx));
+            std::task::ready!(
+                self.in_flight
+                    .poll_acquire(self.config.in_flight.as_ref(), cx)
+            );
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: ReqTy) -> Self::Future {
+            let pool = self.pool.clone();
+            let clone = self.inner.clone();
+            let inner = std::mem::replace(&mut self.inner, clone);
+            let config = self.config.clone();
+            let connect = move || {
+                let pool = pool.clone();
+                async move {
+                    pool.get_owned()
+                        .await
+                        .map(PooledConnection)
+                        .map_err(Error::from)
+                }
+            };
+            let checked = self.in_flight.guard(check(config, inner, req, connect));
+            Box::pin(self.pool_slot.guard(checked))
+        }
+    }
+
+    pub struct RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E = Infallible> {
+        config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>,
+        pool: Pool,
+        pool_slots: Option<Arc<Semaphore>>,
+    }
+
+    impl<PR, ReqTy, RespTy, IntoRespTy, E> Clone for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E> {
+        fn clone(&self) -> Self {
+            Self {
+                config: Arc::clone(&self.config),
+                pool: self.pool.clone(),
+                pool_slots: self.pool_slots.clone(),
+            }
+        }
+    }
+
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> tower::Layer<S>
+        for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E>
+    where
+        S: Clone,
+    {
+        type Service = RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E>;
+        fn layer(&self, inner: S) -> Self::Service {
+            // the services share the slots, as they share the pool
+            RateLimit {
+                pool_slots: self.pool_slots.clone(),
+                ..RateLimit::new(inner, Arc::clone(&self.config), self.pool.clone())
+            }
+        }
+    }
+
+    impl<PR, ReqTy, RespTy, IntoRespTy, E> RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E> {
+        pub fn new<RLC>(config: RLC, pool: Pool) -> Self
+        where
+            RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>>,
         {
+            let config: Arc<config::RateLimitConfig<_, _, _, _, _>> = config.into();
+            #[cfg(feature = "tracing")]
+            config.warn_misconfigured();
+            let pool_slots = pool_slots(config.pool_readiness, pool.config().max_size as usize);
             RateLimitLayer {
+                config,
+                pool,
+                pool_slots,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "mobc")]
+#[cfg_attr(docsrs, doc(cfg(feature = "mobc")))]
+pub mod mobc {
+    use super::{InFlight, ResponseFuture, check};
+    use crate::config;
+    use crate::error::Error;
+    use crate::rule;
+    use mobc::{Manager, async_trait};
+    use redis::aio::{ConnectionLike, MultiplexedConnection};
+    use redis::{Client, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, Value};
+    use std::convert::Infallible;
+    use std::sync::Arc;
+
+    type Pool = mobc::Pool<RedisConnectionManager>;
+
+    /// Manager of multiplexed Redis connections for a `mobc` pool.
+    ///
+    /// The `mobc-redis` crate is built against another version of `redis`,
+    /// hence this counterpart of its manager.
+    #[derive(Debug, Clone)]
+    pub struct RedisConnectionManager {
+        client: Client,
+    }
+
+    impl RedisConnectionManager {
+        pub fn new(client: Client) -> Self {
+            Self { client }
+        }
+    }
+
+    #[async_trait]
+    impl Manager for RedisConnectionManager {
+        type Connection = MultiplexedConnection;
+        type Error = RedisError;
+
+        async fn connect(&self) -> Result<Self::Connection, Self::Error> {
+            self.client.get_multiplexed_async_connection().await
+        }
+
+        async fn check(&self, mut conn: Self::Connection) -> Result<Self::Connection, Self::Error> {
+            let pong: String = redis::cmd("PING").query_async(&mut conn).await?;
+            if pong != "PONG" {
+                return Err((ErrorKind::ResponseError, "unexpected reply to PING").into());
+            }
+            Ok(conn)
+        }
+    }
+
+    /// Connection checked out of the pool for the duration of a single check.
+    struct PooledConnection(mobc::Connection<RedisConnectionManager>);
+
+    impl ConnectionLike for PooledConnection {
+        fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+            self.0.req_packed_command(cmd)
+        }
+
+        fn req_packed_commands<'a>(
+            &'a mut self,
+            cmd: &'a Pipeline,
+            offset: usize,
+            count: usize,
+        ) -> RedisFuture<'a, Vec<Value>> {
+            self.0.req_packed_commands(cmd, offset, count)
+        }
+
+        fn get_db(&self) -> i64 {
+            self.0.get_db()
+        }
+    }
+
+    pub struct RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E = Infallible> {
+        inner: S,
+        config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>,
+        pool: Pool,
+        in_flight: InFlight,
+    }
+
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> Clone for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E>
+    where
+        S: Clone,
+    {
+        fn clone(&self) -> Self {
+            Self {
+                inner: self.inner.clone(),
+                config: Arc::clone(&self.config),
+                pool: self.pool.clone(),
+                in_flight: self.in_flight.clone(),
+            }
+        }
+    }
+
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E> {
+        pub fn new<RLC>(inner: S, config: RLC, pool: Pool) -> Self
+        where
+            RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>>,
+        {
+            RateLimit {
+                inner,
                 config: config.into(),
                 pool,
+                in_flight: InFlight::default(),
+            }
+        }
+    }
+
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> tower::Service<ReqTy>
+        for RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E>
+    where
+        S: tower::Service<ReqTy, Response = RespTy, Error = E> + Clone + Send + 'static,
+        S::Future: Send + 'static,
+        E: Send + 'static,
+        S::Response: Send,
+        PR: rule::AsyncProvideRule<ReqTy> + Clone + Send + Sync + 'static,
+        ReqTy: Send + 'static,
+        IntoRespTy: Into<RespTy> + 'static,
+        RespTy: 'static,
+    {
+        type Response = S::Response;
+        type Error = S::Error;
+        type Future = ResponseFuture<S::Response, S::Error>;
+
+        fn poll_ready(
+            &mut self,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            // `pool_readiness` is ignored, see its docs
+            std::task::ready!(
+                self.in_flight
+                    .poll_acquire(self.config.in_flight.as_ref(), cx)
+            );
+            self.inner.poll_ready(cx)
+        }
+
+        fn call(&mut self, req: ReqTy) -> Self::Future {
+            let pool = self.pool.clone();
+            let clone = self.inner.clone();
+            let inner = std::mem::replace(&mut self.inner, clone);
+            let config = self.config.clone();
+            let connect = move || {
+                let pool = pool.clone();
+                async move { pool.get().await.map(PooledConnection).map_err(Error::from) }
+            };
+            Box::pin(self.in_flight.guard(check(config, inner, req, connect)))
+        }
+    }
+
+    pub struct RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E = Infallible> {
+        config: Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>,
+        pool: Pool,
+    }
+
+    impl<PR, ReqTy, RespTy, IntoRespTy, E> Clone for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E> {
+        fn clone(&self) -> Self {
+            Self {
+                config: Arc::clone(&self.config),
+                pool: self.pool.clone(),
+            }
+        }
+    }
+
+    impl<S, PR, ReqTy, RespTy, IntoRespTy, E> tower::Layer<S>
+        for RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E>
+    where
+        S: Clone,
+    {
+        type Service = RateLimit<S, PR, ReqTy, RespTy, IntoRespTy, E>;
+        fn layer(&self, inner: S) -> Self::Service {
+            RateLimit::new(inner, Arc::clone(&self.config), self.pool.clone())
+        }
+    }
+
+    impl<PR, ReqTy, RespTy, IntoRespTy, E> RateLimitLayer<PR, ReqTy, RespTy, IntoRespTy, E> {
+        pub fn new<RLC>(config: RLC, pool: Pool) -> Self
+        where
+            RLC: Into<Arc<config::RateLimitConfig<PR, ReqTy, RespTy, IntoRespTy, E>>>,
+        {
+            let config: Arc<config::RateLimitConfig<_, _, _, _, _>> = config.into();
+            #[cfg(feature = "tracing")]
+            config.warn_misconfigured();
+            RateLimitLayer { config, pool }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockConnection;
+    use crate::{ProvideRuleResult, RateLimitConfig, Rule};
+    use redis_cell_rs::Policy;
+    use std::task::{Context, Poll};
+    use tower::{Service as _, ServiceExt as _};
+
+    #[derive(Clone)]
+    struct Global;
+
+    impl<R> rule::ProvideRule<R> for Global {
+        fn provide<'a>(&self, _: &'a R) -> ProvideRuleResult<'a> {
+            Ok(Some(Rule::new(
+                "global",
+                Policy::from_tokens_per_second(10),
+            )))
+        }
+    }
+
+    /// Panics when called without having been polled ready, as e.g. a
+    /// `Buffer` does. Its clones start unready.
+    struct MustBeReady(bool);
+
+    impl Clone for MustBeReady {
+        fn clone(&self) -> Self {
+            MustBeReady(false)
+        }
+    }
+
+    impl tower::Service<()> for MustBeReady {
+        type Response = ();
+        type Error = Infallible;
+        type Future = std::future::Ready<Result<(), Infallible>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            self.0 = true;
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: ()) -> Self::Future {
+            assert!(self.0, "called without being ready");
+            self.0 = false;
+            std::future::ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn calls_the_inner_service_readied_in_poll_ready() {
+        let config = RateLimitConfig::new(Global, |_, _: &()| ());
+        let mut svc = RateLimit::new(MustBeReady(false), config, MockConnection::allowed(10, 9));
+        for _ in 0..3 {
+            svc.ready().await.unwrap().call(()).await.unwrap();
+        }
+    }
+
+    #[cfg(feature = "deadpool")]
+    #[tokio::test]
+    async fn waits_for_a_pool_slot() {
+        use std::time::Duration;
+        use tower::Layer as _;
+
+        let mut pool_config = deadpool_redis::Config::from_url("redis://127.0.0.1:1");
+        pool_config.pool = Some(deadpool_redis::PoolConfig::new(1));
+        let pool = pool_config
+            .create_pool(Some(deadpool_redis::Runtime::Tokio1))
+            .unwrap();
+        let config = RateLimitConfig::new(Global, |_, _: &()| ()).pool_readiness(true);
+        let layer = deadpool::RateLimitLayer::new(config, pool);
+        let mut first = layer.layer(MustBeReady(false));
+        let mut second = layer.layer(MustBeReady(false));
+        first.ready().await.unwrap();
+        let waiting = tokio::time::timeout(Duration::from_millis(50), second.ready()).await;
+        assert!(waiting.is_err(), "the only slot is taken");
+        drop(first);
+        second.ready().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn charges_the_locally_allowed_requests_of_a_blocked_batch() {
+        use crate::testing::LocalGcra;
+        use crate::{Error, rate_limit_check};
+
+        #[derive(Clone)]
+        struct Bucket;
+
+        impl<R> rule::ProvideRule<R> for Bucket {
+            fn provide<'a>(&self, _: &'a R) -> ProvideRuleResult<'a> {
+                let policy = Policy::from_tokens_per_hour(1).max_burst(4);
+                Ok(Some(Rule::new("bucket", policy)))
+            }
+        }
+
+        let redis = LocalGcra::new();
+        let config = RateLimitConfig::new(Bucket, |err: Error, _: &()| {
+            assert!(matches!(err, Error::RateLimit(_)));
+            false
+        })
+        .local_counter(10);
+        let inner = tower::service_fn(|_| async { Ok::<_, Infallible>(true) });
+        let mut svc = RateLimit::new(inner, config, redis.clone());
+        // one request synced with Redis (4 tokens left), three allowed locally
+        for _ in 0..4 {
+            assert!(svc.ready().await.unwrap().call(()).await.unwrap());
+        }
+        // someone else takes 3 tokens, so the batch of 4 does not fit
+        let policy = Policy::from_tokens_per_hour(1).max_burst(4).apply_tokens(3);
+        let rule = Rule::new("bucket", policy);
+        rate_limit_check(&mut redis.clone(), &rule).await.unwrap();
+        assert!(!svc.ready().await.unwrap().call(()).await.unwrap());
+        // the last token went to the requests allowed locally
+        assert!(!svc.ready().await.unwrap().call(()).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn reports_the_fallback_cell_once_tried() {
+        use crate::Error;
+        use crate::testing::LocalGcra;
+
+        #[derive(Clone)]
+        struct WithFallback;
+
+        impl<R> rule::ProvideRule<R> for WithFallback {
+            fn provide<'a>(&self, _: &'a R) -> ProvideRuleResult<'a> {
+                let policy = Policy::from_tokens_per_hour(1);
+                Ok(Some(Rule::new("user123", policy).fallback(policy)))
             }
         }
+
+        let config = RateLimitConfig::new(WithFallback, |err: Error, _: &()| match err {
+            Error::RateLimit(details) => details.rule.key.to_string(),
+            err => panic!("unexpected error: {err}"),
+        });
+        let inner = tower::service_fn(|_| async { Ok::<_, Infallible>("allowed".to_string()) });
+        let mut svc = RateLimit::new(inner, config, LocalGcra::new());
+        for _ in 0..2 {
+            let resp = svc.ready().await.unwrap().call(()).await.unwrap();
+            assert_eq!(resp, "allowed");
+        }
+        let resp = svc.ready().await.unwrap().call(()).await.unwrap();
+        assert_eq!(resp, "user123:fallback");
+    }
+
+    #[tokio::test]
+    async fn post_charges_the_fallback_cell() {
+        use crate::Error;
+        use crate::testing::LocalGcra;
+
+        #[derive(Clone)]
+        struct WithFallback;
+
+        impl<R> rule::ProvideRule<R> for WithFallback {
+            fn provide<'a>(&self, _: &'a R) -> ProvideRuleResult<'a> {
+                let policy = Policy::from_tokens_per_hour(1);
+                Ok(Some(
+                    Rule::new("user123", policy).fallback(policy.max_burst(1)),
+                ))
+            }
+        }
+
+        let config = RateLimitConfig::new(WithFallback, |err: Error, _: &()| match err {
+            Error::RateLimit(details) => details.rule.key.to_string(),
+            err => panic!("unexpected error: {err}"),
+        })
+        .post_charge(|_: &String| 1);
+        let inner = tower::service_fn(|_| async { Ok::<_, Infallible>("allowed".to_string()) });
+        let mut svc = RateLimit::new(inner, config, LocalGcra::new());
+        for _ in 0..2 {
+            let resp = svc.ready().await.unwrap().call(()).await.unwrap();
+            assert_eq!(resp, "allowed");
+        }
+        // the second token of the fallback cell went to the post-charge
+        let resp = svc.ready().await.unwrap().call(()).await.unwrap();
+        assert_eq!(resp, "user123:fallback");
+    }
+
+    #[tokio::test]
+    async fn bounds_the_wait_for_a_redis_permit() {
+        use crate::{Error, FailMode};
+        use redis::{Cmd, Pipeline, RedisFuture, Value};
+        use std::time::Duration;
+
+        /// Never replies.
+        #[derive(Clone)]
+        struct Hanging;
+
+        impl ConnectionLike for Hanging {
+            fn req_packed_command<'a>(&'a mut self, _: &'a Cmd) -> RedisFuture<'a, Value> {
+                Box::pin(std::future::pending())
+            }
+
+            fn req_packed_commands<'a>(
+                &'a mut self,
+                _: &'a Pipeline,
+                _: usize,
+                _: usize,
+            ) -> RedisFuture<'a, Vec<Value>> {
+                Box::pin(std::future::pending())
+            }
+
+            fn get_db(&self) -> i64 {
+                0
+            }
+        }
+
+        for (fail_mode, expected) in [(FailMode::Deny, "overloaded"), (FailMode::Allow, "allowed")]
+        {
+            let config = RateLimitConfig::new(Global, |err: Error, _: &()| {
+                assert!(matches!(err, Error::Overloaded));
+                "overloaded"
+            })
+            .on_redis_error(fail_mode)
+            .max_concurrent_redis(1)
+            .redis_permit_wait(Duration::from_millis(10));
+            let inner = tower::service_fn(|_| async { Ok::<_, Infallible>("allowed") });
+            let svc = RateLimit::new(inner, config, Hanging);
+            // takes the only permit for good
+            let first = svc.clone().oneshot(());
+            tokio::pin!(first);
+            let pending = tokio::time::timeout(Duration::from_millis(10), &mut first).await;
+            assert!(pending.is_err());
+            assert_eq!(svc.clone().oneshot(()).await.unwrap(), expected);
+        }
+    }
+
+    #[cfg(feature = "local-cache")]
+    #[tokio::test]
+    async fn takes_no_connection_for_a_cached_block() {
+        let config = RateLimitConfig::new(Global, |err: Error, _: &()| match err {
+            Error::RateLimit(details) => details.retry_after().to_string(),
+            err => err.to_string(),
+        })
+        .blocked_cache(16);
+        let config = Arc::new(config);
+        let inner = tower::service_fn(|_| async { Ok::<_, Infallible>("allowed".to_string()) });
+
+        let connection = MockConnection::blocked(10, 60);
+        let connect = || std::future::ready(Ok(connection.clone()));
+        let resp = check(Arc::clone(&config), inner, (), connect).await;
+        assert_eq!(resp.unwrap(), "60");
+
+        let connect = || -> std::future::Ready<Result<MockConnection, Error<'static>>> {
+            panic!("a cached block takes no connection")
+        };
+        let resp = check(config, inner, (), connect).await;
+        assert_eq!(resp.unwrap(), "60");
+        assert_eq!(connection.calls(), 1);
+    }
+
+    #[tokio::test]
+    async fn checks_a_rule_outside_of_a_service() {
+        let mut redis = crate::testing::LocalGcra::new();
+        let rule = Rule::new("user123", Policy::from_tokens_per_minute(10));
+
+        let verdict = rate_limit_check(&mut redis, &rule).await.unwrap();
+        assert!(matches!(verdict, redis_cell::Verdict::Allowed(details) if details.remaining == 0));
+        let verdict = rate_limit_check(&mut redis, &rule).await.unwrap();
+        assert!(
+            matches!(verdict, redis_cell::Verdict::Blocked(details) if details.retry_after == 6)
+        );
+
+        let err = rate_limit_check(&mut MockConnection::failing(), &rule).await;
+        assert!(matches!(err, Err(Error::Redis { .. })));
+    }
+
+    #[tokio::test]
+    async fn attributes_an_invalid_reply_to_its_key() {
+        use redis::{Cmd, Pipeline, RedisFuture, Value};
+
+        /// Replies with whatever it has been given.
+        struct Replying(Vec<Value>);
+
+        impl ConnectionLike for Replying {
+            fn req_packed_command<'a>(&'a mut self, _: &'a Cmd) -> RedisFuture<'a, Value> {
+                unimplemented!()
+            }
+
+            fn req_packed_commands<'a>(
+                &'a mut self,
+                _: &'a Pipeline,
+                _: usize,
+                _: usize,
+            ) -> RedisFuture<'a, Vec<Value>> {
+                let replies = self.0.clone();
+                Box::pin(async move { Ok(replies) })
+            }
+
+            fn get_db(&self) -> i64 {
+                0
+            }
+        }
+
+        let rules = [
+            Rule::new("global", Policy::from_tokens_per_second(1000)),
+            Rule::new("user123", Policy::from_tokens_per_minute(10)),
+        ];
+        let allowed = Value::Array([0, 1000, 999, -1, 1].map(Value::Int).to_vec());
+
+        let mut connection = Replying(vec![allowed, Value::Nil]);
+        let err = rate_limit_check_many(&mut connection, &rules).await;
+        let Err(Error::Redis { command, .. }) = err else {
+            panic!("should be a Redis error");
+        };
+        let mut connection = Replying(vec![Value::Nil]);
+        let err = rate_limit_check_many(&mut connection, &rules[1..]).await;
+        let Err(Error::Redis {
+            command: user123, ..
+        }) = err
+        else {
+            panic!("should be a Redis error");
+        };
+        assert_eq!(command, user123);
     }
 }
@@ -0,0 +1,462 @@
+//! Utilities for testing rate-limited services without Redis.
+//!
+//! [`MockConnection`] replies to every command with the same canned verdict,
+//! which is all it takes to check that the rule provider and the handlers
+//! play together, e.g. that a blocked request gets the right status:
+//!
+//!```
+//! use axum::http::{Request, Response, StatusCode, header};
+//! use std::convert::Infallible;
+//! use tower::{Layer as _, ServiceExt as _, service_fn};
+//! use tower_redis_cell::redis_cell::Policy;
+//! use tower_redis_cell::testing::MockConnection;
+//! use tower_redis_cell::{Error, ProvideRule, ProvideRuleResult, RateLimitConfig, RateLimitLayer, Rule};
+//!
+//! #[derive(Clone)]
+//! struct ByUser;
+//!
+//! impl<B> ProvideRule<Request<B>> for ByUser {
+//!     fn provide<'a>(&self, req: &'a Request<B>) -> ProvideRuleResult<'a> {
+//!         let user = req.headers()["x-user-id"].to_str().unwrap();
+//!         Ok(Some(Rule::new(user, Policy::from_tokens_per_minute(10))))
+//!     }
+//! }
+//!
+//! fn on_error(err: Error, _req: &Request<()>) -> Response<()> {
+//!     let mut resp = Response::new(());
+//!     if let Error::RateLimit(details) = err {
+//!         *resp.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+//!         resp.headers_mut().insert(header::RETRY_AFTER, details.retry_after().into());
+//!     }
+//!     resp
+//! }
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let connection = MockConnection::blocked(10, 42);
+//! let svc = RateLimitLayer::new(RateLimitConfig::new(ByUser, on_error), connection.clone())
+//!     .layer(service_fn(|_req| async { Ok::<_, Infallible>(Response::new(())) }));
+//!
+//! let req = Request::builder().header("x-user-id", "alice").body(()).unwrap();
+//! let resp = svc.oneshot(req).await.unwrap();
+//! assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+//! assert_eq!(resp.headers()[header::RETRY_AFTER], "42");
+//! assert_eq!(connection.calls(), 1);
+//! # }
+//!```
+//!
+//! [`LocalGcra`] goes further and actually does the math, so that the state
+//! of the cells can be observed as the requests come in.
+
+use futures_util::FutureExt as _;
+use redis::aio::ConnectionLike;
+use redis::{Arg, Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, RedisResult, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const NANOS_PER_SEC: i128 = 1_000_000_000;
+
+#[derive(Debug, Default)]
+struct State {
+    now: i128,
+    calls: usize,
+    last_command: Vec<String>,
+    // theoretical arrival times, in nanoseconds
+    tats: HashMap<Vec<u8>, i128>,
+}
+
+/// In-memory stand-in for Redis with the Redis Cell module loaded.
+///
+/// `CL.THROTTLE` is answered with the same GCRA math the module uses, but
+/// against a clock which only moves when told to with [`LocalGcra::advance`].
+/// This makes it possible to assert refill and retry-after behavior in tests
+/// without sleeping. Besides, `SCAN` (in a single page) and `DEL` are
+/// supported for use with the [`admin`](crate::admin) helpers, while any other
+/// command is rejected. Clones share the state.
+///
+///```
+/// use std::time::Duration;
+/// use tower_redis_cell::redis_cell::{Key, Policy, Verdict};
+/// use tower_redis_cell::testing::LocalGcra;
+/// use tower_redis_cell::{Rule, rate_limit_check};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let mut redis = LocalGcra::new();
+/// // 1 token every 10 seconds, up to 2 at once
+/// let rule = Rule::new("user123", Policy::from_tokens_per_minute(6).max_burst(1));
+///
+/// for remaining in [1, 0] {
+///     let Verdict::Allowed(details) = rate_limit_check(&mut redis, &rule).await.unwrap() else {
+///         panic!("should be allowed");
+///     };
+///     assert_eq!(details.remaining, remaining);
+/// }
+/// let Verdict::Blocked(details) = rate_limit_check(&mut redis, &rule).await.unwrap() else {
+///     panic!("should be blocked");
+/// };
+/// assert_eq!(details.retry_after, 10);
+///
+/// redis.advance(Duration::from_secs(10));
+/// let verdict = rate_limit_check(&mut redis, &rule).await.unwrap();
+/// assert!(matches!(verdict, Verdict::Allowed(details) if details.remaining == 0));
+///
+/// // the bucket is full again once the reset time has passed
+/// redis.advance(Duration::from_secs(20));
+/// let verdict = rate_limit_check(&mut redis, &rule).await.unwrap();
+/// assert!(matches!(verdict, Verdict::Allowed(details) if details.remaining == 1));
+/// # }
+///```
+///
+/// Resetting state with the [`admin`](crate::admin) helpers:
+///```
+/// use tower_redis_cell::redis_cell::{Key, Policy};
+/// use tower_redis_cell::testing::LocalGcra;
+/// use tower_redis_cell::{Error, RateLimitConfig, Rule, admin, rate_limit_check};
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let mut redis = LocalGcra::new();
+/// let policy = Policy::from_tokens_per_minute(1);
+/// for key in ["acme:alice", "acme:bob", "globex:carol"] {
+///     rate_limit_check(&mut redis, &Rule::new(key, policy)).await.unwrap();
+/// }
+///
+/// // the helpers only need the config for its key prefix, of which there is none
+/// let config: RateLimitConfig<(), (), (), ()> = RateLimitConfig::new((), |_err: Error, _req: &()| ());
+/// let keys = admin::scan_keys(&mut redis, &config, "acme:*").await.unwrap();
+/// assert_eq!(keys, ["acme:alice", "acme:bob"]);
+/// assert_eq!(admin::reset_matching(&mut redis, &config, "acme:*").await.unwrap(), 2);
+/// assert_eq!(admin::scan_keys(&mut redis, &config, "*").await.unwrap(), ["globex:carol"]);
+/// # }
+///```
+#[derive(Debug, Clone, Default)]
+pub struct LocalGcra {
+    state: Arc<Mutex<State>>,
+}
+
+impl LocalGcra {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Move the clock forward.
+    pub fn advance(&self, by: Duration) {
+        self.state.lock().unwrap().now += by.as_nanos() as i128;
+    }
+
+    /// Number of commands received so far.
+    pub fn calls(&self) -> usize {
+        self.state.lock().unwrap().calls
+    }
+
+    /// Arguments of the last command received, starting with its name.
+    pub fn last_command(&self) -> Vec<String> {
+        self.state.lock().unwrap().last_command.clone()
+    }
+
+    /// The keys of the cells ever throttled, sorted.
+    #[cfg(test)]
+    pub(crate) fn keys(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut keys: Vec<_> = state
+            .tats
+            .keys()
+            .map(|key| String::from_utf8_lossy(key).into_owned())
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    fn execute(&self, cmd: &Cmd) -> RedisResult<Value> {
+        let args: Vec<&[u8]> = cmd
+            .args_iter()
+            .map(|arg| match arg {
+                Arg::Simple(arg) => arg,
+                Arg::Cursor => b"",
+            })
+            .collect();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.calls += 1;
+            state.last_command = args
+                .iter()
+                .map(|arg| String::from_utf8_lossy(arg).into_owned())
+                .collect();
+        }
+        let [name, key, rest @ ..] = args.as_slice() else {
+            return Err(invalid("wrong number of arguments"));
+        };
+        if name.eq_ignore_ascii_case(b"SCAN") {
+            return self.scan(rest);
+        }
+        if name.eq_ignore_ascii_case(b"DEL") {
+            return Ok(self.del(&args[1..]));
+        }
+        if !name.eq_ignore_ascii_case(b"CL.THROTTLE") {
+            return Err(invalid("only CL.THROTTLE, SCAN, and DEL are supported"));
+        }
+        let int = |arg: &[u8]| -> RedisResult<i128> {
+            std::str::from_utf8(arg)
+                .ok()
+                .and_then(|arg| arg.parse::<i64>().ok())
+                .map(i128::from)
+                .ok_or_else(|| invalid("argument is not an integer"))
+        };
+        let (burst, count, period, quantity) = match rest {
+            [burst, count, period] => (int(burst)?, int(count)?, int(period)?, 1),
+            [burst, count, period, quantity] => {
+                (int(burst)?, int(count)?, int(period)?, int(quantity)?)
+            }
+            _ => return Err(invalid("wrong number of arguments")),
+        };
+        if burst < 0 || count < 1 || period < 1 || quantity < 0 {
+            return Err(invalid("arguments out of range"));
+        }
+        let mut state = self.state.lock().unwrap();
+        let now = state.now;
+        Ok(throttle(
+            &mut state.tats,
+            now,
+            key,
+            burst,
+            count,
+            period,
+            quantity,
+        ))
+    }
+
+    fn scan(&self, args: &[&[u8]]) -> RedisResult<Value> {
+        let pattern: &[u8] = match args {
+            [] => b"*",
+            [option, pattern, ..] if option.eq_ignore_ascii_case(b"MATCH") => pattern,
+            _ => return Err(invalid("only the MATCH option is supported")),
+        };
+        let state = self.state.lock().unwrap();
+        let mut keys: Vec<_> = state
+            .tats
+            .iter()
+            .filter(|(key, tat)| **tat > state.now && glob(pattern, key))
+            .map(|(key, _)| key.clone())
+            .collect();
+        keys.sort();
+        let keys = keys.into_iter().map(Value::BulkString).collect();
+        let cursor = Value::BulkString(b"0".to_vec());
+        Ok(Value::Array(vec![cursor, Value::Array(keys)]))
+    }
+
+    fn del(&self, keys: &[&[u8]]) -> Value {
+        let mut state = self.state.lock().unwrap();
+        let now = state.now;
+        let deleted = keys
+            .iter()
+            .filter(|key| state.tats.remove(**key).is_some_and(|tat| tat > now))
+            .count();
+        Value::Int(deleted as i64)
+    }
+}
+
+/// Glob-style matching supporting `*`, `?`, and escaping with `\`.
+fn glob(pattern: &[u8], value: &[u8]) -> bool {
+    match (pattern.split_first(), value.split_first()) {
+        (None, None) => true,
+        (Some((b'\\', [p, rest @ ..])), Some((v, value))) => p == v && glob(rest, value),
+        (Some((b'*', rest)), _) => {
+            glob(rest, value) || (!value.is_empty() && glob(pattern, &value[1..]))
+        }
+        (Some((b'?', rest)), Some((_, value))) => glob(rest, value),
+        (Some((p, rest)), Some((v, value))) => p == v && glob(rest, value),
+        _ => false,
+    }
+}
+
+fn throttle(
+    tats: &mut HashMap<Vec<u8>, i128>,
+    now: i128,
+    key: &[u8],
+    burst: i128,
+    count: i128,
+    period: i128,
+    quantity: i128,
+) -> Value {
+    let emission_interval = period * NANOS_PER_SEC / count;
+    let delay_variation_tolerance = emission_interval * (burst + 1);
+    let increment = emission_interval * quantity;
+    // an expired key is as good as a missing one
+    let tat = tats
+        .get(key)
+        .copied()
+        .filter(|tat| *tat > now)
+        .unwrap_or(now);
+    let new_tat = tat.max(now) + increment;
+    let diff = now - (new_tat - delay_variation_tolerance);
+    let (limited, retry_after, ttl) = if diff < 0 {
+        // a request which would never fit into the bucket is never retried
+        let retry_after = if increment <= delay_variation_tolerance {
+            -diff
+        } else {
+            -NANOS_PER_SEC
+        };
+        (true, retry_after, tat - now)
+    } else {
+        tats.insert(key.to_vec(), new_tat);
+        (false, -NANOS_PER_SEC, new_tat - now)
+    };
+    let next = delay_variation_tolerance - ttl;
+    let remaining = if next > -emission_interval {
+        next / emission_interval
+    } else {
+        0
+    };
+    let reply = [
+        limited as i128,
+        burst + 1,
+        remaining,
+        retry_after / NANOS_PER_SEC,
+        ttl / NANOS_PER_SEC,
+    ];
+    Value::Array(reply.map(|value| Value::Int(value as i64)).to_vec())
+}
+
+fn invalid(detail: &'static str) -> RedisError {
+    RedisError::from((
+        ErrorKind::ResponseError,
+        "invalid command",
+        detail.to_string(),
+    ))
+}
+
+impl ConnectionLike for LocalGcra {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        std::future::ready(self.execute(cmd)).boxed()
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let replies = cmd
+            .cmd_iter()
+            .map(|cmd| self.execute(cmd))
+            .skip(offset)
+            .take(count)
+            .collect();
+        std::future::ready(replies).boxed()
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Canned {
+    Reply([i64; 5]),
+    Failure,
+}
+
+/// Connection replying to every command with the same canned `CL.THROTTLE`
+/// reply, see the [module docs](self) for an example.
+///
+/// Clones share the count of [calls](MockConnection::calls).
+#[derive(Debug, Clone)]
+pub struct MockConnection {
+    canned: Canned,
+    calls: Arc<AtomicUsize>,
+}
+
+impl MockConnection {
+    fn new(canned: Canned) -> Self {
+        MockConnection {
+            canned,
+            calls: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Allow every request, with `remaining` out of `total` tokens left.
+    pub fn allowed(total: usize, remaining: usize) -> Self {
+        Self::new(Canned::Reply([0, total as i64, remaining as i64, -1, 0]))
+    }
+
+    /// Block every request, to be retried after `retry_after` seconds.
+    pub fn blocked(total: usize, retry_after: u64) -> Self {
+        let retry_after = retry_after as i64;
+        Self::new(Canned::Reply([
+            1,
+            total as i64,
+            0,
+            retry_after,
+            retry_after,
+        ]))
+    }
+
+    /// Fail every command as if Redis were unreachable.
+    pub fn failing() -> Self {
+        Self::new(Canned::Failure)
+    }
+
+    /// Report the cell to be full again in `secs` seconds.
+    ///
+    /// Defaults to zero for [allowed](Self::allowed) requests and to the
+    /// retry-after for [blocked](Self::blocked) ones.
+    pub fn reset_after(mut self, secs: u64) -> Self {
+        if let Canned::Reply(ref mut reply) = self.canned {
+            reply[4] = secs as i64;
+        }
+        self
+    }
+
+    /// Number of commands received so far.
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    fn reply(&self) -> RedisResult<Value> {
+        self.calls.fetch_add(1, Ordering::Relaxed);
+        match self.canned {
+            Canned::Reply(reply) => Ok(Value::Array(reply.map(Value::Int).to_vec())),
+            Canned::Failure => Err(RedisError::from((ErrorKind::IoError, "connection refused"))),
+        }
+    }
+}
+
+impl ConnectionLike for MockConnection {
+    fn req_packed_command<'a>(&'a mut self, _: &'a Cmd) -> RedisFuture<'a, Value> {
+        std::future::ready(self.reply()).boxed()
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let replies = cmd
+            .cmd_iter()
+            .skip(offset)
+            .take(count)
+            .map(|_| self.reply())
+            .collect();
+        std::future::ready(replies).boxed()
+    }
+
+    fn get_db(&self) -> i64 {
+        0
+    }
+}
+
+/// Provides the same rule for every request.
+#[cfg(test)]
+#[derive(Clone)]
+pub(crate) struct Fixed(pub(crate) crate::Rule<'static>);
+
+#[cfg(test)]
+impl<R> crate::ProvideRule<R> for Fixed {
+    fn provide<'a>(&self, _: &'a R) -> crate::ProvideRuleResult<'a> {
+        Ok(Some(self.0.clone()))
+    }
+}
@@ -0,0 +1,128 @@
+use redis::{ErrorKind, FromRedisValue as _, RedisError, RedisResult, Value};
+use redis_cell_rs::Verdict;
+
+/// Number of elements in a CL.THROTTLE reply.
+const REPLY_LEN: usize = 5;
+
+/// How much of the reply is rendered into a decoding error, at most.
+const REPLY_DEBUG_MAX: usize = 256;
+
+const INVALID_REPLY: &str = "invalid Redis Cell response";
+
+/// Decode a CL.THROTTLE reply.
+///
+/// In strict mode, the reply has got to be exactly what Redis Cell documents,
+/// i.e. an array of five integers. Otherwise, any trailing elements are ignored
+/// and integers sent over as strings are accepted.
+///
+/// In either mode, a RESP3 push frame received _instead of_ the reply is an
+/// error: subscriptions (e.g. to keyspace notifications) need a connection
+/// of their own.
+///
+/// Decoding errors are of [`ErrorKind::ParseError`] kind, which tells them
+/// apart from the errors replied by the server, and their detail holds the
+/// reply (truncated).
+pub(crate) fn decode(value: &Value, strict: bool) -> RedisResult<Verdict> {
+    let value = reject_push(value)?;
+    let verdict = if strict {
+        Verdict::from_redis_value(value)
+    } else {
+        decode_lenient(value)
+    };
+    verdict.map_err(|err| {
+        let detail = err.detail().map(truncate).unwrap_or_default();
+        (ErrorKind::ParseError, INVALID_REPLY, detail).into()
+    })
+}
+
+fn decode_lenient(value: &Value) -> RedisResult<Verdict> {
+    match value {
+        Value::Array(items) if items.len() > REPLY_LEN || items.iter().any(is_textual) => {
+            let items = items.iter().take(REPLY_LEN).map(lenient_int).collect();
+            Verdict::from_redis_value(&Value::Array(items))
+        }
+        other => Verdict::from_redis_value(other),
+    }
+}
+
+/// Decode the "limited" flag of a CL.THROTTLE reply, the element which the
+/// verdict is derived from.
+pub(crate) fn limited(value: &Value, strict: bool) -> RedisResult<bool> {
+    let value = reject_push(value)?;
+    let flag = match value {
+        Value::Array(items) if !strict => items.first().map(lenient_int),
+        Value::Array(items) => items.first().cloned(),
+        _ => None,
+    };
+    match flag {
+        Some(Value::Int(0)) => Ok(false),
+        Some(Value::Int(1)) => Ok(true),
+        _ => Err(invalid_reply(
+            "expected the limited flag to be 0 or 1",
+            value,
+        )),
+    }
+}
+
+fn invalid_reply(reason: &str, reply: &Value) -> RedisError {
+    let detail = truncate(&format!("{}, but got {:?}", reason, reply));
+    (ErrorKind::ParseError, INVALID_REPLY, detail).into()
+}
+
+fn truncate(detail: &str) -> String {
+    if detail.len() <= REPLY_DEBUG_MAX {
+        return detail.to_string();
+    }
+    let mut end = REPLY_DEBUG_MAX;
+    while !detail.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &detail[..end])
+}
+
+fn is_textual(value: &Value) -> bool {
+    matches!(value, Value::BulkString(_) | Value::SimpleString(_))
+}
+
+fn lenient_int(value: &Value) -> Value {
+    let parsed = match value {
+        Value::BulkString(bytes) => std::str::from_utf8(bytes).ok().and_then(|s| s.parse().ok()),
+        Value::SimpleString(s) => s.parse().ok(),
+        _ => None,
+    };
+    parsed.map(Value::Int).unwrap_or_else(|| value.clone())
+}
+
+fn reject_push(value: &Value) -> RedisResult<&Value> {
+    match value {
+        Value::Push { kind, .. } => {
+            let detail = format!(
+                "received a {:?} push message instead of a reply, \
+                 consider using a dedicated connection for rate limiting",
+                kind
+            );
+            Err((ErrorKind::ParseError, INVALID_REPLY, detail).into())
+        }
+        value => Ok(value),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::PushKind;
+
+    #[test]
+    fn rejects_a_push_frame_received_instead_of_the_reply() {
+        let push = Value::Push {
+            kind: PushKind::Message,
+            data: vec![Value::BulkString(b"__keyspace@0__:user123".to_vec())],
+        };
+        for strict in [true, false] {
+            let err = decode(&push, strict).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::ParseError);
+            assert!(err.detail().unwrap().contains("dedicated connection"));
+            assert!(limited(&push, strict).is_err());
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Runs against Valkey with the Redis Cell module in a container, and so
+//! requires Docker: `cargo test --test admin -- --ignored`.
+
+use std::convert::Infallible;
+use std::sync::Arc;
+use tower::{Layer as _, ServiceExt as _, service_fn};
+use tower_redis_cell::redis_cell::Policy;
+use tower_redis_cell::{
+    Error, ProvideRule, ProvideRuleResult, RateLimitConfig, RateLimitLayer, Rule, admin,
+};
+
+const POLICY: Policy = Policy::from_tokens_per_hour(1);
+
+#[derive(Clone)]
+struct ByUser;
+
+impl ProvideRule<&'static str> for ByUser {
+    fn provide<'a>(&self, user: &'a &'static str) -> ProvideRuleResult<'a> {
+        Ok(Some(Rule::new(*user, POLICY)))
+    }
+}
+
+#[tokio::test]
+#[ignore = "requires Docker"]
+async fn resets_a_subset_of_the_keys() {
+    let (_container, port) = utils::launch_redis_container().await;
+    let client = redis::Client::open(("localhost", port)).unwrap();
+    let mut connection = client.get_multiplexed_async_connection().await.unwrap();
+
+    let config = RateLimitConfig::new(ByUser, |err: Error, _req: &&str| match err {
+        Error::RateLimit(_) => "blocked",
+        err => panic!("unexpected error: {err}"),
+    })
+    .key_prefix("rl:");
+    let config = Arc::new(config);
+    let svc = RateLimitLayer::new(Arc::clone(&config), connection.clone())
+        .layer(service_fn(|_req| async { Ok::<_, Infallible>("allowed") }));
+
+    let users = ["acme:alice", "acme:bob", "globex:carol"];
+    for user in users {
+        assert_eq!(svc.clone().oneshot(user).await.unwrap(), "allowed");
+        assert_eq!(svc.clone().oneshot(user).await.unwrap(), "blocked");
+    }
+    // a key of the same shape, but not one of ours
+    let _: () = redis::cmd("SET")
+        .arg("acme:dave")
+        .arg(1)
+        .query_async(&mut connection)
+        .await
+        .unwrap();
+
+    let keys = admin::scan_keys(&mut connection, &config, "acme:*")
+        .await
+        .unwrap();
+    assert_eq!(keys, ["acme:alice", "acme:bob"]);
+    let deleted = admin::reset_matching(&mut connection, &config, "acme:*")
+        .await
+        .unwrap();
+    assert_eq!(deleted, 2);
+
+    let keys = admin::scan_keys(&mut connection, &config, "*")
+        .await
+        .unwrap();
+    assert_eq!(keys, ["globex:carol"]);
+    let exists: bool = redis::cmd("EXISTS")
+        .arg("acme:dave")
+        .query_async(&mut connection)
+        .await
+        .unwrap();
+    assert!(exists);
+    // the reset keys start over with a full bucket, the other one does not
+    assert_eq!(svc.clone().oneshot("acme:alice").await.unwrap(), "allowed");
+    assert_eq!(svc.clone().oneshot("acme:bob").await.unwrap(), "allowed");
+    assert_eq!(
+        svc.clone().oneshot("globex:carol").await.unwrap(),
+        "blocked"
+    );
+}
+
+mod utils {
+    use testcontainers::ContainerAsync;
+    use testcontainers::core::IntoContainerPort as _;
+    use testcontainers::runners::AsyncRunner;
+    use testcontainers::{GenericImage, core::WaitFor};
+
+    pub async fn launch_redis_container() -> (ContainerAsync<GenericImage>, u16) {
+        let container = GenericImage::new("ghcr.io/rustworthy/valkey-cell", "latest")
+            .with_exposed_port(6379.tcp())
+            .with_wait_for(WaitFor::message_on_stdout("Ready to accept connections"))
+            .start()
+            .await
+            .unwrap();
+        let port = container.get_host_port_ipv4(6379).await.unwrap();
+        (container, port)
+    }
+}